@@ -1,6 +1,9 @@
 # Run this scripts from base path!
 PATH=$(pwd)/ronn/bin:$PATH
 
+# The ronn source is generated from the CLI definitions in src/arg_parser.rs
+cargo run --quiet -- man > ./man/xornet-reporter.1.ronn
+
 ronn ./man/xornet-reporter.1.ronn
 rm ./man/xornet-reporter.1.html
 echo "ronn man page generation done."
@@ -212,7 +212,7 @@ if [ ! -f /opt/xornet/config.json ]; then
   else
     token=$1
   fi
-  ./xornet-reporter -su $token
+  ./xornet-reporter --config /opt/xornet/config.json signup $token
   handle_exit_code
   cd $path
 fi
@@ -231,7 +231,7 @@ After=network.target
 Type=simple
 User=root
 WorkingDirectory=/opt/xornet
-ExecStart=/opt/xornet/xornet-reporter --silent
+ExecStart=/opt/xornet/xornet-reporter --config /opt/xornet/config.json run
 Restart=always
 RestartSec=10
 
@@ -262,7 +262,7 @@ elif [ $service_manager = "openrc" ]; then
     cat >/etc/init.d/xornet-reporter <<EOF
 #!/sbin/openrc-run
 command=/opt/xornet/xornet-reporter
-command_args="--silent"
+command_args="--config /opt/xornet/config.json run"
 command_user="root:root"
 pidfile=/var/run/xornet-reporter.pid
 directory="/opt/xornet/"
@@ -311,5 +311,5 @@ elif [ $service_manager = "openrc" ]; then
 else
   echo "No supported service manager was found. No background service was created."
   echo "You can start the reporter by running the following command:"
-  echo "cd /opt/xornet && sudo ./xornet-reporter --silent"
+  echo "cd /opt/xornet && sudo ./xornet-reporter run"
 fi
@@ -1,142 +1,379 @@
 use anyhow::Result;
-use colored::Colorize;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use std::fmt::Write as _;
+use std::io::Write;
 
-use crate::{
-  auth_manager::AuthManager, config_manager::ConfigManager, data_collector::DataCollector,
-};
-
-/// The structure of the launch parameters.
-#[derive(Debug, Clone)]
+/// Data collector that gets your system's state and sends it to the Opal backend
+///
+/// It can also be used as a pure system stat inspector without connecting it to Opal.
+#[derive(Parser, Debug, Clone)]
+#[command(
+  name = "xornet-reporter",
+  version,
+  after_help = "More info at https://github.com/xornet-cloud/Reporter"
+)]
 pub struct ArgParser {
-  pub interval: f64,
+  /// Uses this config file instead of searching for one
+  #[arg(short, long, global = true, value_name = "PATH")]
+  pub config: Option<String>,
+
+  #[command(subcommand)]
+  pub command: Option<Command>,
+
+  /// Running without a subcommand is the same as `run`
+  #[command(flatten)]
+  pub run: RunArgs,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+  /// Collects and reports the data every interval (default)
+  Run(RunArgs),
+  /// Signs the machine up to Opal with an authentication key and saves the access token
+  Signup {
+    /// The key shown when adding a machine to Opal
+    key: String,
+  },
+  /// Inspects the config
+  #[command(subcommand)]
+  Config(ConfigCommand),
+  /// Collects a single snapshot of the data, prints it and exits
+  #[command(alias = "collect-once")]
+  Collect {
+    /// How the snapshot is printed
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Json)]
+    format: OutputFormat,
+  },
+  /// Shows the config in use, the configured sinks and the buffered samples
+  Status,
+  /// Prints the completion script for a shell
+  Completions {
+    #[arg(value_enum)]
+    shell: Shell,
+  },
+  /// Prints the man page source in ronn format
+  #[command(hide = true)]
+  Man,
+}
+
+/// How `collect` prints the snapshot
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+  Json,
+  Yaml,
+  /// Human readable tables
+  Table,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+  /// Checks the config and prints it with its secrets redacted, exits with 1 on problems
+  Check,
+  /// Prints the effective config and where each value came from, with its secrets redacted
+  Print,
+}
+
+/// The options of the long running reporter
+#[derive(Args, Debug, Clone, Default)]
+pub struct RunArgs {
+  /// Data collection interval in seconds, overrides `collection.interval` from the config
+  #[arg(short, long, value_name = "SECONDS", value_parser = parse_interval)]
+  pub interval: Option<f64>,
+  /// Disables sending data to Opal's backend
+  #[arg(long)]
   pub offline: bool,
+  /// Serves Prometheus metrics on http://ADDRESS/metrics
+  #[arg(long, value_name = "ADDRESS")]
+  pub prometheus: Option<String>,
+  /// Writes the data as JSON lines to a rotating file, or to stdout when PATH is -
+  #[arg(short, long, value_name = "PATH")]
+  pub output: Option<String>,
+  /// Shows a live dashboard in the terminal instead of reporting
+  #[arg(short, long)]
+  pub tui: bool,
+  /// Accepted for older service files, does nothing
+  #[arg(short, long, hide = true)]
+  pub silent: bool,
+}
+
+fn parse_interval(value: &str) -> Result<f64, String> {
+  match value.parse::<f64>() {
+    Ok(interval) if interval.is_finite() && interval > 0.0 => Ok(interval),
+    _ => Err(format!(
+      "{} is not a number of seconds greater than 0",
+      value
+    )),
+  }
 }
 
 impl ArgParser {
-  pub async fn new() -> Result<ArgParser> {
-    let mut arg_parser = ArgParser {
-      interval: 1.0,
-      offline: false,
-    };
-    let args: Vec<String> = std::env::args().collect();
-    let mut index: usize = 0;
-    while args.len() > index {
-      let arg: &str = &args[index];
-      match arg {
-        "-h" | "--help" => {
-          println!("\n{} Usage:", "●".green());
-          println!("    {} {}", "xornet".yellow(), "[options]".bright_black());
-          println!("\n{} Options:", "●".blue());
-          println!(
-            "    -h,  --help                         : {}",
-            "Show this help".white()
-          );
-          println!(
-            "    -v,  --version                      : {}",
-            "Show version and exit".white()
-          );
-          println!(
-            "    -su, --signup <key>                 : {}",
-            "Sign up the machine with an authentication key to Xornet for online features".white()
-          );
-          println!(
-            "    -i,  --interval   {}      : {}",
-            "(default: 1)".bright_black(),
-            "Data collection interval in seconds".white()
-          );
-          println!(
-            "    -off,  --offline  {}  : {}",
-            "(default: false)".bright_black(),
-            "Disables sending data to Xornet's backend".white()
-          );
-          println!("\n{} Examples:", "●".magenta());
-          println!("    {} {}", "$".bright_black(), "xornet".yellow());
-          println!("    {} {} -i 0.25", "$".bright_black(), "xornet".yellow());
-          println!(
-            "    {} {} -su 86F8575ADBA746BA8008C9AA48317EF6",
-            "$".bright_black(),
-            "xornet".yellow(),
-          );
-          println!(
-            "\n    {}",
-            "More info at https://github.com/xornet-cloud/Reporter".bright_black()
-          );
-
-          println!();
-          std::process::exit(0);
-        }
-        "-v" | "--version" => {
-          println!("xornet v{}", env!("CARGO_PKG_VERSION"));
-          std::process::exit(0);
-        }
-        "-off" | "--offline" => {
-          arg_parser.offline = true;
-        }
-        "-su" | "--signup" => {
-          if args.len() > index + 1 {
-            index += 1;
-            let two_factor_key = &args[index];
-            let config_manager: ConfigManager = ConfigManager::new()?;
-
-            if config_manager.config.backend_hostname.is_empty() {
-              println!(
-                "{}",
-                "Backend Hostname is not set in the config.json, please set it and retry:".red(),
-              );
-              std::process::exit(1)
-            }
-
-            match AuthManager::signup(
-              two_factor_key,
-              &DataCollector::get_hostname()?,
-              &config_manager.config.backend_hostname,
-              &config_manager.config.uuid,
-            )
-            .await
-            {
-              Err(error) => {
-                println!("{} {}", "Signup failed:".red(), error.to_string().red());
-                std::process::exit(1)
-              }
-              Ok(response) => {
-                ConfigManager::save_access_token(&response.access_token)?;
-                println!(
-                  "{} {}\n",
-                  "Signup successful:".green(),
-                  response.access_token
-                );
-                println!(
-                                    "You can now start the reporter with the following command: \n    $ xornet --silent",
-                                );
-                std::process::exit(0)
-              }
-            }
-          } else {
-            println!(
-              "{}",
-              "Missing argument for option -su <key>, use -h for help".red()
-            );
-            std::process::exit(1);
-          }
-        }
-        "-i" | "--interval" => {
-          if args.len() > index + 1 {
-            index += 1;
-            arg_parser.interval = args[index]
-              .parse::<f64>()
-              .expect("Could not parse interval as integer");
-          } else {
-            println!(
-              "{}",
-              "Missing argument for option -i <interval>, use -h for help".red()
-            );
-            std::process::exit(1);
-          }
-        }
-        _ => {}
+  /// Parses the launch parameters, exiting with the usage on errors
+  pub fn new() -> ArgParser {
+    ArgParser::parse()
+  }
+
+  /// The subcommand to run, `run` when none was given
+  pub fn subcommand(&self) -> Command {
+    self
+      .command
+      .clone()
+      .unwrap_or_else(|| Command::Run(self.run.clone()))
+  }
+
+  /// Prints the completion script for `shell` to stdout
+  pub fn print_completions(shell: Shell) -> Result<()> {
+    let mut command = ArgParser::command();
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, "xornet-reporter", &mut script);
+    std::io::stdout().write_all(&script)?;
+    Ok(())
+  }
+
+  /// Renders the man page in ronn format from the same definitions the parser uses
+  pub fn ronn() -> String {
+    let mut command = ArgParser::command();
+    command.build();
+
+    let mut ronn = String::new();
+    let title = format!(
+      "xornet-reporter(1) -- {}",
+      command
+        .get_about()
+        .map(|about| about.to_string())
+        .unwrap_or_default()
+    );
+    let _ = writeln!(ronn, "{}\n{}\n", title, "=".repeat(title.len()));
+
+    let _ = writeln!(ronn, "## SYNOPSIS");
+    let _ = writeln!(ronn, "`xornet-reporter` \\[OPTIONS\\] \\[COMMAND\\]\n");
+
+    let _ = writeln!(ronn, "## DESCRIPTION");
+    if let Some(about) = command.get_long_about().or_else(|| command.get_about()) {
+      let _ = writeln!(ronn, "{}\n", about);
+    }
+    if let Some(after_help) = command.get_after_help() {
+      let _ = writeln!(ronn, "{}\n", after_help);
+    }
+
+    let _ = writeln!(ronn, "## OPTIONS");
+    write_ronn_options(&mut ronn, &command);
+
+    let _ = writeln!(ronn, "\n## COMMANDS");
+    for subcommand in documented_subcommands(&command) {
+      write_ronn_command(&mut ronn, subcommand, "");
+    }
+
+    ronn.push_str(RONN_FOOTER);
+    ronn
+  }
+}
+
+fn documented_subcommands(command: &clap::Command) -> impl Iterator<Item = &clap::Command> {
+  command
+    .get_subcommands()
+    .filter(|subcommand| !subcommand.is_hide_set() && subcommand.get_name() != "help")
+}
+
+fn write_ronn_command(ronn: &mut String, command: &clap::Command, parent: &str) {
+  let name = format!("{}{}", parent, command.get_name());
+  if command.has_subcommands() {
+    for subcommand in documented_subcommands(command) {
+      write_ronn_command(ronn, subcommand, &format!("{} ", name));
+    }
+    return;
+  }
+
+  let positionals: Vec<String> = command
+    .get_positionals()
+    .filter(|arg| arg.get_id() != "help" && arg.get_id() != "version")
+    .map(|arg| {
+      arg
+        .get_value_names()
+        .and_then(|names| names.first().map(|name| name.to_string()))
+        .unwrap_or_else(|| arg.get_id().to_string().to_uppercase())
+    })
+    .collect();
+  let usage = format!("`{}` {}", name, positionals.join(" "));
+  let _ = writeln!(ronn, "  * {}:", usage.trim_end());
+  if let Some(about) = command.get_about() {
+    let _ = writeln!(ronn, "    {}", about);
+  }
+  for arg in command.get_positionals() {
+    if let Some(help) = arg.get_help() {
+      let _ = writeln!(
+        ronn,
+        "    {}: {}",
+        arg.get_id().as_str().to_uppercase(),
+        help
+      );
+    }
+    let values: Vec<String> = arg
+      .get_possible_values()
+      .iter()
+      .map(|value| value.get_name().to_string())
+      .collect();
+    if !values.is_empty() {
+      let _ = writeln!(ronn, "    (one of: {})", values.join(", "));
+    }
+  }
+  if command.get_name() == "run" {
+    let _ = writeln!(
+      ronn,
+      "    Takes the same options as running without a command"
+    );
+    return;
+  }
+  for arg in command.get_arguments().filter(|arg| {
+    !arg.is_positional() && !arg.is_hide_set() && !arg.is_global_set() && arg.get_id() != "help"
+  }) {
+    let values: Vec<String> = arg
+      .get_possible_values()
+      .iter()
+      .map(|value| value.get_name().to_string())
+      .collect();
+    let _ = write!(
+      ronn,
+      "    {}: {}",
+      option_flags(arg).join(", "),
+      arg
+        .get_help()
+        .map(|help| help.to_string())
+        .unwrap_or_default()
+    );
+    if !values.is_empty() {
+      let _ = write!(ronn, " (one of: {})", values.join(", "));
+    }
+    let _ = writeln!(ronn);
+  }
+}
+
+fn write_ronn_options(ronn: &mut String, command: &clap::Command) {
+  for arg in command.get_arguments().filter(|arg| !arg.is_hide_set()) {
+    if arg.is_positional() {
+      continue;
+    }
+    let _ = writeln!(ronn, "  * {}:", option_flags(arg).join(", "));
+    if let Some(help) = arg.get_help() {
+      let _ = writeln!(ronn, "    {}", help);
+    }
+  }
+}
+
+/// The short and long form of an option along with its value name
+fn option_flags(arg: &clap::Arg) -> Vec<String> {
+  let value = Some(arg)
+    .filter(|arg| arg.get_action().takes_values())
+    .and_then(|arg| arg.get_value_names())
+    .and_then(|names| names.first().map(|name| format!(" {}", name)))
+    .unwrap_or_default();
+  arg
+    .get_short()
+    .map(|short| format!("`-{}`{}", short, value))
+    .into_iter()
+    .chain(arg.get_long().map(|long| format!("`--{}`{}", long, value)))
+    .collect()
+}
+
+/// The hand written parts of the man page after the generated options and commands
+const RONN_FOOTER: &str = "
+## FILES
+  * `/etc/opal-connector/config.json`:
+    System wide config, overridden by the other configs
+  * `./config.json`:
+    Config in the working directory used by older installs, overridden by the user config
+  * `$XDG_CONFIG_HOME/opal-connector/config.json`:
+    User config, `~/.config` is used when `XDG_CONFIG_HOME` isn't set
+
+## ENVIRONMENT
+  * `OPAL_`KEY:
+    Overrides a config key, nested keys are joined with `__` (e.g. `OPAL_ACCESS_TOKEN`)
+
+## EXAMPLES
+
+Run xornet-reporter normally:
+
+    $ xornet-reporter
+
+Run xornet with 0.25 seconds between data collection interval:
+
+    $ xornet-reporter --interval=0.25
+
+Sign the machine up:
+
+    $ xornet-reporter signup 86F8575ADBA746BA8008C9AA48317EF6
+
+Print the data as JSON lines without connecting to Xornet:
+
+    $ xornet-reporter --offline -o - | jq .data.cpu
+
+Run xornet as a local Prometheus exporter only:
+
+    $ xornet-reporter --offline --prometheus 127.0.0.1:9101
+
+Install the bash completions:
+
+    $ xornet-reporter completions bash > /etc/bash_completion.d/xornet-reporter
+";
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(args: &[&str]) -> ArgParser {
+    ArgParser::try_parse_from(std::iter::once("xornet-reporter").chain(args.iter().copied()))
+      .unwrap()
+  }
+
+  #[test]
+  fn the_config_can_come_before_the_subcommand() {
+    let args = parse(&["--config", "/opt/xornet/config.json", "run"]);
+    assert_eq!(args.config.as_deref(), Some("/opt/xornet/config.json"));
+    assert!(matches!(args.subcommand(), Command::Run(_)));
+
+    let args = parse(&["--config", "/opt/xornet/config.json", "signup", "KEY"]);
+    assert_eq!(args.config.as_deref(), Some("/opt/xornet/config.json"));
+    assert!(matches!(args.subcommand(), Command::Signup { key } if key == "KEY"));
+
+    let args = parse(&["-c", "config.json", "config", "check"]);
+    assert_eq!(args.config.as_deref(), Some("config.json"));
+    assert!(matches!(
+      args.subcommand(),
+      Command::Config(ConfigCommand::Check)
+    ));
+
+    let args = parse(&["--config", "config.json", "collect", "-f", "table"]);
+    assert!(matches!(
+      args.subcommand(),
+      Command::Collect {
+        format: OutputFormat::Table
+      }
+    ));
+  }
+
+  #[test]
+  fn the_config_can_come_after_the_subcommand() {
+    let args = parse(&["signup", "KEY", "--config", "config.json"]);
+    assert_eq!(args.config.as_deref(), Some("config.json"));
+  }
+
+  #[test]
+  fn no_subcommand_runs_with_the_given_options() {
+    let args = parse(&["--config", "config.json", "--offline", "-i", "0.5"]);
+    match args.subcommand() {
+      Command::Run(run) => {
+        assert!(run.offline);
+        assert_eq!(run.interval, Some(0.5));
       }
-      index += 1;
+      command => panic!("expected run, got {:?}", command),
     }
-    Ok(arg_parser)
+    assert!(ArgParser::try_parse_from(["xornet-reporter", "--interval", "0"]).is_err());
+  }
+
+  #[test]
+  fn the_man_page_lists_possible_values_only_when_there_are_some() {
+    let ronn = ArgParser::ronn();
+    assert!(ronn.contains("How the snapshot is printed (one of: json, yaml, table)"));
+    assert!(!ronn.contains("(one of: )"));
   }
 }
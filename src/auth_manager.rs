@@ -1,6 +1,8 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
+use crate::config_manager::TlsConfig;
+
 #[derive(Serialize, Debug)]
 pub struct SignupBody {
   pub two_factor_key: String,
@@ -28,11 +30,17 @@ impl AuthManager {
     hostname: &str,
     backend_hostname: &str,
     hardware_uuid: &str,
+    tls: &TlsConfig,
   ) -> Result<SignupResponse> {
     println!("Signing up to Xornet...");
 
-    let client = reqwest::Client::new();
-    let url = format!("https://{}/machines/@signup", backend_hostname);
+    let mut client =
+      reqwest::Client::builder().danger_accept_invalid_certs(tls.accept_invalid_certs);
+    if let Some(pem) = tls.read_ca_certificate()? {
+      client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    let client = client.build()?;
+    let url = tls.backend_url(false, backend_hostname, "/machines/@signup");
     let body = SignupBody {
       two_factor_key: two_factor_key.to_string(),
       hostname: hostname.to_string(),
@@ -57,7 +65,7 @@ impl AuthManager {
       }
       _any_other => {
         println!("{:?}", _any_other);
-        return Err(anyhow::anyhow!("Unexpected response from Xornet"));
+        Err(anyhow::anyhow!("Unexpected response from Xornet"))
       }
     }
   }
@@ -0,0 +1,472 @@
+use anyhow::Result;
+use colored::Colorize;
+use serde::Serialize;
+use std::fs;
+use std::time::Duration;
+
+use crate::arg_parser::OutputFormat;
+use crate::auth_manager::AuthManager;
+use crate::config_manager::ConfigManager;
+use crate::data_collector::DataCollector;
+use crate::sinks::{SinkConfig, WebsocketSink};
+use crate::types::{DynamicData, StaticData};
+use crate::util::format_bytes;
+
+/// Signs the machine up with the key and saves the access token to the config
+pub async fn signup(config_path: Option<&str>, two_factor_key: &str) -> Result<()> {
+  let mut config_manager: ConfigManager = ConfigManager::new(config_path)?;
+
+  match AuthManager::signup(
+    two_factor_key,
+    &DataCollector::get_hostname()?,
+    &config_manager.config.backend_hostname,
+    &config_manager.config.uuid,
+    &config_manager.config.transport.tls,
+  )
+  .await
+  {
+    Err(error) => {
+      println!("{} {}", "Signup failed:".red(), error.to_string().red());
+      std::process::exit(1)
+    }
+    Ok(response) => {
+      config_manager.save_access_token(&response.access_token)?;
+      println!(
+        "{} {}\n",
+        "Signup successful:".green(),
+        response.access_token
+      );
+      println!("Saved to {}", config_manager.path.display());
+      println!(
+        "You can now start the reporter with the following command: \n    $ xornet-reporter run",
+      );
+      Ok(())
+    }
+  }
+}
+
+/// Prints the effective config and exits with 1 if anything's wrong with it
+pub fn config_check(config_path: Option<&str>) -> Result<()> {
+  let config_manager = match ConfigManager::read(config_path) {
+    Ok(config_manager) => config_manager,
+    Err(e) => {
+      println!("{} {}", "✗".red(), e.to_string().red());
+      std::process::exit(1)
+    }
+  };
+
+  config_manager.print_sources()?;
+
+  println!();
+  match config_manager.check_access_token() {
+    Ok(_) => {
+      println!("{} {}", "✓".green(), "The config is valid".green());
+      Ok(())
+    }
+    Err(e) => {
+      println!("{} {}", "✗".red(), e.to_string().red());
+      std::process::exit(1)
+    }
+  }
+}
+
+/// Prints the effective config and where each value came from
+pub fn config_print(config_path: Option<&str>) -> Result<()> {
+  ConfigManager::read(config_path)?.print_sources()
+}
+
+/// A single snapshot of everything the reporter collects
+#[derive(Serialize)]
+pub struct Snapshot {
+  pub static_data: StaticData,
+  pub dynamic_data: DynamicData,
+}
+
+/// Collects one properly primed snapshot and prints it
+pub async fn collect(config_path: Option<&str>, format: OutputFormat) -> Result<()> {
+  let config_manager = ConfigManager::new(config_path)?;
+  let mut data_collector = DataCollector::new(&config_manager.config)?;
+  let static_data = data_collector.get_statics().await?;
+
+  // The usage and throughput are measured over one collection interval like the reporter does
+  data_collector
+    .prime(Duration::from_secs_f64(
+      config_manager.config.collection.interval,
+    ))
+    .await?;
+  let dynamic_data = data_collector.get_all_dynamic_data().await?;
+
+  let snapshot = Snapshot {
+    static_data,
+    dynamic_data,
+  };
+  match format {
+    OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&snapshot)?),
+    OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&snapshot)?),
+    OutputFormat::Table => print_table(&snapshot),
+  }
+  Ok(())
+}
+
+fn print_table(snapshot: &Snapshot) {
+  let (sd, dd) = (&snapshot.static_data, &snapshot.dynamic_data);
+  let row = |label: &str, value: String| println!("{:<12} {}", label, value);
+
+  row("Hostname", sd.hostname.clone().unwrap_or_default());
+  row(
+    "OS",
+    format!(
+      "{} {}",
+      sd.os_name.clone().unwrap_or_default(),
+      sd.os_version.clone().unwrap_or_default()
+    ),
+  );
+  row(
+    "CPU",
+    format!(
+      "{} ({} cores, {} threads)",
+      sd.cpu_model,
+      sd.cpu_cores.unwrap_or_default(),
+      sd.cpu_threads
+    ),
+  );
+  if let Some(public_ip) = sd.public_ip.as_ref() {
+    row(
+      "Location",
+      format!(
+        "{} {}, {} ({})",
+        public_ip,
+        sd.city.clone().unwrap_or_default(),
+        sd.country.clone().unwrap_or_default(),
+        sd.isp.clone().unwrap_or_default()
+      ),
+    );
+  }
+  row(
+    "Uptime",
+    format!("{}s", Duration::from_millis(dd.host_uptime).as_secs()),
+  );
+  row("Processes", dd.process_count.to_string());
+
+  let usage = &dd.cpu.usage;
+  row(
+    "CPU usage",
+    format!(
+      "{}% avg, per core: {}",
+      usage.iter().map(|u| *u as u64).sum::<u64>() / usage.len().max(1) as u64,
+      usage
+        .iter()
+        .map(|u| format!("{}%", u))
+        .collect::<Vec<String>>()
+        .join(" ")
+    ),
+  );
+  // sysinfo reports memory in kilobytes
+  row(
+    "RAM",
+    format!(
+      "{} / {}",
+      format_bytes(dd.ram.used * 1000),
+      format_bytes(dd.ram.total * 1000)
+    ),
+  );
+  row(
+    "Swap",
+    format!(
+      "{} / {}",
+      format_bytes(dd.swap.used * 1000),
+      format_bytes(dd.swap.total * 1000)
+    ),
+  );
+  for gpu in &dd.gpus {
+    row(
+      "GPU",
+      format!(
+        "#{} {}{}{}{}{}",
+        gpu.index,
+        gpu.name,
+        gpu
+          .gpu_usage
+          .map(|usage| format!(" {}%", usage))
+          .unwrap_or_default(),
+        gpu
+          .power_usage
+          .map(|milliwatts| format!(" {:.1}W", milliwatts as f64 / 1000.0))
+          .unwrap_or_default(),
+        gpu
+          .memory_used
+          .zip(gpu.memory_total)
+          .map(|(used, total)| format!(" {} / {}", format_bytes(used), format_bytes(total)))
+          .unwrap_or_default(),
+        gpu
+          .temperature
+          .map(|temperature| format!(" {}°C", temperature))
+          .unwrap_or_default()
+      ),
+    );
+    for process in gpu.processes.iter().flatten() {
+      row(
+        "",
+        format!(
+          "  {} {} {}",
+          process.pid,
+          process.name.clone().unwrap_or_default(),
+          process.memory_used.map(format_bytes).unwrap_or_default()
+        ),
+      );
+    }
+  }
+  if let Some(cgroup) = dd.cgroup.as_ref() {
+    let limit = |used: Option<u64>, max: Option<u64>| match (used, max) {
+      (Some(used), Some(max)) => format!("{} / {}", format_bytes(used), format_bytes(max)),
+      (Some(used), None) => format!("{} / unlimited", format_bytes(used)),
+      _ => "-".to_string(),
+    };
+    row("Cgroup", cgroup.path.clone());
+    row(
+      "",
+      format!(
+        "  memory {}, swap {}, {} OOM kills",
+        limit(cgroup.memory_current, cgroup.memory_max),
+        limit(cgroup.swap_current, cgroup.swap_max),
+        cgroup.oom_kills.unwrap_or_default()
+      ),
+    );
+    row(
+      "",
+      format!(
+        "  cpu {} of {}, throttled {:.1}s",
+        cgroup
+          .cpu_usage
+          .map(|usage| format!("{:.1}%", usage))
+          .unwrap_or_else(|| "-".to_string()),
+        cgroup
+          .cpu_limit
+          .map(|cores| format!("{} cores", cores))
+          .unwrap_or_else(|| "unlimited".to_string()),
+        cgroup.cpu_throttled_usec.unwrap_or_default() as f64 / 1_000_000.0
+      ),
+    );
+    for (resource, pressure) in [
+      ("cpu", &cgroup.cpu_pressure),
+      ("memory", &cgroup.memory_pressure),
+      ("io", &cgroup.io_pressure),
+    ] {
+      if let Some(pressure) = pressure {
+        row(
+          "",
+          format!(
+            "  {} pressure {:.2}% {:.2}% {:.2}%",
+            resource, pressure.some.avg10, pressure.some.avg60, pressure.some.avg300
+          ),
+        );
+      }
+    }
+    for slice in &cgroup.slices {
+      row(
+        "",
+        format!(
+          "  {} {} {}",
+          slice.name,
+          slice
+            .memory_current
+            .map(format_bytes)
+            .unwrap_or_else(|| "-".to_string()),
+          slice
+            .cpu_usage
+            .map(|usage| format!("{:.1}%", usage))
+            .unwrap_or_else(|| "-".to_string())
+        ),
+      );
+    }
+  }
+  for temp in dd.temps.iter().flatten() {
+    row("Temperature", format!("{} {:.1}°C", temp.label, temp.value));
+  }
+
+  println!();
+  println!(
+    "{:<24} {:<10} {:>10} {:>10} {:>5} {:>7} {:>12} {:>12} {:>7} {:>5}  Flags",
+    "Mount", "FS", "Used", "Total", "Use", "Inodes", "Read/s", "Write/s", "IOPS", "Busy"
+  );
+  for disk in &dd.disks {
+    let io = disk.io.clone().unwrap_or_default();
+    let inodes = match (disk.inodes_used, disk.inodes_total) {
+      (Some(used), Some(total)) => format!("{:.0}%", used as f64 / total.max(1) as f64 * 100.0),
+      _ => "-".to_string(),
+    };
+    let flags: Vec<&str> = [(disk.read_only, "ro"), (disk.removable, "removable")]
+      .iter()
+      .filter(|(set, _)| *set)
+      .map(|(_, flag)| *flag)
+      .collect();
+    println!(
+      "{:<24} {:<10} {:>10} {:>10} {:>4.0}% {:>7} {:>12} {:>12} {:>7.0} {:>4.0}%  {}",
+      disk.mount,
+      disk.fs,
+      format_bytes(disk.used),
+      format_bytes(disk.total),
+      disk.used as f64 / disk.total.max(1) as f64 * 100.0,
+      inodes,
+      format_bytes(io.read_bytes_per_second),
+      format_bytes(io.write_bytes_per_second),
+      io.read_iops + io.write_iops,
+      io.utilization,
+      flags.join(",")
+    );
+  }
+
+  if !dd.top_processes.is_empty() {
+    println!();
+    println!(
+      "{:>8} {:<16} {:<12} {:>6} {:>10} {:>12} {:>12} {:>10}  Command",
+      "PID", "Name", "User", "CPU", "Memory", "Read/s", "Write/s", "GPU"
+    );
+    for process in &dd.top_processes {
+      println!(
+        "{:>8} {:<16} {:<12} {:>5.1}% {:>10} {:>12} {:>12} {:>10}  {}",
+        process.pid,
+        process.name,
+        process.user.clone().unwrap_or_default(),
+        process.cpu_usage,
+        format_bytes(process.memory),
+        format_bytes(process.disk_read_bytes_per_second),
+        format_bytes(process.disk_write_bytes_per_second),
+        process
+          .gpu_memory
+          .map(format_bytes)
+          .unwrap_or_else(|| "-".to_string()),
+        process.command.clone().unwrap_or_default()
+      );
+    }
+  }
+
+  if let Some(systemd) = dd.systemd.as_ref() {
+    println!();
+    println!("Failed systemd units: {}", systemd.failed_units);
+    if !systemd.units.is_empty() {
+      println!(
+        "{:<32} {:<10} {:<10} {:<12} {:>8}  Since",
+        "Unit", "Load", "Active", "Sub", "Restarts"
+      );
+    }
+    for unit in &systemd.units {
+      println!(
+        "{:<32} {:<10} {:<10} {:<12} {:>8}  {}",
+        unit.name,
+        unit.load_state,
+        unit.active_state,
+        unit.sub_state,
+        unit
+          .restarts
+          .map(|restarts| restarts.to_string())
+          .unwrap_or_else(|| "-".to_string()),
+        unit
+          .since
+          .map(|since| since.to_string())
+          .unwrap_or_else(|| "-".to_string())
+      );
+    }
+  }
+
+  if !dd.containers.is_empty() {
+    println!();
+    println!(
+      "{:<12} {:<20} {:<8} {:<10} {:<10} {:>8} {:>6} {:>21} {:>12} {:>12}  Image",
+      "ID", "Name", "Runtime", "State", "Health", "Restarts", "CPU", "Memory", "Rx/s", "Tx/s"
+    );
+    for container in &dd.containers {
+      let per_second = |bits: Option<u64>| {
+        bits
+          .map(|bits| format_bytes(bits / 8))
+          .unwrap_or_else(|| "-".to_string())
+      };
+      println!(
+        "{:<12} {:<20} {:<8} {:<10} {:<10} {:>8} {:>6} {:>21} {:>12} {:>12}  {}",
+        container.id,
+        container.name,
+        container.runtime,
+        container.state,
+        container.health.clone().unwrap_or_else(|| "-".to_string()),
+        container.restart_count,
+        container
+          .cpu_usage
+          .map(|usage| format!("{:.1}%", usage))
+          .unwrap_or_else(|| "-".to_string()),
+        match (container.memory_used, container.memory_limit) {
+          (Some(used), Some(limit)) => format!("{} / {}", format_bytes(used), format_bytes(limit)),
+          (Some(used), None) => format_bytes(used),
+          _ => "-".to_string(),
+        },
+        per_second(container.network_rx),
+        per_second(container.network_tx),
+        container.image
+      );
+    }
+  }
+
+  println!();
+  println!(
+    "{:<16} {:<10} {:<8} {:>12} {:>12} {:>10}  Addresses",
+    "Interface", "Type", "State", "Rx/s", "Tx/s", "Link"
+  );
+  for nic in &dd.network {
+    let per_second = |bits: u64| format_bytes(bits / 8);
+    println!(
+      "{:<16} {:<10} {:<8} {:>12} {:>12} {:>10}  {}",
+      nic.n,
+      format!("{:?}", nic.r#type).to_lowercase(),
+      nic.operstate.clone().unwrap_or_default(),
+      per_second(nic.rx),
+      per_second(nic.tx),
+      nic
+        .s
+        .map(|speed| format!("{}Mb/s", speed))
+        .unwrap_or_else(|| "unknown".to_string()),
+      nic.addresses.join(" ")
+    );
+  }
+}
+
+/// Shows the config in use, where the data is sent to and what's waiting to be back-filled
+pub fn status(config_path: Option<&str>) -> Result<()> {
+  let config_manager = ConfigManager::read(config_path)?;
+  let config = &config_manager.config;
+  let label = |label: &str| format!("{:<15}", label).bright_black();
+
+  println!("{} {}", label("Config:"), config_manager.path.display());
+  println!("{} {}", label("Backend:"), config.backend_hostname);
+  println!(
+    "{} {}",
+    label("Access token:"),
+    match config_manager.check_access_token() {
+      Ok(_) => "set".green(),
+      Err(_) => "missing, run `xornet-reporter signup <key>`".red(),
+    }
+  );
+  println!("{} {}", label("UUID:"), config.uuid);
+  println!("{} {}s", label("Interval:"), config.collection.interval);
+
+  println!("{}", label("Sinks:"));
+  for sink_config in &config.sinks {
+    println!("  - {}", serde_json::to_string(&sink_config.redacted())?);
+  }
+
+  if config.sinks.contains(&SinkConfig::Websocket) {
+    let buffer_path = WebsocketSink::buffer_path(&config_manager);
+    // Only read here so a running reporter's buffer isn't touched
+    let (samples, bytes) = match fs::read_to_string(&buffer_path) {
+      Ok(content) => (content.lines().count(), content.len()),
+      Err(_) => (0, 0),
+    };
+    println!(
+      "{} {} samples ({} bytes) in {}",
+      label("Buffered:"),
+      samples,
+      bytes,
+      buffer_path.display()
+    );
+  }
+
+  Ok(())
+}
@@ -1,65 +1,597 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::path::Path;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
 use uuid::Uuid;
 
+use crate::filter::PatternFilter;
+use crate::sinks::SinkConfig;
+
+pub const DEFAULT_BACKEND_HOSTNAME: &str = "xbackend.otiskujawa.net";
+
+const CONFIG_FILE_NAME: &str = "config.json";
+const CONFIG_DIR_NAME: &str = "opal-connector";
+const SYSTEM_CONFIG_DIR: &str = "/etc/opal-connector";
+const ENV_PREFIX: &str = "OPAL_";
+/// Printed instead of secrets like the access token
+pub const REDACTED: &str = "<redacted>";
+
+/// The version of the config schema, bumped whenever keys move around so
+/// older config files can be migrated on load
+pub const CONFIG_VERSION: u64 = 3;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
+  #[serde(default = "default_version")]
+  pub version: u64,
   pub access_token: String,
   pub backend_hostname: String,
   pub uuid: String,
+  #[serde(default)]
+  pub collection: CollectionConfig,
+  #[serde(default)]
+  pub filters: FiltersConfig,
+  #[serde(default)]
+  pub transport: TransportConfig,
+  /// Where the collected data gets sent to, the Opal backend by default
+  #[serde(default = "default_sinks")]
+  pub sinks: Vec<SinkConfig>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      version: CONFIG_VERSION,
+      access_token: String::new(),
+      backend_hostname: DEFAULT_BACKEND_HOSTNAME.to_string(),
+      uuid: String::new(),
+      collection: CollectionConfig::default(),
+      filters: FiltersConfig::default(),
+      transport: TransportConfig::default(),
+      sinks: default_sinks(),
+    }
+  }
+}
+
+impl Config {
+  /// A copy without the access token and the secrets of the sinks, safe to print
+  pub fn redacted(&self) -> Config {
+    let mut config = self.clone();
+    if !config.access_token.is_empty() {
+      config.access_token = REDACTED.to_string();
+    }
+    config.sinks = config.sinks.iter().map(SinkConfig::redacted).collect();
+    config
+  }
+
+  /// Checks the values serde can't, the error names the offending key
+  pub fn validate(&self) -> std::result::Result<(), (String, String)> {
+    if !self.collection.interval.is_finite() || self.collection.interval <= 0.0 {
+      return Err((
+        "collection.interval".to_string(),
+        "must be a number of seconds greater than 0".to_string(),
+      ));
+    }
+    if self.collection.refresh_iterations == 0 {
+      return Err((
+        "collection.refresh_iterations".to_string(),
+        "must be at least 1".to_string(),
+      ));
+    }
+    for (index, redact) in self.collection.top_processes.redact.iter().enumerate() {
+      if let Err(e) = Regex::new(redact) {
+        return Err((
+          format!("collection.top_processes.redact[{}]", index),
+          e.to_string(),
+        ));
+      }
+    }
+    for (name, filter) in [
+      ("disks", &self.filters.disks.patterns),
+      ("disks.fs_types", &self.filters.disks.fs_types),
+      ("network", &self.filters.network.patterns),
+    ] {
+      if let Err(e) = filter.compile() {
+        return Err((format!("filters.{}", name), e.to_string()));
+      }
+    }
+    if let Some(ca_certificate) = self.transport.tls.ca_certificate.as_ref() {
+      if !Path::new(ca_certificate).is_file() {
+        return Err((
+          "transport.tls.ca_certificate".to_string(),
+          format!("{} does not exist", ca_certificate),
+        ));
+      }
+    }
+    Ok(())
+  }
+}
+
+fn default_version() -> u64 {
+  1
+}
+
+fn default_sinks() -> Vec<SinkConfig> {
+  vec![SinkConfig::Websocket]
+}
+
+/// What gets collected and how often
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CollectionConfig {
+  /// Seconds between two collections, `--interval` overrides it
+  pub interval: f64,
+  /// How many collections pass between refreshing the list of network interfaces and their link speeds
+  pub refresh_iterations: usize,
+  pub collectors: CollectorsConfig,
+  /// Where the public IP, country, city and ISP are looked up
+  pub geolocation_url: String,
+  pub top_processes: TopProcessesConfig,
+  /// The systemd units whose state is reported, `.service` is implied like with `systemctl`
+  pub systemd_units: Vec<String>,
+  /// The Unix sockets of the Docker compatible APIs the containers are read from,
+  /// the ones that don't exist are skipped
+  pub container_sockets: Vec<String>,
+}
+
+impl Default for CollectionConfig {
+  fn default() -> Self {
+    Self {
+      interval: 1.0,
+      refresh_iterations: 60,
+      collectors: CollectorsConfig::default(),
+      geolocation_url: "https://ipwhois.app/json/".to_string(),
+      top_processes: TopProcessesConfig::default(),
+      systemd_units: Vec::new(),
+      container_sockets: vec![
+        "/var/run/docker.sock".to_string(),
+        "/run/podman/podman.sock".to_string(),
+      ],
+    }
+  }
+}
+
+/// The collectors that can be turned off, CPU, memory and uptimes are always collected
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CollectorsConfig {
+  pub gpu: bool,
+  pub temps: bool,
+  pub disks: bool,
+  pub network: bool,
+  pub processes: bool,
+  pub geolocation: bool,
+  /// The processes using the most CPU, memory, disk and GPU memory, see `TopProcessesConfig`
+  pub top_processes: bool,
+  /// The state of the units in `collection.systemd_units` and how many have failed
+  pub systemd: bool,
+  /// The containers of the Docker and Podman daemons in `collection.container_sockets`
+  pub containers: bool,
+  /// The cgroup v2 limits, usage and pressure of the reporter's own cgroup and the top level slices
+  pub cgroup: bool,
+}
+
+impl Default for CollectorsConfig {
+  fn default() -> Self {
+    Self {
+      gpu: true,
+      temps: true,
+      disks: true,
+      network: true,
+      processes: true,
+      geolocation: true,
+      top_processes: false,
+      systemd: false,
+      containers: false,
+      cgroup: true,
+    }
+  }
 }
 
-/// Manages the config.json for the reporter
+/// What's reported about the busiest processes when the `top_processes` collector is on
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TopProcessesConfig {
+  /// How many processes are picked by each of CPU, memory, disk I/O and GPU memory usage
+  pub count: usize,
+  /// Whether the command lines are reported, they can hold secrets passed as arguments
+  pub command_line: bool,
+  /// Regular expressions masked in the command lines, only their `secret` group when they
+  /// have one so `--password=hunter2` can become `--password=***`
+  pub redact: Vec<String>,
+}
+
+impl Default for TopProcessesConfig {
+  fn default() -> Self {
+    Self {
+      count: 5,
+      command_line: true,
+      redact: vec![
+        r"(?i)(?:^|[^a-z0-9])(?:password|passwd|pwd|secret|token|api[_-]?key|auth)(?:[_-][a-z0-9_-]*)?[=:\s]+(?P<secret>\S+)"
+          .to_string(),
+        r"://[^:/\s@]+:(?P<secret>[^@/\s]+)@".to_string(),
+      ],
+    }
+  }
+}
+
+/// Which disks and network interfaces are reported
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct FiltersConfig {
+  pub disks: DiskFilterConfig,
+  pub network: NetworkFilterConfig,
+}
+
+/// Which mounted filesystems are reported
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DiskFilterConfig {
+  /// Matched against both the device and the mount point of the disk
+  #[serde(flatten)]
+  pub patterns: PatternFilter,
+  /// Matched against the filesystem type like `ext4` or `tmpfs`
+  pub fs_types: PatternFilter,
+  /// Reports a filesystem mounted in several places, like bind mounts, only once
+  pub deduplicate: bool,
+}
+
+impl Default for DiskFilterConfig {
+  fn default() -> Self {
+    Self {
+      patterns: PatternFilter::new(
+        &[],
+        &["/var/lib/docker/*", "/var/lib/containers/*", "/snap/*"],
+      ),
+      fs_types: PatternFilter::new(&[], &["squashfs", "overlay", "tmpfs", "devtmpfs", "ramfs"]),
+      deduplicate: true,
+    }
+  }
+}
+
+/// Which network interfaces are reported and have their link speed looked up
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct NetworkFilterConfig {
+  /// Matched against the name of the interface
+  #[serde(flatten)]
+  pub patterns: PatternFilter,
+  /// Skips the interfaces without a device behind them in `/sys/class/net/*/device`,
+  /// like `veth*`, `docker0`, `br-*` and `cali*`
+  pub exclude_virtual: bool,
+}
+
+impl Default for NetworkFilterConfig {
+  fn default() -> Self {
+    Self {
+      patterns: PatternFilter::new(&[], &["*NPCAP*", "lo*", "loopback*"]),
+      exclude_virtual: false,
+    }
+  }
+}
+
+/// How the data gets to the backend
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TransportConfig {
+  /// The longest delay in seconds between two reconnect attempts to the backend
+  pub reconnect_max_delay: u64,
+  /// How many bytes of samples to keep around while the backend is unreachable
+  pub buffer_max_bytes: usize,
+  /// How old in seconds a buffered sample can get before it's dropped
+  pub buffer_max_age: u64,
+  pub tls: TlsConfig,
+}
+
+impl Default for TransportConfig {
+  fn default() -> Self {
+    Self {
+      reconnect_max_delay: 60,
+      buffer_max_bytes: 16 * 1024 * 1024,
+      buffer_max_age: 24 * 60 * 60,
+      tls: TlsConfig::default(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TlsConfig {
+  /// Whether the backend is reached over `wss://` and `https://`, only turn it off for local backends
+  pub enabled: bool,
+  /// Accepts self-signed and expired certificates
+  pub accept_invalid_certs: bool,
+  /// Path to an extra PEM encoded CA certificate to trust
+  pub ca_certificate: Option<String>,
+}
+
+impl Default for TlsConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      accept_invalid_certs: false,
+      ca_certificate: None,
+    }
+  }
+}
+
+impl TlsConfig {
+  /// The URL of `path` on the backend using the websocket or the HTTP scheme
+  pub fn backend_url(&self, websocket: bool, backend_hostname: &str, path: &str) -> String {
+    let scheme = match (websocket, self.enabled) {
+      (true, true) => "wss",
+      (true, false) => "ws",
+      (false, true) => "https",
+      (false, false) => "http",
+    };
+    format!("{}://{}{}", scheme, backend_hostname, path)
+  }
+
+  /// Reads the extra CA certificate if one is set
+  pub fn read_ca_certificate(&self) -> Result<Option<Vec<u8>>> {
+    match self.ca_certificate.as_ref() {
+      Some(path) => Ok(Some(fs::read(path).map_err(|e| {
+        anyhow!("Could not read the CA certificate {}: {}", path, e)
+      })?)),
+      None => Ok(None),
+    }
+  }
+
+  /// Builds the connector used for the websocket connection
+  pub fn connector(&self) -> Result<native_tls::TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    builder.danger_accept_invalid_certs(self.accept_invalid_certs);
+    if let Some(pem) = self.read_ca_certificate()? {
+      builder.add_root_certificate(native_tls::Certificate::from_pem(&pem)?);
+    }
+    Ok(builder.build()?)
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+  #[error("{path} is not valid JSON ({message}), fix or remove it, it's left untouched so the access token isn't lost")]
+  Unparsable { path: String, message: String },
+  #[error("{0} has to contain a JSON object")]
+  NotAnObject(String),
+  #[error("No access token is set, sign the machine up with `xornet-reporter signup <key>` or set OPAL_ACCESS_TOKEN")]
+  MissingAccessToken,
+  #[error("`backend_hostname` {value:?} in {origin} is not a valid hostname, expected something like backend.example.com or 10.0.0.2:8080")]
+  InvalidHostname { value: String, origin: ConfigSource },
+  #[error(
+    "`uuid` {value:?} in {origin} is not a valid UUID, remove it to have a new one generated"
+  )]
+  InvalidUuid { value: String, origin: ConfigSource },
+  #[error("Invalid value for `{key}` in {origin}: {message}")]
+  InvalidValue {
+    key: String,
+    origin: ConfigSource,
+    message: String,
+  },
+  #[error("{0} is from a newer version of the reporter (config version {1}, this one supports up to {CONFIG_VERSION})")]
+  UnsupportedVersion(PathBuf, u64),
+}
+
+/// Where the effective value of a config key came from
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigSource {
+  Default,
+  File(PathBuf),
+  Environment(String),
+}
+
+impl fmt::Display for ConfigSource {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ConfigSource::Default => write!(f, "default"),
+      ConfigSource::File(path) => write!(f, "{}", path.display()),
+      ConfigSource::Environment(name) => write!(f, "${}", name),
+    }
+  }
+}
+
+/// Manages the config.json for the reporter.
+///
+/// The config is layered, later layers override the keys set by the earlier ones:
+/// 1. the defaults
+/// 2. `/etc/opal-connector/config.json`
+/// 3. the file given with `--config`, otherwise `config.json` in the working directory which
+///    older installs used and `$XDG_CONFIG_HOME/opal-connector/config.json`, the more specific
+///    user config wins
+/// 4. `OPAL_*` environment variables, nested keys are joined with `__`
+///    (`OPAL_ACCESS_TOKEN`, `OPAL_BACKEND_HOSTNAME`...)
 #[derive(Clone, Debug)]
 pub struct ConfigManager {
   pub config: Config,
+  /// The file changes are saved to, the most specific layer
+  pub path: PathBuf,
+  /// Where every key got its effective value from
+  pub sources: BTreeMap<String, ConfigSource>,
+  /// The content of the file at `path` so saving doesn't flatten the other layers into it
+  layer: Value,
+  /// Loaded without creating, migrating or adding a UUID to any of the files
+  read_only: bool,
 }
 
 impl ConfigManager {
-  pub fn new() -> Result<ConfigManager> {
-    let config = ConfigManager::load_config()?;
-    Ok(Self { config })
+  pub fn new(config_path: Option<&str>) -> Result<ConfigManager> {
+    ConfigManager::open(config_path, false)
   }
 
-  pub fn save_access_token(access_token: &str) -> Result<()> {
-    let mut config = ConfigManager::load_config()?;
-    config.access_token = access_token.to_string();
-    ConfigManager::save_config(config)?;
-    Ok(())
+  /// Loads the config like `new` but leaves the files as they are, for the commands that
+  /// only look at it. Migrations are applied in memory and a missing UUID isn't generated
+  pub fn read(config_path: Option<&str>) -> Result<ConfigManager> {
+    ConfigManager::open(config_path, true)
+  }
+
+  fn open(config_path: Option<&str>, read_only: bool) -> Result<ConfigManager> {
+    let layer_paths = ConfigManager::layer_paths(config_path);
+    let path = match config_path {
+      Some(config_path) => PathBuf::from(config_path),
+      None => layer_paths
+        .iter()
+        .rev()
+        .find(|path| path.exists())
+        .cloned()
+        .unwrap_or_else(ConfigManager::default_path),
+    };
+    ConfigManager::load(&layer_paths, path, read_only)
+  }
+
+  /// Loads the config from the given layers, changes are saved to `path`
+  fn load(layer_paths: &[PathBuf], path: PathBuf, read_only: bool) -> Result<ConfigManager> {
+    let mut this = Self {
+      config: Config::default(),
+      path,
+      sources: BTreeMap::new(),
+      layer: Value::Object(Map::new()),
+      read_only,
+    };
+    this.load_config(layer_paths)?;
+    Ok(this)
   }
 
-  /// Saves the modified config to the config file
-  pub fn save_config(config: Config) -> Result<()> {
-    let file = File::create("config.json")?;
-    serde_json::to_writer_pretty(file, &config)?;
+  /// The config files in the order they're layered in
+  pub fn layer_paths(config_path: Option<&str>) -> Vec<PathBuf> {
+    ConfigManager::layer_paths_with(config_path, ConfigManager::user_path())
+  }
+
+  /// The config files in the order they're layered in with the given user config
+  fn layer_paths_with(config_path: Option<&str>, user_path: Option<PathBuf>) -> Vec<PathBuf> {
+    let mut paths = vec![Path::new(SYSTEM_CONFIG_DIR).join(CONFIG_FILE_NAME)];
+    match config_path {
+      Some(config_path) => paths.push(PathBuf::from(config_path)),
+      None => {
+        paths.push(PathBuf::from(CONFIG_FILE_NAME));
+        paths.extend(user_path);
+      }
+    }
+    paths
+  }
+
+  /// `$XDG_CONFIG_HOME/opal-connector/config.json`, falling back to `~/.config`
+  fn user_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+      .filter(|dir| !dir.is_empty())
+      .map(PathBuf::from)
+      .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME))
+  }
+
+  /// Where a new config is created when there's none yet
+  fn default_path() -> PathBuf {
+    ConfigManager::user_path().unwrap_or_else(|| PathBuf::from(CONFIG_FILE_NAME))
+  }
+
+  pub fn save_access_token(&mut self, access_token: &str) -> Result<()> {
+    self.config.access_token = access_token.to_string();
+    self.set("access_token", Value::String(access_token.to_string()));
+    self.save_config()
+  }
+
+  /// Sets a key in the file at `path`, call `save_config` to write it
+  fn set(&mut self, key: &str, value: Value) {
+    if let Value::Object(layer) = &mut self.layer {
+      layer.insert(key.to_string(), value);
+    }
+    self
+      .sources
+      .insert(key.to_string(), ConfigSource::File(self.path.clone()));
+  }
+
+  /// Saves the modified config to the config file, only its owner can read it since
+  /// it holds the access token. It's written next to the file and renamed over it
+  /// so a crash halfway through can't lose the token
+  pub fn save_config(&self) -> Result<()> {
+    if let Some(parent) = self
+      .path
+      .parent()
+      .filter(|parent| !parent.as_os_str().is_empty())
+    {
+      fs::create_dir_all(parent)?;
+    }
+    let temp_path = self.path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(target_family = "unix")]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temp_path)?;
+    // The mode only applies to new files, one left behind by a crash keeps its own
+    #[cfg(target_family = "unix")]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    serde_json::to_writer_pretty(&mut file, &self.layer)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &self.path)?;
     Ok(())
   }
 
-  /// Loads the config file from disk or creates a new one if it doesn't exist.
-  pub fn load_config() -> Result<Config> {
-    if !Path::new("config.json").exists() {
-      Ok(ConfigManager::create_config()?)
-    } else {
-      let file = File::open("config.json")?;
+  /// Loads and layers the config files, creating a new one if none of them exist.
+  fn load_config(&mut self, layer_paths: &[PathBuf]) -> Result<()> {
+    let mut merged = serde_json::to_value(Config::default())?;
+    mark_sources(&merged, "", &ConfigSource::Default, &mut self.sources);
 
-      let result = serde_json::from_reader(file);
-      match result {
-        Ok(config) => {
-          let mut config: Config = config;
-          if config.uuid.is_empty() {
-            config.uuid = ConfigManager::create_uuid();
-          }
-          if config.backend_hostname.is_empty() {
-            config.uuid = "xbackend.otiskujawa.net".to_string();
-          }
-          ConfigManager::save_config(config.clone())?;
-          Ok(config)
+    if !layer_paths.iter().any(|path| path.exists()) && !self.read_only {
+      return self.create_config();
+    }
+
+    for path in layer_paths.iter().filter(|path| path.exists()) {
+      // A broken file is never replaced, it still holds the access token
+      let mut layer = serde_json::from_reader::<_, Value>(File::open(path)?).map_err(|e| {
+        ConfigError::Unparsable {
+          path: path.display().to_string(),
+          message: e.to_string(),
         }
-        Err(_) => Ok(ConfigManager::create_config()?),
+      })?;
+      if !layer.is_object() {
+        return Err(ConfigError::NotAnObject(path.display().to_string()).into());
       }
+
+      if migrate(&mut layer, path)? && *path == self.path && !self.read_only {
+        eprintln!(
+          "Migrated {} to config version {}",
+          path.display(),
+          CONFIG_VERSION
+        );
+        self.layer = layer.clone();
+        self.save_config()?;
+      }
+
+      merge(
+        &mut merged,
+        &layer,
+        "",
+        &ConfigSource::File(path.clone()),
+        &mut self.sources,
+      );
+      if *path == self.path {
+        self.layer = layer;
+      }
+    }
+
+    apply_env_overrides(&mut merged, "", &mut self.sources)?;
+    self.config = self.parse(merged)?;
+
+    if self.config.uuid.is_empty() && !self.read_only {
+      self.config.uuid = ConfigManager::create_uuid();
+      self.set("uuid", Value::String(self.config.uuid.clone()));
+      self.save_config()?;
     }
+
+    Ok(())
+  }
+
+  /// Fails when there's no access token to log in to the backend with
+  pub fn check_access_token(&self) -> Result<(), ConfigError> {
+    if self.config.access_token.trim().is_empty() {
+      return Err(ConfigError::MissingAccessToken);
+    }
+    Ok(())
   }
 
   pub fn create_uuid() -> String {
@@ -67,13 +599,825 @@ impl ConfigManager {
   }
 
   /// Creates a new config file with an empty access token and default backend address.
-  pub fn create_config() -> Result<Config> {
+  fn create_config(&mut self) -> Result<()> {
     let config = Config {
-      access_token: String::new(),
-      backend_hostname: "xbackend.otiskujawa.net".to_string(),
       uuid: ConfigManager::create_uuid(),
+      ..Config::default()
     };
-    ConfigManager::save_config(config.clone())?;
+
+    self.layer = serde_json::to_value(&config)?;
+    self.sources.clear();
+    mark_sources(
+      &self.layer,
+      "",
+      &ConfigSource::File(self.path.clone()),
+      &mut self.sources,
+    );
+
+    let mut merged = self.layer.clone();
+    apply_env_overrides(&mut merged, "", &mut self.sources)?;
+    self.config = self.parse(merged)?;
+
+    self.save_config()
+  }
+
+  /// Deserializes and validates the merged layers, errors point at the
+  /// offending key and the layer its value came from
+  fn parse(&self, merged: Value) -> Result<Config> {
+    let config: Config = serde_path_to_error::deserialize(merged).map_err(|e| {
+      let key = e.path().to_string();
+      self.invalid_value(&key, e.into_inner().to_string())
+    })?;
+    config
+      .validate()
+      .map_err(|(key, message)| self.invalid_value(&key, message))?;
+
+    if !is_valid_hostname(&config.backend_hostname) {
+      return Err(
+        ConfigError::InvalidHostname {
+          value: config.backend_hostname,
+          origin: self.source_of("backend_hostname"),
+        }
+        .into(),
+      );
+    }
+    // An empty UUID gets generated after loading
+    if !config.uuid.is_empty() && Uuid::parse_str(&config.uuid).is_err() {
+      return Err(
+        ConfigError::InvalidUuid {
+          value: config.uuid,
+          origin: self.source_of("uuid"),
+        }
+        .into(),
+      );
+    }
+
     Ok(config)
   }
+
+  fn invalid_value(&self, key: &str, message: String) -> ConfigError {
+    ConfigError::InvalidValue {
+      key: key.to_string(),
+      origin: self.source_of(key),
+      message,
+    }
+  }
+
+  /// Where the value of `key` came from, array items and missing keys
+  /// are attributed to the closest parent that has a source
+  pub fn source_of(&self, key: &str) -> ConfigSource {
+    let mut key = key.split('[').next().unwrap_or_default();
+    loop {
+      if let Some(source) = self.sources.get(key) {
+        return source.clone();
+      }
+      if let Some((_, source)) = self
+        .sources
+        .iter()
+        .find(|(existing, _)| existing.starts_with(&format!("{}.", key)))
+      {
+        return source.clone();
+      }
+      match key.rfind('.') {
+        Some(index) => key = &key[..index],
+        None => return ConfigSource::Default,
+      }
+    }
+  }
+
+  /// Prints every key of the effective config along with where it came from
+  pub fn print_sources(&self) -> Result<()> {
+    let effective = serde_json::to_value(self.config.redacted())?;
+    println!("{} {}", "Saving to:".bright_black(), self.path.display());
+    for (key, source) in &self.sources {
+      let value = key
+        .split('.')
+        .try_fold(&effective, |value, part| value.get(part));
+      let value = match value {
+        Some(value) => value.to_string(),
+        None => continue,
+      };
+      println!(
+        "{} = {} {}",
+        key,
+        value,
+        format!("({})", source).bright_black()
+      );
+    }
+    Ok(())
+  }
+}
+
+/// Whether `hostname` is a host name or IP address with an optional port,
+/// without a scheme or path since those are added by the reporter
+pub fn is_valid_hostname(hostname: &str) -> bool {
+  let (host, port) = if let Some(rest) = hostname.strip_prefix('[') {
+    // [::1]:8080
+    match rest.split_once(']') {
+      Some((host, port)) => {
+        if host.parse::<std::net::Ipv6Addr>().is_err() {
+          return false;
+        }
+        match port {
+          "" => (None, None),
+          port => match port.strip_prefix(':') {
+            Some(port) => (None, Some(port)),
+            None => return false,
+          },
+        }
+      }
+      None => return false,
+    }
+  } else {
+    match hostname.rsplit_once(':') {
+      Some((host, port)) => (Some(host), Some(port)),
+      None => (Some(hostname), None),
+    }
+  };
+
+  if let Some(port) = port {
+    if port.parse::<u16>().map_or(true, |port| port == 0) {
+      return false;
+    }
+  }
+
+  match host {
+    Some(host) => {
+      !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+          !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+              .chars()
+              .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+    }
+    None => true,
+  }
+}
+
+/// Brings a config file written by an older version up to `CONFIG_VERSION`,
+/// returns whether anything was changed
+fn migrate(layer: &mut Value, path: &Path) -> Result<bool> {
+  let version = layer
+    .get("version")
+    .and_then(Value::as_u64)
+    .unwrap_or_else(default_version);
+  if version > CONFIG_VERSION {
+    return Err(ConfigError::UnsupportedVersion(path.to_path_buf(), version).into());
+  }
+  if version == CONFIG_VERSION {
+    return Ok(false);
+  }
+
+  if let Value::Object(map) = layer {
+    // 1 -> 2: the reconnect and buffer settings moved under `transport`
+    if version < 2 {
+      let mut transport = Map::new();
+      for key in ["reconnect_max_delay", "buffer_max_bytes", "buffer_max_age"] {
+        if let Some(value) = map.remove(key) {
+          transport.insert(key.to_string(), value);
+        }
+      }
+      if !transport.is_empty() {
+        map.insert("transport".to_string(), Value::Object(transport));
+      }
+    }
+
+    // 2 -> 3: the old default disk exclusions also hid mounts like `/boot/efi`,
+    // files that still have them written out get the new defaults instead
+    if version < 3 {
+      if let Some(Value::Object(disks)) = map
+        .get_mut("filters")
+        .and_then(|filters| filters.get_mut("disks"))
+      {
+        if disks.get("exclude") == Some(&serde_json::json!(["*docker*", "*boot*"])) {
+          disks.remove("exclude");
+        }
+      }
+    }
+
+    map.insert("version".to_string(), Value::from(CONFIG_VERSION));
+  }
+
+  Ok(true)
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+  if prefix.is_empty() {
+    key.to_string()
+  } else {
+    format!("{}.{}", prefix, key)
+  }
+}
+
+/// Records the source of every leaf under `value`, arrays count as leaves
+fn mark_sources(
+  value: &Value,
+  prefix: &str,
+  source: &ConfigSource,
+  sources: &mut BTreeMap<String, ConfigSource>,
+) {
+  match value {
+    Value::Object(map) => {
+      for (key, value) in map {
+        mark_sources(value, &join_key(prefix, key), source, sources);
+      }
+    }
+    _ => {
+      sources.insert(prefix.to_string(), source.clone());
+    }
+  }
+}
+
+/// Deep merges the objects of `layer` into `base`, anything else gets replaced
+fn merge(
+  base: &mut Value,
+  layer: &Value,
+  prefix: &str,
+  source: &ConfigSource,
+  sources: &mut BTreeMap<String, ConfigSource>,
+) {
+  match (base, layer) {
+    (Value::Object(base), Value::Object(layer)) => {
+      for (key, value) in layer {
+        let key_path = join_key(prefix, key);
+        match base.get_mut(key) {
+          Some(existing) if existing.is_object() && value.is_object() => {
+            merge(existing, value, &key_path, source, sources);
+          }
+          _ => {
+            sources.retain(|existing, _| !existing.starts_with(&format!("{}.", key_path)));
+            mark_sources(value, &key_path, source, sources);
+            base.insert(key.to_string(), value.clone());
+          }
+        }
+      }
+    }
+    (base, layer) => *base = layer.clone(),
+  }
+}
+
+/// Overrides every leaf with its `OPAL_*` environment variable if it's set.
+/// Strings are taken as they are and anything else is parsed as JSON
+fn apply_env_overrides(
+  value: &mut Value,
+  prefix: &str,
+  sources: &mut BTreeMap<String, ConfigSource>,
+) -> Result<()> {
+  if let Value::Object(map) = value {
+    for (key, value) in map.iter_mut() {
+      apply_env_overrides(value, &join_key(prefix, key), sources)?;
+    }
+    return Ok(());
+  }
+
+  let name = format!("{}{}", ENV_PREFIX, prefix.replace('.', "__").to_uppercase());
+  if let Ok(env_value) = std::env::var(&name) {
+    *value = match value {
+      Value::String(_) => Value::String(env_value),
+      // Unset optional values can't tell us their type so anything that isn't JSON is a string
+      Value::Null => serde_json::from_str(&env_value).unwrap_or(Value::String(env_value)),
+      _ => serde_json::from_str(&env_value)
+        .map_err(|e| anyhow!("{} is not a valid value for {}: {}", env_value, name, e))?,
+    };
+    sources.insert(prefix.to_string(), ConfigSource::Environment(name));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use tempfile::TempDir;
+
+  fn write(dir: &TempDir, name: &str, layer: Value) -> PathBuf {
+    let path = dir.path().join(name);
+    fs::write(&path, layer.to_string()).unwrap();
+    path
+  }
+
+  const UUID: &str = "6c5d4f0e-8d4b-4a51-9a52-3d0e4a1f8b27";
+
+  /// Loads a single config file that changes are saved to
+  fn load(path: &Path) -> Result<ConfigManager> {
+    ConfigManager::load(&[path.to_path_buf()], path.to_path_buf(), false)
+  }
+
+  #[test]
+  fn later_layers_override_earlier_ones() {
+    let dir = TempDir::new().unwrap();
+    let system = write(
+      &dir,
+      "system.json",
+      json!({
+        "version": CONFIG_VERSION,
+        "backend_hostname": "backend.example.com",
+        "collection": { "interval": 5.0, "refresh_iterations": 10 },
+      }),
+    );
+    let user = write(
+      &dir,
+      "user.json",
+      json!({
+        "version": CONFIG_VERSION,
+        "access_token": "token",
+        "uuid": UUID,
+        "collection": { "interval": 2.0 },
+      }),
+    );
+
+    let manager =
+      ConfigManager::load(&[system.clone(), user.clone()], user.clone(), false).unwrap();
+    let config = &manager.config;
+    assert_eq!(config.backend_hostname, "backend.example.com");
+    assert_eq!(config.access_token, "token");
+    assert_eq!(config.collection.interval, 2.0);
+    assert_eq!(config.collection.refresh_iterations, 10);
+    assert_eq!(config.transport.reconnect_max_delay, 60);
+
+    assert_eq!(
+      manager.source_of("backend_hostname"),
+      ConfigSource::File(system.clone())
+    );
+    assert_eq!(
+      manager.source_of("collection.interval"),
+      ConfigSource::File(user.clone())
+    );
+    assert_eq!(
+      manager.source_of("collection.refresh_iterations"),
+      ConfigSource::File(system)
+    );
+    assert_eq!(
+      manager.source_of("transport.reconnect_max_delay"),
+      ConfigSource::Default
+    );
+  }
+
+  #[test]
+  fn source_of_falls_back_to_the_closest_parent() {
+    let dir = TempDir::new().unwrap();
+    let user = write(
+      &dir,
+      "user.json",
+      json!({
+        "version": CONFIG_VERSION,
+        "uuid": UUID,
+        "collection": { "systemd_units": ["nginx"] },
+      }),
+    );
+    let manager = load(&user).unwrap();
+
+    // Arrays are leaves so their items belong to them
+    assert_eq!(
+      manager.source_of("collection.systemd_units[0]"),
+      ConfigSource::File(user.clone())
+    );
+    // Keys inside array items are attributed to the array
+    assert_eq!(
+      manager.source_of("collection.systemd_units[0].name"),
+      ConfigSource::File(user.clone())
+    );
+    assert_eq!(
+      manager.source_of("collection.interval"),
+      ConfigSource::Default
+    );
+    assert_eq!(manager.source_of("nope"), ConfigSource::Default);
+  }
+
+  #[test]
+  fn invalid_values_name_their_layer() {
+    let dir = TempDir::new().unwrap();
+    let user = write(
+      &dir,
+      "user.json",
+      json!({ "version": CONFIG_VERSION, "collection": { "interval": 0 } }),
+    );
+    let error = load(&user).unwrap_err();
+    assert_eq!(
+      error.downcast::<ConfigError>().unwrap().to_string(),
+      ConfigError::InvalidValue {
+        key: "collection.interval".to_string(),
+        origin: ConfigSource::File(user),
+        message: "must be a number of seconds greater than 0".to_string(),
+      }
+      .to_string()
+    );
+  }
+
+  #[test]
+  fn the_user_config_overrides_the_working_directory() {
+    let user_path = PathBuf::from("/home/user/.config/opal-connector/config.json");
+    assert_eq!(
+      ConfigManager::layer_paths_with(None, Some(user_path.clone())),
+      vec![
+        Path::new(SYSTEM_CONFIG_DIR).join(CONFIG_FILE_NAME),
+        PathBuf::from(CONFIG_FILE_NAME),
+        user_path.clone(),
+      ]
+    );
+
+    assert_eq!(
+      ConfigManager::layer_paths_with(Some("custom.json"), Some(user_path)),
+      vec![
+        Path::new(SYSTEM_CONFIG_DIR).join(CONFIG_FILE_NAME),
+        PathBuf::from("custom.json"),
+      ]
+    );
+  }
+
+  #[test]
+  fn creates_a_missing_config() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("nested/config.json");
+    let manager = load(&path).unwrap();
+
+    assert!(Uuid::parse_str(&manager.config.uuid).is_ok());
+    let saved: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["uuid"], json!(manager.config.uuid));
+    assert_eq!(saved["version"], json!(CONFIG_VERSION));
+  }
+
+  #[test]
+  fn saving_keeps_the_other_layers_out() {
+    let dir = TempDir::new().unwrap();
+    let system = write(
+      &dir,
+      "system.json",
+      json!({ "version": CONFIG_VERSION, "backend_hostname": "backend.example.com" }),
+    );
+    let user = write(
+      &dir,
+      "user.json",
+      json!({ "version": CONFIG_VERSION, "uuid": UUID }),
+    );
+    let mut manager = ConfigManager::load(&[system, user.clone()], user.clone(), false).unwrap();
+    manager.save_access_token("token").unwrap();
+
+    let saved: Value = serde_json::from_str(&fs::read_to_string(&user).unwrap()).unwrap();
+    assert_eq!(
+      saved,
+      json!({ "version": CONFIG_VERSION, "uuid": UUID, "access_token": "token" })
+    );
+    assert_eq!(manager.source_of("access_token"), ConfigSource::File(user));
+  }
+
+  /// The variables are named after `prefix` so tests running in parallel don't see each other's
+  fn overrides(
+    prefix: &str,
+    vars: &[(&str, &str)],
+  ) -> Result<(Value, BTreeMap<String, ConfigSource>)> {
+    let mut value = json!({
+      "name": "default",
+      "nested": { "count": 1, "ratio": 0.5, "enabled": false, "list": ["a"], "optional": null },
+    });
+    for (name, env_value) in vars {
+      std::env::set_var(name, env_value);
+    }
+    let mut sources = BTreeMap::new();
+    let result = apply_env_overrides(&mut value, prefix, &mut sources);
+    for (name, _) in vars {
+      std::env::remove_var(name);
+    }
+    result.map(|_| (value, sources))
+  }
+
+  #[test]
+  fn env_overrides_coerce_to_the_type_of_the_default() {
+    let (value, sources) = overrides(
+      "coerce",
+      &[
+        ("OPAL_COERCE__NAME", "42"),
+        ("OPAL_COERCE__NESTED__COUNT", "7"),
+        ("OPAL_COERCE__NESTED__RATIO", "2.5"),
+        ("OPAL_COERCE__NESTED__ENABLED", "true"),
+        ("OPAL_COERCE__NESTED__LIST", r#"["b", "c"]"#),
+      ],
+    )
+    .unwrap();
+    assert_eq!(
+      value,
+      json!({
+        "name": "42",
+        "nested": { "count": 7, "ratio": 2.5, "enabled": true, "list": ["b", "c"], "optional": null },
+      })
+    );
+    assert_eq!(
+      sources.get("coerce.nested.count"),
+      Some(&ConfigSource::Environment(
+        "OPAL_COERCE__NESTED__COUNT".to_string()
+      ))
+    );
+    assert_eq!(sources.len(), 5);
+  }
+
+  #[test]
+  fn unset_optional_values_take_json_or_a_string() {
+    let (value, _) = overrides(
+      "optional_json",
+      &[("OPAL_OPTIONAL_JSON__NESTED__OPTIONAL", "3")],
+    )
+    .unwrap();
+    assert_eq!(value["nested"]["optional"], json!(3));
+    let (value, _) = overrides(
+      "optional_string",
+      &[("OPAL_OPTIONAL_STRING__NESTED__OPTIONAL", "/etc/ca.pem")],
+    )
+    .unwrap();
+    assert_eq!(value["nested"]["optional"], json!("/etc/ca.pem"));
+  }
+
+  #[test]
+  fn env_overrides_that_dont_parse_are_errors() {
+    let error = overrides("invalid", &[("OPAL_INVALID__NESTED__ENABLED", "yes")]).unwrap_err();
+    assert!(error
+      .to_string()
+      .starts_with("yes is not a valid value for OPAL_INVALID__NESTED__ENABLED"));
+  }
+
+  #[test]
+  fn without_env_overrides_nothing_changes() {
+    let (value, sources) = overrides("untouched", &[]).unwrap();
+    assert_eq!(value["nested"]["count"], json!(1));
+    assert!(sources.is_empty());
+  }
+
+  #[test]
+  fn migrates_version_1() {
+    let mut layer = json!({
+      "access_token": "token",
+      "backend_hostname": "backend.example.com",
+      "uuid": UUID,
+      "reconnect_max_delay": 30,
+      "buffer_max_bytes": 1000,
+    });
+    assert!(migrate(&mut layer, Path::new("config.json")).unwrap());
+    assert_eq!(
+      layer,
+      json!({
+        "version": CONFIG_VERSION,
+        "access_token": "token",
+        "backend_hostname": "backend.example.com",
+        "uuid": UUID,
+        "transport": { "reconnect_max_delay": 30, "buffer_max_bytes": 1000 },
+      })
+    );
+  }
+
+  #[test]
+  fn migrates_version_2() {
+    let mut layer = json!({
+      "version": 2,
+      "filters": { "disks": { "include": ["/data*"], "exclude": ["*docker*", "*boot*"] } },
+    });
+    assert!(migrate(&mut layer, Path::new("config.json")).unwrap());
+    assert_eq!(
+      layer,
+      json!({
+        "version": CONFIG_VERSION,
+        "filters": { "disks": { "include": ["/data*"] } },
+      })
+    );
+
+    // Exclusions that were changed from the old defaults are kept
+    let mut layer = json!({
+      "version": 2,
+      "filters": { "disks": { "exclude": ["*docker*"] } },
+    });
+    assert!(migrate(&mut layer, Path::new("config.json")).unwrap());
+    assert_eq!(layer["filters"]["disks"]["exclude"], json!(["*docker*"]));
+  }
+
+  #[test]
+  fn current_configs_are_left_alone() {
+    let mut layer = json!({ "version": CONFIG_VERSION, "reconnect_max_delay": 30 });
+    assert!(!migrate(&mut layer, Path::new("config.json")).unwrap());
+    assert_eq!(
+      layer,
+      json!({ "version": CONFIG_VERSION, "reconnect_max_delay": 30 })
+    );
+  }
+
+  #[test]
+  fn configs_from_newer_versions_are_refused() {
+    let mut layer = json!({ "version": CONFIG_VERSION + 1 });
+    let error = migrate(&mut layer, Path::new("config.json")).unwrap_err();
+    assert!(matches!(
+      error.downcast::<ConfigError>().unwrap(),
+      ConfigError::UnsupportedVersion(_, version) if version == CONFIG_VERSION + 1
+    ));
+  }
+
+  #[test]
+  fn migrated_files_are_saved() {
+    let dir = TempDir::new().unwrap();
+    let user = write(
+      &dir,
+      "user.json",
+      json!({ "uuid": UUID, "reconnect_max_delay": 30 }),
+    );
+    let manager = load(&user).unwrap();
+    assert_eq!(manager.config.transport.reconnect_max_delay, 30);
+
+    let saved: Value = serde_json::from_str(&fs::read_to_string(&user).unwrap()).unwrap();
+    assert_eq!(
+      saved,
+      json!({
+        "version": CONFIG_VERSION,
+        "uuid": UUID,
+        "transport": { "reconnect_max_delay": 30 },
+      })
+    );
+  }
+
+  #[test]
+  fn validate_names_the_offending_key() {
+    let invalid_key = |change: fn(&mut Config)| {
+      let mut config = Config::default();
+      change(&mut config);
+      config.validate().unwrap_err().0
+    };
+    assert!(Config::default().validate().is_ok());
+    assert_eq!(
+      invalid_key(|config| config.collection.interval = f64::NAN),
+      "collection.interval"
+    );
+    assert_eq!(
+      invalid_key(|config| config.collection.refresh_iterations = 0),
+      "collection.refresh_iterations"
+    );
+    assert_eq!(
+      invalid_key(|config| config.collection.top_processes.redact = vec!["(".to_string()]),
+      "collection.top_processes.redact[0]"
+    );
+    assert_eq!(
+      invalid_key(|config| config.filters.network.patterns.exclude = vec!["re:[".to_string()]),
+      "filters.network"
+    );
+    assert_eq!(
+      invalid_key(|config| config.filters.disks.fs_types.include = vec!["re:(".to_string()]),
+      "filters.disks.fs_types"
+    );
+    assert_eq!(
+      invalid_key(|config| {
+        config.transport.tls.ca_certificate = Some("/nonexistent/ca.pem".to_string())
+      }),
+      "transport.tls.ca_certificate"
+    );
+  }
+
+  #[test]
+  fn invalid_hostnames_and_uuids_are_refused() {
+    let dir = TempDir::new().unwrap();
+    let user = write(
+      &dir,
+      "user.json",
+      json!({ "version": CONFIG_VERSION, "uuid": UUID, "backend_hostname": "https://backend.example.com" }),
+    );
+    let error = load(&user).unwrap_err().downcast::<ConfigError>().unwrap();
+    assert!(matches!(error, ConfigError::InvalidHostname { .. }));
+
+    let user = write(
+      &dir,
+      "user.json",
+      json!({ "version": CONFIG_VERSION, "uuid": "not-a-uuid" }),
+    );
+    let error = load(&user).unwrap_err().downcast::<ConfigError>().unwrap();
+    assert!(matches!(error, ConfigError::InvalidUuid { .. }));
+  }
+
+  #[test]
+  fn broken_files_are_left_untouched() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("config.json");
+    fs::write(&path, "{ \"access_token\": ").unwrap();
+    let error = load(&path).unwrap_err().downcast::<ConfigError>().unwrap();
+    assert!(matches!(error, ConfigError::Unparsable { .. }));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "{ \"access_token\": ");
+
+    fs::write(&path, "[]").unwrap();
+    let error = load(&path).unwrap_err().downcast::<ConfigError>().unwrap();
+    assert!(matches!(error, ConfigError::NotAnObject(_)));
+  }
+
+  #[test]
+  fn redacted_configs_hide_the_token_and_the_http_headers() {
+    let config = Config {
+      access_token: "hunter2-token".to_string(),
+      sinks: vec![
+        SinkConfig::Http {
+          url: "https://metrics.example.com/ingest".to_string(),
+          headers: [(
+            "Authorization".to_string(),
+            "Bearer hunter2-key".to_string(),
+          )]
+          .into(),
+        },
+        SinkConfig::Stdout,
+      ],
+      ..Config::default()
+    };
+    let redacted = config.redacted();
+    let printed = serde_json::to_string(&redacted).unwrap();
+    assert!(!printed.contains("hunter2"), "{}", printed);
+    assert!(printed.contains("https://metrics.example.com/ingest"));
+    assert!(printed.contains(r#""Authorization":"<redacted>""#));
+    assert_eq!(redacted.sinks[1], SinkConfig::Stdout);
+
+    // There's nothing to hide when no token is set
+    assert_eq!(Config::default().redacted().access_token, "");
+  }
+
+  #[test]
+  fn reading_leaves_the_files_alone() {
+    let dir = TempDir::new().unwrap();
+    let missing = dir.path().join("missing.json");
+    let manager =
+      ConfigManager::load(std::slice::from_ref(&missing), missing.clone(), true).unwrap();
+    assert!(!missing.exists());
+    assert_eq!(manager.config.uuid, "");
+
+    // An old config without a UUID is migrated in memory only
+    let old = json!({
+      "access_token": "token",
+      "backend_hostname": "backend.example.com",
+      "reconnect_max_delay": 30,
+    });
+    let path = write(&dir, "config.json", old.clone());
+    let manager = ConfigManager::load(std::slice::from_ref(&path), path.clone(), true).unwrap();
+    assert_eq!(manager.config.transport.reconnect_max_delay, 30);
+    assert_eq!(manager.config.uuid, "");
+    let on_disk: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(on_disk, old);
+  }
+
+  #[test]
+  fn the_missing_token_advice_is_a_valid_command() {
+    use crate::arg_parser::{ArgParser, Command};
+    use clap::Parser;
+
+    let message = ConfigError::MissingAccessToken.to_string();
+    let command = message.split('`').nth(1).unwrap();
+    let args = command
+      .split_whitespace()
+      .map(|arg| if arg == "<key>" { "KEY" } else { arg });
+    let args = ArgParser::try_parse_from(args).unwrap();
+    assert!(matches!(args.subcommand(), Command::Signup { key } if key == "KEY"));
+  }
+
+  #[test]
+  fn validates_hostnames() {
+    for valid in [
+      "backend.example.com",
+      "localhost",
+      "localhost:8080",
+      "10.0.0.2:8080",
+      "my_host-1.lan",
+      "[::1]",
+      "[::1]:8080",
+    ] {
+      assert!(is_valid_hostname(valid), "{}", valid);
+    }
+    for invalid in [
+      "",
+      "https://backend.example.com",
+      "backend.example.com/path",
+      "backend..example.com",
+      "-backend.example.com",
+      "backend.example.com:0",
+      "backend.example.com:65536",
+      "backend.example.com:",
+      "[::1",
+      "[not-ipv6]:8080",
+      "[::1]8080",
+      &"a".repeat(64),
+    ] {
+      assert!(!is_valid_hostname(invalid), "{}", invalid);
+    }
+  }
+
+  #[cfg(target_family = "unix")]
+  #[test]
+  fn only_the_owner_can_read_the_saved_config() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("config.json");
+    load(&path).unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // A config that was readable by everyone is locked down once it's saved
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    let mut manager = load(&path).unwrap();
+    manager.save_access_token("token").unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert!(!dir.path().join("config.tmp").exists());
+    assert_eq!(load(&path).unwrap().config.access_token, "token");
+  }
 }
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::types::{CgroupSliceStats, CgroupStats, PressureAverages, PressureStats};
+
+use super::mounts::Mount;
+use super::sysfs::Sysfs;
+use super::DataCollector;
+
+/// The CPU time a cgroup used up to a collection, for its usage since the previous one
+#[derive(Debug, Clone, Copy)]
+pub struct CgroupSample {
+  at: Instant,
+  usage_usec: u64,
+}
+
+impl DataCollector {
+  /// Gets the limits and usage of the cgroup the reporter runs in and of the top level slices
+  pub fn get_cgroup(&mut self) -> Option<CgroupStats> {
+    read_cgroup(&self.procfs, &mut self.cgroup_samples, Instant::now())
+  }
+}
+
+/// Reads the process' cgroup under the cgroup2 mount of the mount table, `None` without one.
+/// `samples` holds the CPU time of every group at the previous collection by directory
+fn read_cgroup(
+  procfs: &Sysfs,
+  samples: &mut HashMap<PathBuf, CgroupSample>,
+  now: Instant,
+) -> Option<CgroupStats> {
+  let root = Mount::from_procfs(procfs)
+    .into_iter()
+    .find(|mount| mount.fs_type == "cgroup2")
+    .map(|mount| PathBuf::from(mount.mount_point))?;
+  let path = procfs
+    .read_string("self/cgroup")?
+    .lines()
+    .find_map(|line| line.strip_prefix("0::"))?
+    .to_string();
+  // Without a cgroup namespace a container sees the host's path but only has its own group mounted
+  let dir = Some(root.join(path.trim_start_matches('/')))
+    .filter(|dir| dir.is_dir())
+    .unwrap_or_else(|| root.clone());
+
+  let mut slice_dirs: Vec<PathBuf> = fs::read_dir(&root)
+    .into_iter()
+    .flatten()
+    .flatten()
+    .map(|entry| entry.path())
+    .filter(|path| path.is_dir() && path.to_string_lossy().ends_with(".slice"))
+    .collect();
+  slice_dirs.sort();
+  let slices = slice_dirs
+    .iter()
+    .map(|slice_dir| {
+      let slice = Sysfs::new(slice_dir);
+      CgroupSliceStats {
+        name: slice_dir
+          .file_name()
+          .map(|name| name.to_string_lossy().to_string())
+          .unwrap_or_default(),
+        memory_current: slice.read_u64("memory.current"),
+        cpu_usage: cpu_usage(&slice, samples, now),
+      }
+    })
+    .collect();
+
+  let group = Sysfs::new(&dir);
+  let cpu_stat = read_keyed(&group, "cpu.stat");
+  let stats = CgroupStats {
+    path,
+    memory_current: group.read_u64("memory.current"),
+    memory_max: group.read_string("memory.max").and_then(parse_max),
+    swap_current: group.read_u64("memory.swap.current"),
+    swap_max: group.read_string("memory.swap.max").and_then(parse_max),
+    oom_kills: read_keyed(&group, "memory.events").get("oom_kill").copied(),
+    cpu_usage: cpu_usage(&group, samples, now),
+    cpu_limit: group
+      .read_string("cpu.max")
+      .and_then(|max| parse_cpu_max(&max)),
+    cpu_throttled_usec: cpu_stat.get("throttled_usec").copied(),
+    cpu_pressure: group
+      .read_string("cpu.pressure")
+      .and_then(|p| parse_pressure(&p)),
+    memory_pressure: group
+      .read_string("memory.pressure")
+      .and_then(|p| parse_pressure(&p)),
+    io_pressure: group
+      .read_string("io.pressure")
+      .and_then(|p| parse_pressure(&p)),
+    slices,
+  };
+
+  samples.retain(|sample_dir, _| *sample_dir == dir || slice_dirs.contains(sample_dir));
+  Some(stats)
+}
+
+/// The CPU usage of a group since its previous sample, which is replaced with the current one
+fn cpu_usage(
+  group: &Sysfs,
+  samples: &mut HashMap<PathBuf, CgroupSample>,
+  now: Instant,
+) -> Option<f64> {
+  let usage_usec = *read_keyed(group, "cpu.stat").get("usage_usec")?;
+  let previous = samples.insert(
+    group.root.clone(),
+    CgroupSample {
+      at: now,
+      usage_usec,
+    },
+  )?;
+  let elapsed = now.checked_duration_since(previous.at)?.as_micros();
+  let used = usage_usec.checked_sub(previous.usage_usec)?;
+  if elapsed == 0 {
+    return None;
+  }
+  Some(used as f64 / elapsed as f64 * 100.0)
+}
+
+/// The `key value` lines of files like `cpu.stat` and `memory.events`
+fn read_keyed(group: &Sysfs, file: impl AsRef<Path>) -> HashMap<String, u64> {
+  group
+    .read_string(file)
+    .unwrap_or_default()
+    .lines()
+    .filter_map(|line| {
+      let (key, value) = line.split_once(' ')?;
+      Some((key.to_string(), value.trim().parse().ok()?))
+    })
+    .collect()
+}
+
+/// A limit, `None` when it's `max`
+fn parse_max(value: String) -> Option<u64> {
+  value.parse().ok()
+}
+
+/// `cpu.max` holds the microseconds the group can run every period, `max 100000` without a quota
+fn parse_cpu_max(value: &str) -> Option<f64> {
+  let mut fields = value.split_whitespace();
+  let quota: u64 = fields.next()?.parse().ok()?;
+  let period: u64 = fields.next()?.parse().ok()?;
+  Some(quota as f64 / period.max(1) as f64)
+}
+
+/// `some avg10=0.12 avg60=0.05 avg300=0.01 total=123456` and a `full` line the same way
+fn parse_pressure(content: &str) -> Option<PressureStats> {
+  let mut some = None;
+  let mut full = None;
+  for line in content.lines() {
+    let mut fields = line.split_whitespace();
+    let kind = fields.next()?;
+    let values: HashMap<&str, &str> = fields.filter_map(|field| field.split_once('=')).collect();
+    let averages = PressureAverages {
+      avg10: values.get("avg10")?.parse().ok()?,
+      avg60: values.get("avg60")?.parse().ok()?,
+      avg300: values.get("avg300")?.parse().ok()?,
+      total: values.get("total")?.parse().ok()?,
+    };
+    match kind {
+      "some" => some = Some(averages),
+      "full" => full = Some(averages),
+      _ => {}
+    }
+  }
+  Some(PressureStats { some: some?, full })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use tempfile::TempDir;
+
+  /// A procfs and a cgroup2 tree like systemd sets up, with the reporter in its own service
+  fn fake_tree(usage_usec: u64) -> (TempDir, Sysfs) {
+    let root = TempDir::new().unwrap();
+    let cgroup = root.path().join("cgroup");
+    let service = cgroup.join("system.slice/xornet-reporter.service");
+    fs::create_dir_all(&service).unwrap();
+    fs::create_dir_all(cgroup.join("user.slice")).unwrap();
+    fs::create_dir_all(cgroup.join("init.scope")).unwrap();
+    fs::create_dir_all(root.path().join("proc/self")).unwrap();
+
+    fs::write(
+      root.path().join("proc/self/mounts"),
+      format!(
+        "/dev/sda2 / ext4 rw 0 0\ncgroup2 {} cgroup2 rw,nosuid 0 0\n",
+        cgroup.display()
+      ),
+    )
+    .unwrap();
+    fs::write(
+      root.path().join("proc/self/cgroup"),
+      "0::/system.slice/xornet-reporter.service\n",
+    )
+    .unwrap();
+
+    let files = [
+      ("memory.current", "52428800\n"),
+      ("memory.max", "268435456\n"),
+      ("memory.swap.current", "0\n"),
+      ("memory.swap.max", "max\n"),
+      (
+        "memory.events",
+        "low 0\nhigh 0\nmax 4\noom 1\noom_kill 1\n",
+      ),
+      ("cpu.max", "50000 100000\n"),
+      (
+        "cpu.pressure",
+        "some avg10=1.50 avg60=0.75 avg300=0.20 total=99000\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n"
+          ,
+      ),
+      (
+        "memory.pressure",
+        "some avg10=0.00 avg60=0.00 avg300=0.00 total=10\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=5\n"
+          ,
+      ),
+    ];
+    for (file, content) in &files {
+      fs::write(service.join(file), content).unwrap();
+    }
+    fs::write(cgroup.join("system.slice/memory.current"), "1073741824\n").unwrap();
+    fs::write(cgroup.join("user.slice/memory.current"), "2048\n").unwrap();
+    set_usage(&root, usage_usec);
+
+    let procfs = Sysfs::new(root.path().join("proc"));
+    (root, procfs)
+  }
+
+  /// Sets the CPU time of the service, its slice used 10 times as much
+  fn set_usage(root: &TempDir, usage_usec: u64) {
+    let slice = root.path().join("cgroup/system.slice");
+    fs::write(
+      slice.join("xornet-reporter.service/cpu.stat"),
+      format!(
+        "usage_usec {}\nuser_usec 1\nsystem_usec 1\nnr_periods 10\nnr_throttled 2\nthrottled_usec 3000\n",
+        usage_usec
+      ),
+    )
+    .unwrap();
+    fs::write(
+      slice.join("cpu.stat"),
+      format!("usage_usec {}\n", usage_usec * 10),
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn reads_the_limits_and_pressure() {
+    let (_root, procfs) = fake_tree(1_000_000);
+    let stats = read_cgroup(&procfs, &mut HashMap::new(), Instant::now()).unwrap();
+
+    assert_eq!(stats.path, "/system.slice/xornet-reporter.service");
+    assert_eq!(stats.memory_current, Some(52428800));
+    assert_eq!(stats.memory_max, Some(268435456));
+    assert_eq!(stats.swap_current, Some(0));
+    assert_eq!(stats.swap_max, None);
+    assert_eq!(stats.oom_kills, Some(1));
+    assert_eq!(stats.cpu_limit, Some(0.5));
+    assert_eq!(stats.cpu_throttled_usec, Some(3000));
+    assert_eq!(
+      stats.cpu_pressure,
+      Some(PressureStats {
+        some: PressureAverages {
+          avg10: 1.5,
+          avg60: 0.75,
+          avg300: 0.2,
+          total: 99000,
+        },
+        full: Some(PressureAverages::default()),
+      })
+    );
+    assert_eq!(stats.memory_pressure.unwrap().full.unwrap().total, 5);
+    assert_eq!(stats.io_pressure, None);
+    assert_eq!(
+      stats.slices,
+      vec![
+        CgroupSliceStats {
+          name: "system.slice".to_string(),
+          memory_current: Some(1073741824),
+          cpu_usage: None,
+        },
+        CgroupSliceStats {
+          name: "user.slice".to_string(),
+          memory_current: Some(2048),
+          cpu_usage: None,
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn measures_the_cpu_usage_between_collections() {
+    let (root, procfs) = fake_tree(1_000_000);
+    let mut samples = HashMap::new();
+    let then = Instant::now();
+    assert_eq!(
+      read_cgroup(&procfs, &mut samples, then).unwrap().cpu_usage,
+      None
+    );
+
+    // 3 seconds of CPU time over 2 seconds, the slice used 10 times as much
+    set_usage(&root, 4_000_000);
+    let stats = read_cgroup(&procfs, &mut samples, then + Duration::from_secs(2)).unwrap();
+    assert_eq!(stats.cpu_usage, Some(150.0));
+    assert_eq!(stats.slices[0].cpu_usage, Some(1500.0));
+    assert_eq!(stats.slices[1].cpu_usage, None);
+    assert_eq!(samples.len(), 2);
+  }
+
+  #[test]
+  fn no_cgroup2_mount_reports_nothing() {
+    let root = TempDir::new().unwrap();
+    fs::create_dir_all(root.path().join("self")).unwrap();
+    fs::write(
+      root.path().join("self/mounts"),
+      "cgroup /sys/fs/cgroup/memory cgroup rw,memory 0 0\n",
+    )
+    .unwrap();
+    fs::write(root.path().join("self/cgroup"), "4:memory:/\n0::/\n").unwrap();
+    let procfs = Sysfs::new(root.path());
+    assert_eq!(
+      read_cgroup(&procfs, &mut HashMap::new(), Instant::now()),
+      None
+    );
+  }
+
+  #[test]
+  fn parses_the_cpu_quota() {
+    assert_eq!(parse_cpu_max("max 100000"), None);
+    assert_eq!(parse_cpu_max("200000 100000"), Some(2.0));
+    assert_eq!(parse_max("max".to_string()), None);
+  }
+}
@@ -0,0 +1,843 @@
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+use crate::types::ContainerStats;
+
+use super::DataCollector;
+
+/// How long a request to a daemon can take, the stats of daemons that ignore `one-shot`
+/// take a second or two while they wait for a second sample
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a collection waits for the containers before reporting the previous ones
+const COLLECTION_DEADLINE: Duration = Duration::from_millis(500);
+
+/// How many containers are inspected at the same time, each one takes a connection
+const CONCURRENT_CONTAINERS: usize = 16;
+
+/// The counters of a container at a collection, for the usage since the previous one
+#[derive(Debug, Clone, Copy)]
+pub struct ContainerSample {
+  at: Instant,
+  cpu_total: u64,
+  system_total: u64,
+  rx_bytes: u64,
+  tx_bytes: u64,
+}
+
+/// A daemon serving the Docker Engine API on a Unix socket, Podman serves a compatible one
+#[derive(Debug, Clone)]
+pub struct ContainerRuntime {
+  pub socket: PathBuf,
+  /// `docker` or `podman`
+  pub name: String,
+  /// How long a single request can take
+  pub timeout: Duration,
+}
+
+impl DataCollector {
+  /// Gets the containers of every configured runtime whose socket exists. The daemons are read
+  /// on a blocking thread so a slow or hung one doesn't hold up the other collectors
+  pub async fn get_containers(&mut self) -> Vec<ContainerStats> {
+    let sockets = self.collection.container_sockets.clone();
+    self.containers.collect(sockets, COLLECTION_DEADLINE).await
+  }
+}
+
+/// Reads the containers off the async loop and keeps their counters between collections
+#[derive(Debug, Default)]
+pub struct ContainerCollector {
+  runtimes: Runtimes,
+  /// A collection that missed its deadline, picked up by the next one
+  running: Option<JoinHandle<(Runtimes, Vec<ContainerStats>)>>,
+  /// The containers of the last finished collection, reported while the next one is running
+  latest: Vec<ContainerStats>,
+  /// Whether the last collection missed its deadline, so it's logged once
+  slow: bool,
+}
+
+impl ContainerCollector {
+  /// Waits up to `deadline` for the containers. A collection that takes longer keeps running and
+  /// the previous containers are reported until it's done
+  pub async fn collect(&mut self, sockets: Vec<String>, deadline: Duration) -> Vec<ContainerStats> {
+    let mut running = match self.running.take() {
+      Some(running) => running,
+      None => {
+        // The counters are moved to the blocking thread and come back with the containers
+        let mut runtimes = std::mem::take(&mut self.runtimes);
+        tokio::task::spawn_blocking(move || {
+          let containers = runtimes.collect(&sockets);
+          (runtimes, containers)
+        })
+      }
+    };
+
+    match tokio::time::timeout(deadline, &mut running).await {
+      Ok(finished) => {
+        self.slow = false;
+        // A collection that panicked starts over without the previous counters
+        let (runtimes, containers) = finished.unwrap_or_default();
+        self.runtimes = runtimes;
+        self.latest = containers;
+      }
+      Err(_) => {
+        if !self.slow {
+          eprintln!(
+            "The containers took longer than {:?} to read, the previous ones are reported until they're read",
+            deadline
+          );
+        }
+        self.slow = true;
+        self.running = Some(running);
+      }
+    }
+    self.latest.clone()
+  }
+}
+
+/// The state of the container runtimes between two collections
+#[derive(Debug, Default)]
+struct Runtimes {
+  /// The counters of every running container at the previous collection by ID
+  samples: HashMap<String, ContainerSample>,
+  /// The sockets whose runtime failed to answer the last time, so the error is logged once
+  failing: Vec<PathBuf>,
+}
+
+impl Runtimes {
+  /// Reads the containers of every runtime whose socket exists, a runtime that fails to
+  /// answer is left out instead of hiding the others
+  fn collect(&mut self, sockets: &[String]) -> Vec<ContainerStats> {
+    let mut read: Vec<PathBuf> = Vec::new();
+    let mut containers = Vec::new();
+    for socket in sockets {
+      // podman-docker links the Docker socket to Podman's, those containers are only read once
+      let socket = match std::fs::canonicalize(socket) {
+        Ok(socket) if !read.contains(&socket) => socket,
+        _ => continue,
+      };
+      read.push(socket.clone());
+      let runtime = ContainerRuntime::new(socket);
+      match runtime.containers(&mut self.samples) {
+        Ok(mut stats) => {
+          self.failing.retain(|failing| *failing != runtime.socket);
+          containers.append(&mut stats);
+        }
+        Err(e) => {
+          if !self.failing.contains(&runtime.socket) {
+            eprintln!(
+              "Could not read the {} containers from {}: {}",
+              runtime.name,
+              runtime.socket.display(),
+              e
+            );
+            self.failing.push(runtime.socket);
+          }
+        }
+      }
+    }
+    let ids: Vec<String> = containers.iter().map(|c| c.id.clone()).collect();
+    self
+      .samples
+      .retain(|id, _| ids.iter().any(|known| id.starts_with(known.as_str())));
+    containers
+  }
+}
+
+impl ContainerRuntime {
+  pub fn new(socket: PathBuf) -> Self {
+    let name = if socket.to_string_lossy().contains("podman") {
+      "podman"
+    } else {
+      "docker"
+    };
+    Self {
+      socket,
+      name: name.to_string(),
+      timeout: REQUEST_TIMEOUT,
+    }
+  }
+
+  /// Lists every container, the stopped ones included, and reads the usage of the running ones.
+  /// The containers are read concurrently so a slow daemon takes about as long as its slowest one.
+  /// `samples` holds the counters of the previous collection by container ID
+  pub fn containers(
+    &self,
+    samples: &mut HashMap<String, ContainerSample>,
+  ) -> Result<Vec<ContainerStats>> {
+    let summaries: Vec<ContainerSummary> = self.get("/containers/json?all=true")?;
+    let mut details = Vec::new();
+    for batch in summaries.chunks(CONCURRENT_CONTAINERS) {
+      std::thread::scope(|scope| {
+        let handles: Vec<_> = batch
+          .iter()
+          .map(|summary| scope.spawn(move || self.details(summary)))
+          .collect();
+        details.extend(
+          handles
+            .into_iter()
+            .map(|handle| handle.join().ok().flatten()),
+        );
+      });
+    }
+
+    let mut containers = Vec::new();
+    // A container removed since it was listed is left out
+    for (summary, (inspect, stats)) in summaries
+      .into_iter()
+      .zip(details)
+      .filter_map(|(summary, details)| Some((summary, details?)))
+    {
+      let mut container = ContainerStats {
+        id: summary.id.chars().take(12).collect(),
+        name: summary
+          .names
+          .first()
+          .map(|name| name.trim_start_matches('/').to_string())
+          .unwrap_or_default(),
+        image: summary.image,
+        runtime: self.name.clone(),
+        state: summary.state,
+        health: inspect
+          .state
+          .health
+          .map(|health| health.status)
+          .filter(|status| !status.is_empty() && status != "none"),
+        restart_count: inspect.restart_count,
+        ..Default::default()
+      };
+
+      if let Some((stats, at)) = stats {
+        let sample = stats.sample(at);
+        let previous = samples.insert(summary.id, sample);
+        stats.apply(&mut container, sample, previous);
+      }
+      containers.push(container);
+    }
+    Ok(containers)
+  }
+
+  /// Inspects a container and reads the usage of a running one along with when it was read,
+  /// `None` when it's gone. Stats that time out are left out so the rest is still reported
+  fn details(
+    &self,
+    summary: &ContainerSummary,
+  ) -> Option<(ContainerInspect, Option<(StatsResponse, Instant)>)> {
+    let inspect = self
+      .get::<ContainerInspect>(&format!("/containers/{}/json", summary.id))
+      .ok()?;
+    let stats = if summary.state == "running" {
+      let path = format!(
+        "/containers/{}/stats?stream=false&one-shot=true",
+        summary.id
+      );
+      self
+        .get::<StatsResponse>(&path)
+        .ok()
+        .map(|stats| (stats, Instant::now()))
+    } else {
+      None
+    };
+    Some((inspect, stats))
+  }
+
+  fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+    let body = self.request(path)?;
+    serde_json::from_slice(&body).map_err(|e| anyhow!("{} returned invalid JSON: {}", path, e))
+  }
+
+  #[cfg(target_family = "unix")]
+  fn request(&self, path: &str) -> Result<Vec<u8>> {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    // The timeout applies to the whole request, not to every read of a daemon trickling the response
+    let deadline = Instant::now() + self.timeout;
+    let mut stream = UnixStream::connect(&self.socket)?;
+    stream.set_write_timeout(Some(self.timeout))?;
+    write!(
+      stream,
+      "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+      path
+    )?;
+    let mut response = Vec::new();
+    let mut buffer = [0; 8192];
+    loop {
+      let remaining = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|remaining| !remaining.is_zero())
+        .ok_or_else(|| anyhow!("{}: timed out after {:?}", path, self.timeout))?;
+      stream.set_read_timeout(Some(remaining))?;
+      match stream.read(&mut buffer) {
+        Ok(0) => break,
+        Ok(read) => response.extend_from_slice(&buffer[..read]),
+        Err(e)
+          if matches!(
+            e.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+          ) =>
+        {
+          return Err(anyhow!("{}: timed out after {:?}", path, self.timeout));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+        Err(e) => return Err(e.into()),
+      }
+    }
+    parse_response(&response).map_err(|e| anyhow!("{}: {}", path, e))
+  }
+
+  #[cfg(not(target_family = "unix"))]
+  fn request(&self, _path: &str) -> Result<Vec<u8>> {
+    Err(anyhow!(
+      "container runtimes are only read from Unix sockets"
+    ))
+  }
+}
+
+/// The body of an HTTP/1.1 response, an error unless the status is 2xx
+fn parse_response(response: &[u8]) -> Result<Vec<u8>> {
+  let split = response
+    .windows(4)
+    .position(|window| window == b"\r\n\r\n")
+    .ok_or_else(|| anyhow!("incomplete response"))?;
+  let head = String::from_utf8_lossy(&response[..split]);
+  let body = &response[split + 4..];
+
+  let mut lines = head.lines();
+  let status = lines
+    .next()
+    .and_then(|line| line.split_whitespace().nth(1))
+    .and_then(|status| status.parse::<u16>().ok())
+    .ok_or_else(|| anyhow!("invalid status line"))?;
+  let chunked = lines.any(|line| {
+    line
+      .split_once(':')
+      .map(|(name, value)| {
+        name.trim().eq_ignore_ascii_case("transfer-encoding")
+          && value.trim().eq_ignore_ascii_case("chunked")
+      })
+      .unwrap_or(false)
+  });
+  let body = if chunked {
+    decode_chunked(body)?
+  } else {
+    body.to_vec()
+  };
+
+  if !(200..300).contains(&status) {
+    return Err(anyhow!(
+      "status {}: {}",
+      status,
+      String::from_utf8_lossy(&body).trim()
+    ));
+  }
+  Ok(body)
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>> {
+  let mut decoded = Vec::new();
+  loop {
+    let line_end = body
+      .windows(2)
+      .position(|window| window == b"\r\n")
+      .ok_or_else(|| anyhow!("incomplete chunk"))?;
+    let size = String::from_utf8_lossy(&body[..line_end]);
+    // Chunk extensions after a `;` aren't used by the daemons
+    let size = usize::from_str_radix(size.split(';').next().unwrap_or_default().trim(), 16)?;
+    body = &body[line_end + 2..];
+    if size == 0 {
+      return Ok(decoded);
+    }
+    if body.len() < size + 2 {
+      return Err(anyhow!("incomplete chunk"));
+    }
+    decoded.extend_from_slice(&body[..size]);
+    body = &body[size + 2..];
+  }
+}
+
+/// Percentage of a single core used between two samples of the container's and the
+/// host's CPU time, like `docker stats`
+fn cpu_usage(current: (u64, u64), previous: (u64, u64), online_cpus: u32) -> Option<f64> {
+  let container = current.0.checked_sub(previous.0)?;
+  let system = current.1.checked_sub(previous.1)?;
+  if system == 0 {
+    return None;
+  }
+  Some(container as f64 / system as f64 * online_cpus as f64 * 100.0)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerSummary {
+  id: String,
+  #[serde(default)]
+  names: Vec<String>,
+  #[serde(default)]
+  image: String,
+  #[serde(default)]
+  state: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerInspect {
+  #[serde(default)]
+  restart_count: u32,
+  #[serde(default)]
+  state: InspectState,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct InspectState {
+  /// Older Podman versions call it `Healthcheck`
+  #[serde(alias = "Healthcheck")]
+  health: Option<InspectHealth>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InspectHealth {
+  #[serde(default)]
+  status: String,
+}
+
+#[derive(Deserialize)]
+struct StatsResponse {
+  #[serde(default)]
+  cpu_stats: CpuStats,
+  #[serde(default)]
+  precpu_stats: CpuStats,
+  #[serde(default)]
+  memory_stats: MemoryStats,
+  networks: Option<HashMap<String, NetworkStats>>,
+}
+
+#[derive(Deserialize, Default)]
+struct CpuStats {
+  #[serde(default)]
+  cpu_usage: CpuUsage,
+  system_cpu_usage: Option<u64>,
+  online_cpus: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+struct CpuUsage {
+  #[serde(default)]
+  total_usage: u64,
+  percpu_usage: Option<Vec<u64>>,
+}
+
+#[derive(Deserialize, Default)]
+struct MemoryStats {
+  usage: Option<u64>,
+  limit: Option<u64>,
+  #[serde(default)]
+  stats: HashMap<String, u64>,
+}
+
+#[derive(Deserialize)]
+struct NetworkStats {
+  #[serde(default)]
+  rx_bytes: u64,
+  #[serde(default)]
+  tx_bytes: u64,
+}
+
+impl StatsResponse {
+  fn network_bytes(&self) -> Option<(u64, u64)> {
+    self.networks.as_ref().map(|networks| {
+      networks.values().fold((0, 0), |(rx, tx), network| {
+        (rx + network.rx_bytes, tx + network.tx_bytes)
+      })
+    })
+  }
+
+  fn sample(&self, at: Instant) -> ContainerSample {
+    let (rx_bytes, tx_bytes) = self.network_bytes().unwrap_or_default();
+    ContainerSample {
+      at,
+      cpu_total: self.cpu_stats.cpu_usage.total_usage,
+      system_total: self.cpu_stats.system_cpu_usage.unwrap_or_default(),
+      rx_bytes,
+      tx_bytes,
+    }
+  }
+
+  /// Fills in the usage, measured against `previous` unless the daemon already waited for a
+  /// second sample itself like Docker does without `one-shot`
+  fn apply(
+    &self,
+    container: &mut ContainerStats,
+    current: ContainerSample,
+    previous: Option<ContainerSample>,
+  ) {
+    let online_cpus = self.cpu_stats.online_cpus.unwrap_or_else(|| {
+      self
+        .cpu_stats
+        .cpu_usage
+        .percpu_usage
+        .as_ref()
+        .map(|percpu| percpu.len() as u32)
+        .unwrap_or(1)
+    });
+    let current_cpu = (current.cpu_total, current.system_total);
+    container.cpu_usage = match self.precpu_stats.system_cpu_usage {
+      Some(system) if system > 0 => cpu_usage(
+        current_cpu,
+        (self.precpu_stats.cpu_usage.total_usage, system),
+        online_cpus,
+      ),
+      _ => previous.and_then(|previous| {
+        cpu_usage(
+          current_cpu,
+          (previous.cpu_total, previous.system_total),
+          online_cpus,
+        )
+      }),
+    };
+
+    // The page cache can be reclaimed so it isn't counted, cgroup v2 calls it `inactive_file`
+    let cache = ["inactive_file", "total_inactive_file", "cache"]
+      .iter()
+      .find_map(|key| self.memory_stats.stats.get(*key))
+      .copied()
+      .unwrap_or_default();
+    container.memory_used = self
+      .memory_stats
+      .usage
+      .map(|usage| usage.saturating_sub(cache));
+    container.memory_limit = self.memory_stats.limit;
+
+    if let Some((rx_bytes, tx_bytes)) = self.network_bytes() {
+      container.network_rx_bytes = Some(rx_bytes);
+      container.network_tx_bytes = Some(tx_bytes);
+      if let Some(previous) = previous {
+        let elapsed = current.at.duration_since(previous.at).as_secs_f64();
+        let per_second = |bytes: u64, previous: u64| {
+          bytes
+            .checked_sub(previous)
+            .filter(|_| elapsed > 0.0)
+            .map(|bytes| (bytes as f64 * 8.0 / elapsed) as u64)
+        };
+        container.network_rx = per_second(rx_bytes, previous.rx_bytes);
+        container.network_tx = per_second(tx_bytes, previous.tx_bytes);
+      }
+    }
+  }
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+  use super::*;
+  use std::io::{BufRead, BufReader, Write};
+  use std::os::unix::net::UnixListener;
+  use std::time::Duration;
+
+  const WEB: &str = "3f4e5d6c7b8a9f0e1d2c3b4a";
+  const JOB: &str = "0a1b2c3d4e5f6a7b8c9d0e1f";
+
+  /// Serves canned responses by request path on a socket in `dir` like the daemon would
+  fn stub_daemon(dir: &tempfile::TempDir, responses: Vec<(String, String)>) -> PathBuf {
+    slow_daemon(dir, responses, Duration::ZERO)
+  }
+
+  /// A daemon that takes `stats_delay` to answer for the stats, connections are served
+  /// concurrently like the real daemons do
+  fn slow_daemon(
+    dir: &tempfile::TempDir,
+    responses: Vec<(String, String)>,
+    stats_delay: Duration,
+  ) -> PathBuf {
+    let socket = dir.path().join("docker.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    let responses = std::sync::Arc::new(responses);
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let responses = responses.clone();
+        std::thread::spawn(move || {
+          let mut request_line = String::new();
+          BufReader::new(&stream)
+            .read_line(&mut request_line)
+            .unwrap();
+          let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+          if path.contains("/stats") {
+            std::thread::sleep(stats_delay);
+          }
+          // The client may have given up waiting already
+          let _ = match responses.iter().find(|(known, _)| known == path) {
+            // Docker chunks the bodies it streams
+            Some((_, body)) => write!(
+              stream,
+              "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+              body.len(),
+              body
+            ),
+            None => write!(
+              stream,
+              "HTTP/1.1 404 Not Found\r\nContent-Length: 28\r\n\r\n{{\"message\":\"No such thing\"}}"
+            ),
+          };
+        });
+      }
+    });
+    socket
+  }
+
+  fn responses(rx_bytes: u64) -> Vec<(String, String)> {
+    vec![
+      (
+        "/containers/json?all=true".to_string(),
+        format!(
+          r#"[{{"Id":"{}","Names":["/web"],"Image":"nginx:1.25","State":"running"}},
+              {{"Id":"{}","Names":["/job"],"Image":"busybox","State":"exited"}}]"#,
+          WEB, JOB
+        ),
+      ),
+      (
+        format!("/containers/{}/json", WEB),
+        r#"{"RestartCount":3,"State":{"Status":"running","Health":{"Status":"healthy"}}}"#
+          .to_string(),
+      ),
+      (
+        format!("/containers/{}/json", JOB),
+        r#"{"RestartCount":0,"State":{"Status":"exited"}}"#.to_string(),
+      ),
+      (
+        format!("/containers/{}/stats?stream=false&one-shot=true", WEB),
+        format!(
+          r#"{{"cpu_stats":{{"cpu_usage":{{"total_usage":3000}},"system_cpu_usage":20000,"online_cpus":4}},
+              "precpu_stats":{{"cpu_usage":{{"total_usage":0}}}},
+              "memory_stats":{{"usage":1000,"limit":8000,"stats":{{"inactive_file":200}}}},
+              "networks":{{"eth0":{{"rx_bytes":{},"tx_bytes":50}},"eth1":{{"rx_bytes":0,"tx_bytes":50}}}}}}"#,
+          rx_bytes
+        ),
+      ),
+    ]
+  }
+
+  #[test]
+  fn reads_the_containers_from_the_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let runtime = ContainerRuntime::new(stub_daemon(&dir, responses(1500)));
+    let mut samples = HashMap::new();
+    samples.insert(
+      WEB.to_string(),
+      ContainerSample {
+        at: Instant::now() - Duration::from_secs(2),
+        cpu_total: 1000,
+        system_total: 10000,
+        rx_bytes: 500,
+        tx_bytes: 100,
+      },
+    );
+
+    let containers = runtime.containers(&mut samples).unwrap();
+    let web = &containers[0];
+    assert_eq!(web.id, "3f4e5d6c7b8a");
+    assert_eq!(web.name, "web");
+    assert_eq!(web.image, "nginx:1.25");
+    assert_eq!(web.runtime, "docker");
+    assert_eq!(web.health.as_deref(), Some("healthy"));
+    assert_eq!(web.restart_count, 3);
+    // 2000 of the 10000 host nanoseconds on 4 cores
+    assert_eq!(web.cpu_usage.map(|usage| usage.round()), Some(80.0));
+    assert_eq!(web.memory_used, Some(800));
+    assert_eq!(web.memory_limit, Some(8000));
+    assert_eq!(web.network_rx_bytes, Some(1500));
+    assert_eq!(web.network_tx_bytes, Some(100));
+    // 1000 bytes over about 2 seconds
+    assert!((3990..=4000).contains(&web.network_rx.unwrap()));
+    assert_eq!(web.network_tx, Some(0));
+
+    assert_eq!(
+      containers[1],
+      ContainerStats {
+        id: "0a1b2c3d4e5f".to_string(),
+        name: "job".to_string(),
+        image: "busybox".to_string(),
+        runtime: "docker".to_string(),
+        state: "exited".to_string(),
+        ..Default::default()
+      }
+    );
+    assert_eq!(samples[WEB].cpu_total, 3000);
+  }
+
+  #[test]
+  fn the_first_collection_has_no_rates() {
+    let dir = tempfile::tempdir().unwrap();
+    let runtime = ContainerRuntime::new(stub_daemon(&dir, responses(1500)));
+    let containers = runtime.containers(&mut HashMap::new()).unwrap();
+    assert_eq!(containers[0].cpu_usage, None);
+    assert_eq!(containers[0].network_rx, None);
+    assert_eq!(containers[0].network_rx_bytes, Some(1500));
+  }
+
+  #[test]
+  fn errors_carry_the_daemon_message() {
+    let dir = tempfile::tempdir().unwrap();
+    let runtime = ContainerRuntime::new(stub_daemon(&dir, Vec::new()));
+    let error = runtime.containers(&mut HashMap::new()).unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "/containers/json?all=true: status 404: {\"message\":\"No such thing\"}"
+    );
+  }
+
+  /// `count` running containers with the same stats
+  fn running_containers(count: usize) -> Vec<(String, String)> {
+    let ids: Vec<String> = (0..count).map(|i| format!("{:012x}", i)).collect();
+    let list: Vec<String> = ids
+      .iter()
+      .map(|id| {
+        format!(
+          r#"{{"Id":"{}","Names":["/c{}"],"State":"running"}}"#,
+          id, id
+        )
+      })
+      .collect();
+    let mut responses = vec![(
+      "/containers/json?all=true".to_string(),
+      format!("[{}]", list.join(",")),
+    )];
+    for id in &ids {
+      responses.push((format!("/containers/{}/json", id), "{}".to_string()));
+      responses.push((
+        format!("/containers/{}/stats?stream=false&one-shot=true", id),
+        r#"{"memory_stats":{"usage":1000}}"#.to_string(),
+      ));
+    }
+    responses
+  }
+
+  #[test]
+  fn slow_stats_are_read_concurrently() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = slow_daemon(&dir, running_containers(5), Duration::from_millis(400));
+    let runtime = ContainerRuntime::new(socket);
+
+    let started = Instant::now();
+    let containers = runtime.containers(&mut HashMap::new()).unwrap();
+    // One after the other they'd take 2 seconds
+    assert!(started.elapsed() < Duration::from_millis(1500));
+    assert_eq!(containers.len(), 5);
+    assert!(containers
+      .iter()
+      .all(|container| container.memory_used == Some(1000)));
+  }
+
+  #[test]
+  fn stats_that_time_out_are_left_out() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = slow_daemon(&dir, responses(1500), Duration::from_secs(5));
+    let runtime = ContainerRuntime {
+      timeout: Duration::from_millis(200),
+      ..ContainerRuntime::new(socket)
+    };
+
+    let started = Instant::now();
+    let mut samples = HashMap::new();
+    let containers = runtime.containers(&mut samples).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(containers.len(), 2);
+    assert_eq!(containers[0].restart_count, 3);
+    assert_eq!(containers[0].memory_used, None);
+    assert!(samples.is_empty());
+  }
+
+  #[test]
+  fn an_unresponsive_daemon_times_out() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("docker.sock");
+    // Accepts the connections and never answers
+    let listener = UnixListener::bind(&socket).unwrap();
+    let runtime = ContainerRuntime {
+      timeout: Duration::from_millis(200),
+      ..ContainerRuntime::new(socket)
+    };
+
+    let started = Instant::now();
+    let error = runtime.containers(&mut HashMap::new()).unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(
+      error.to_string(),
+      "/containers/json?all=true: timed out after 200ms"
+    );
+    drop(listener);
+  }
+
+  fn socket_paths(sockets: &[&PathBuf]) -> Vec<String> {
+    sockets
+      .iter()
+      .map(|socket| socket.to_string_lossy().into_owned())
+      .collect()
+  }
+
+  #[test]
+  fn a_failing_runtime_doesnt_hide_the_others() {
+    let (working, broken) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let working = stub_daemon(&working, responses(1500));
+    let broken = stub_daemon(&broken, Vec::new());
+    let missing = PathBuf::from("/nonexistent/docker.sock");
+    let sockets = socket_paths(&[&broken, &missing, &working]);
+
+    let mut runtimes = Runtimes::default();
+    for _ in 0..2 {
+      assert_eq!(runtimes.collect(&sockets).len(), 2);
+    }
+    // Remembered so it's only logged the first time, a missing socket isn't an error
+    assert_eq!(runtimes.failing, vec![broken.canonicalize().unwrap()]);
+    assert_eq!(runtimes.samples.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn a_slow_collection_reports_the_previous_containers() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = slow_daemon(&dir, responses(1500), Duration::from_millis(400));
+    let sockets = socket_paths(&[&socket]);
+    let deadline = Duration::from_millis(100);
+    let mut collector = ContainerCollector::default();
+
+    let started = Instant::now();
+    assert!(collector
+      .collect(sockets.clone(), deadline)
+      .await
+      .is_empty());
+    assert!(started.elapsed() < Duration::from_millis(300));
+    assert!(collector.slow);
+
+    // The next collection picks up the one that kept running
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(collector.collect(sockets.clone(), deadline).await.len(), 2);
+    assert!(!collector.slow);
+    assert_eq!(collector.runtimes.samples.len(), 1);
+
+    // And a slow one after that reports those until it's done
+    let containers = collector.collect(sockets, deadline).await;
+    assert_eq!(containers.len(), 2);
+    assert!(collector.running.is_some());
+  }
+
+  #[test]
+  fn decodes_chunked_bodies() {
+    assert_eq!(
+      decode_chunked(b"4\r\nWiki\r\n6;name=x\r\npedia \r\n0\r\n\r\n").unwrap(),
+      b"Wikipedia ".to_vec()
+    );
+    assert!(decode_chunked(b"a\r\nshort\r\n").is_err());
+  }
+
+  #[test]
+  fn podman_sockets_are_named_after_it() {
+    let runtime = ContainerRuntime::new(PathBuf::from("/run/podman/podman.sock"));
+    assert_eq!(runtime.name, "podman");
+  }
+}
@@ -1,42 +1,417 @@
 use anyhow::Result;
-use sysinfo::{DiskExt, SystemExt};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
+use sysinfo::{DiskExt, DiskType, SystemExt};
 
-use crate::types::DiskStats;
+use crate::types::{DiskIoStats, DiskStats};
 
+use super::mounts::{device_id, FsUsage, Mount};
+use super::sysfs::Sysfs;
 use super::DataCollector;
 
+/// `/proc/diskstats` always counts in 512 byte sectors whatever the device's sector size is
+const SECTOR_SIZE: u64 = 512;
+
+/// Kernel filesystems without any space, skipped without asking them so a hung one can't block
+const PSEUDO_FILESYSTEMS: &[&str] = &[
+  "autofs",
+  "binfmt_misc",
+  "bpf",
+  "cgroup",
+  "cgroup2",
+  "configfs",
+  "debugfs",
+  "devpts",
+  "efivarfs",
+  "fusectl",
+  "hugetlbfs",
+  "mqueue",
+  "nsfs",
+  "proc",
+  "pstore",
+  "rpc_pipefs",
+  "securityfs",
+  "selinuxfs",
+  "sysfs",
+  "tracefs",
+];
+
+/// The cumulative counters of a block device from `/proc/diskstats`,
+/// see Documentation/admin-guide/iostats.rst in the kernel
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DiskCounters {
+  reads: u64,
+  read_sectors: u64,
+  read_ms: u64,
+  writes: u64,
+  write_sectors: u64,
+  write_ms: u64,
+  /// Milliseconds spent doing I/O, the time the device was busy
+  io_ms: u64,
+}
+
+impl DiskCounters {
+  /// Parses `/proc/diskstats` into the counters of every block device by name,
+  /// empty outside of Linux
+  fn from_procfs(procfs: &Sysfs) -> HashMap<String, Self> {
+    procfs
+      .read_string("diskstats")
+      .unwrap_or_default()
+      .lines()
+      .filter_map(DiskCounters::parse_line)
+      .collect()
+  }
+
+  /// `major minor name reads reads_merged read_sectors read_ms writes writes_merged
+  /// write_sectors write_ms in_flight io_ms ...`, newer kernels append more fields
+  fn parse_line(line: &str) -> Option<(String, Self)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let field = |index: usize| fields.get(index)?.parse::<u64>().ok();
+    Some((
+      fields.get(2)?.to_string(),
+      Self {
+        reads: field(3)?,
+        read_sectors: field(5)?,
+        read_ms: field(6)?,
+        writes: field(7)?,
+        write_sectors: field(9)?,
+        write_ms: field(10)?,
+        io_ms: field(12)?,
+      },
+    ))
+  }
+}
+
+/// A mounted filesystem before the filters are applied
+struct MountedDisk {
+  name: String,
+  mount: String,
+  fs: String,
+  r#type: DiskType,
+  usage: FsUsage,
+  options: Vec<String>,
+  removable: bool,
+}
+
 impl DataCollector {
   /// Gets the current disk(s) stats
   pub fn get_disks(&mut self) -> Result<Vec<DiskStats>> {
+    let io = DataCollector::get_disk_io(
+      &mut self.disk_counters,
+      DiskCounters::from_procfs(&self.procfs),
+    );
+
+    // sysinfo always leaves out tmpfs, squashfs and a few others, the mount table
+    // is read directly where there's one so the filters decide instead
+    let mounts = Mount::from_procfs(&self.procfs);
+    let mounted_disks = if mounts.is_empty() {
+      self.get_sysinfo_disks()
+    } else {
+      self.get_mounted_disks(mounts)
+    };
+
     let mut disks = Vec::<DiskStats>::new();
-    self.fetcher.refresh_disks_list();
-    for disk in self.fetcher.disks() {
-      let (name, mount) = (
-        disk.name().to_string_lossy(),
-        disk.mount_point().to_string_lossy(),
-      );
-
-      if name.contains("docker") || mount.contains("docker") || mount.contains("boot") {
-        continue;
+    let mut device_ids = Vec::new();
+    for disk in mounted_disks {
+      // Bind mounts repeat the same filesystem and device id, only its first mount is reported.
+      // btrfs gives every subvolume its own anonymous device id so each of them is reported
+      if self.deduplicate_disks {
+        if let Some(device_id) = device_id(Path::new(&disk.mount)) {
+          if device_ids.contains(&device_id) {
+            continue;
+          }
+          device_ids.push(device_id);
+        }
       }
 
-      let (fs_type, mut str) = (disk.file_system(), String::from(""));
+      disks.push(DiskStats {
+        io: DataCollector::get_block_device(&disk.name).and_then(|device| io.get(&device).cloned()),
+        name: disk.name,
+        mount: disk.mount,
+        fs: disk.fs,
+        r#type: format!("{:?}", disk.r#type),
+        total: disk.usage.total,
+        used: disk.usage.total.saturating_sub(disk.usage.available),
+        inodes_total: disk.usage.inodes_total,
+        inodes_used: disk
+          .usage
+          .inodes_total
+          .zip(disk.usage.inodes_free)
+          .map(|(total, free)| total.saturating_sub(free)),
+        inodes_free: disk.usage.inodes_free,
+        read_only: disk.usage.read_only || disk.options.iter().any(|option| option == "ro"),
+        mount_options: disk.options,
+        removable: disk.removable,
+      });
+    }
+    Ok(disks)
+  }
+
+  fn get_sysinfo_disks(&mut self) -> Vec<MountedDisk> {
+    self.fetcher.refresh_disks_list();
+    self
+      .fetcher
+      .disks()
+      .iter()
+      .filter(|disk| {
+        self.is_disk_reported(
+          &disk.name().to_string_lossy(),
+          &disk.mount_point().to_string_lossy(),
+          &String::from_utf8_lossy(disk.file_system()),
+        )
+      })
+      .map(|disk| MountedDisk {
+        name: disk.name().to_string_lossy().to_string(),
+        mount: disk.mount_point().to_string_lossy().to_string(),
+        fs: String::from_utf8_lossy(disk.file_system()).to_string(),
+        r#type: disk.type_(),
+        // statvfs knows the inodes and read-only state where there's one, sysinfo doesn't
+        usage: FsUsage::from_statvfs(disk.mount_point()).unwrap_or(FsUsage {
+          total: disk.total_space(),
+          available: disk.available_space(),
+          ..FsUsage::default()
+        }),
+        options: Vec::new(),
+        removable: disk.is_removable(),
+      })
+      .collect()
+  }
+
+  /// The mounted filesystems that pass the filters and have any space. Only those get asked for
+  /// their usage since a hung network filesystem blocks whoever does
+  fn get_mounted_disks(&self, mounts: Vec<Mount>) -> Vec<MountedDisk> {
+    mounts
+      .into_iter()
+      .filter(|mount| {
+        !is_pseudo_filesystem(&mount.fs_type)
+          && self.is_disk_reported(&mount.device, &mount.mount_point, &mount.fs_type)
+      })
+      .filter_map(|mount| {
+        let usage = FsUsage::from_statvfs(Path::new(&mount.mount_point))?;
+        if usage.total == 0 {
+          return None;
+        }
+        Some(MountedDisk {
+          r#type: DataCollector::get_disk_type(&self.sysfs, &mount.device),
+          removable: DataCollector::is_removable(&self.sysfs, &mount.device),
+          name: mount.device,
+          mount: mount.mount_point,
+          fs: mount.fs_type,
+          usage,
+          options: mount.options,
+        })
+      })
+      .collect()
+  }
 
-      for unit in fs_type {
-        str.push(*unit as char);
-      }
+  /// Whether a filesystem passes the disk filters, checked before anything is read about it
+  fn is_disk_reported(&self, name: &str, mount: &str, fs: &str) -> bool {
+    self.disk_filter.allows(&[name, mount]) && self.disk_fs_type_filter.allows(&[fs])
+  }
+
+  /// Reads a file of the block device behind a mount, partitions don't have the
+  /// attributes of the whole disk so they're read from the disk they're on
+  fn read_block_attribute(sysfs: &Sysfs, name: &str, attribute: &str) -> Option<u64> {
+    let device = sysfs
+      .root
+      .join("class/block")
+      .join(DataCollector::get_block_device(name)?);
+    if device.join("partition").exists() {
+      sysfs.read_u64(device.join("..").join(attribute))
+    } else {
+      sysfs.read_u64(device.join(attribute))
+    }
+  }
+
+  /// Whether the block device behind a mount spins
+  fn get_disk_type(sysfs: &Sysfs, name: &str) -> DiskType {
+    match DataCollector::read_block_attribute(sysfs, name, "queue/rotational") {
+      Some(1) => DiskType::HDD,
+      Some(0) => DiskType::SSD,
+      _ => DiskType::Unknown(-1),
+    }
+  }
+
+  /// Whether the block device behind a mount is a USB stick, SD card and the like
+  fn is_removable(sysfs: &Sysfs, name: &str) -> bool {
+    DataCollector::read_block_attribute(sysfs, name, "removable") == Some(1)
+  }
 
-      let disk = DiskStats {
-        name: format!("{}", disk.name().to_string_lossy()),
-        mount: format!("{}", disk.mount_point().to_string_lossy()),
-        fs: str,
-        r#type: format!("{:?}", disk.type_()),
-        total: disk.total_space(),
-        used: disk.total_space() - disk.available_space(),
+  /// The name `/proc/diskstats` knows a mounted device by, `/dev/mapper/root` is a symlink to `/dev/dm-0`
+  fn get_block_device(name: &str) -> Option<String> {
+    let path = Path::new(name);
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    path
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string())
+  }
+
+  /// The I/O of every block device since its counters were last read, all 0 on the first read.
+  /// Only the devices still there are remembered so one coming back starts over
+  fn get_disk_io(
+    disk_counters: &mut HashMap<String, (Instant, DiskCounters)>,
+    counters: HashMap<String, DiskCounters>,
+  ) -> HashMap<String, DiskIoStats> {
+    let now = Instant::now();
+    let mut io = HashMap::new();
+
+    for (device, current) in counters {
+      let stats = match disk_counters.get(&device) {
+        Some((then, previous)) => {
+          DataCollector::get_disk_rates(previous, &current, now.duration_since(*then).as_secs_f64())
+        }
+        None => DiskIoStats::default(),
       };
+      io.insert(device.clone(), stats);
+      disk_counters.insert(device, (now, current));
+    }
 
-      disks.push(disk);
+    disk_counters.retain(|device, _| io.contains_key(device));
+    io
+  }
+
+  /// Counters going backwards after a device was re-added count as no I/O
+  fn get_disk_rates(previous: &DiskCounters, current: &DiskCounters, elapsed: f64) -> DiskIoStats {
+    if elapsed <= 0.0 {
+      return DiskIoStats::default();
+    }
+    let delta = |current: u64, previous: u64| current.saturating_sub(previous);
+    let (reads, writes) = (
+      delta(current.reads, previous.reads),
+      delta(current.writes, previous.writes),
+    );
+    let io_time =
+      delta(current.read_ms, previous.read_ms) + delta(current.write_ms, previous.write_ms);
+
+    let bytes_per_second =
+      |current: u64, previous: u64| (delta(current, previous) * SECTOR_SIZE) as f64 / elapsed;
+
+    DiskIoStats {
+      read_bytes_per_second: bytes_per_second(current.read_sectors, previous.read_sectors).round()
+        as u64,
+      write_bytes_per_second: bytes_per_second(current.write_sectors, previous.write_sectors)
+        .round() as u64,
+      read_iops: reads as f64 / elapsed,
+      write_iops: writes as f64 / elapsed,
+      latency_ms: if reads + writes == 0 {
+        0.0
+      } else {
+        io_time as f64 / (reads + writes) as f64
+      },
+      utilization: (delta(current.io_ms, previous.io_ms) as f64 / (elapsed * 1000.0) * 100.0)
+        .min(100.0),
+    }
+  }
+}
+
+/// Whether `fs_type` is one of the kernel's pseudo filesystems
+fn is_pseudo_filesystem(fs_type: &str) -> bool {
+  PSEUDO_FILESYSTEMS.contains(&fs_type)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use tempfile::TempDir;
+
+  const DISKSTATS: &str =
+    "   8       0 sda 1000 10 80000 500 2000 20 160000 1500 0 1800 2000 0 0 0 0
+ 253       0 dm-0 30 0 240 10 40 0 320 30 0 50 40
+   7       0 loop0 garbage
+";
+
+  #[test]
+  fn parses_diskstats() {
+    let root = TempDir::new().unwrap();
+    fs::write(root.path().join("diskstats"), DISKSTATS).unwrap();
+
+    let counters = DiskCounters::from_procfs(&Sysfs::new(root.path()));
+    assert_eq!(counters.len(), 2);
+    assert_eq!(
+      counters["sda"],
+      DiskCounters {
+        reads: 1000,
+        read_sectors: 80000,
+        read_ms: 500,
+        writes: 2000,
+        write_sectors: 160000,
+        write_ms: 1500,
+        io_ms: 1800,
+      }
+    );
+    assert_eq!(counters["dm-0"].io_ms, 50);
+  }
+
+  #[test]
+  fn computes_the_rates_over_the_elapsed_time() {
+    let previous = DiskCounters::default();
+    let current = DiskCounters {
+      reads: 100,
+      read_sectors: 2048,
+      read_ms: 100,
+      writes: 300,
+      write_sectors: 4096,
+      write_ms: 700,
+      io_ms: 1000,
+    };
+
+    let io = DataCollector::get_disk_rates(&previous, &current, 2.0);
+    assert_eq!(io.read_bytes_per_second, 512 * 1024);
+    assert_eq!(io.write_bytes_per_second, 1024 * 1024);
+    assert_eq!(io.read_iops, 50.0);
+    assert_eq!(io.write_iops, 150.0);
+    assert_eq!(io.latency_ms, 2.0);
+    assert_eq!(io.utilization, 50.0);
+  }
+
+  #[test]
+  fn the_first_read_and_reset_counters_report_no_io() {
+    let counters = DiskCounters {
+      reads: 100,
+      ..DiskCounters::default()
+    };
+    let mut disk_counters = HashMap::new();
+    let io = DataCollector::get_disk_io(
+      &mut disk_counters,
+      HashMap::from([("sda".to_string(), counters.clone())]),
+    );
+    assert_eq!(io["sda"], DiskIoStats::default());
+
+    let io = DataCollector::get_disk_rates(&counters, &DiskCounters::default(), 1.0);
+    assert_eq!(io, DiskIoStats::default());
+  }
+
+  #[test]
+  #[cfg(target_family = "unix")]
+  fn partitions_get_the_attributes_of_their_disk() {
+    let root = TempDir::new().unwrap();
+    let disk = root.path().join("devices/usb1/block/sdb");
+    fs::create_dir_all(disk.join("sdb1")).unwrap();
+    fs::create_dir_all(disk.join("queue")).unwrap();
+    fs::write(disk.join("sdb1/partition"), "1\n").unwrap();
+    fs::write(disk.join("removable"), "1\n").unwrap();
+    fs::write(disk.join("queue/rotational"), "0\n").unwrap();
+    fs::create_dir_all(root.path().join("class/block")).unwrap();
+    std::os::unix::fs::symlink(disk.join("sdb1"), root.path().join("class/block/sdb1")).unwrap();
+
+    let sysfs = Sysfs::new(root.path());
+    assert!(DataCollector::is_removable(&sysfs, "/dev/sdb1"));
+    assert!(matches!(
+      DataCollector::get_disk_type(&sysfs, "/dev/sdb1"),
+      DiskType::SSD
+    ));
+    assert!(!DataCollector::is_removable(&sysfs, "/dev/sdc1"));
+  }
+
+  #[test]
+  fn pseudo_filesystems_are_skipped() {
+    for fs_type in ["proc", "sysfs", "cgroup2", "devpts", "autofs"] {
+      assert!(is_pseudo_filesystem(fs_type), "{}", fs_type);
+    }
+    for fs_type in ["ext4", "btrfs", "nfs4", "fuse.sshfs", "tmpfs", "zfs"] {
+      assert!(!is_pseudo_filesystem(fs_type), "{}", fs_type);
     }
-    Ok(disks)
   }
 }
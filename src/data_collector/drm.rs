@@ -0,0 +1,421 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::types::GPUStats;
+
+use super::gpu::GpuBackend;
+use super::sysfs::Sysfs;
+
+/// The kernel drivers whose GPUs are read from sysfs, NVIDIA's are left to NVML
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DrmDriver {
+  Amdgpu,
+  I915,
+  Xe,
+}
+
+impl DrmDriver {
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "amdgpu" => Some(DrmDriver::Amdgpu),
+      "i915" => Some(DrmDriver::I915),
+      "xe" => Some(DrmDriver::Xe),
+      _ => None,
+    }
+  }
+
+  fn brand(&self) -> &'static str {
+    match self {
+      DrmDriver::Amdgpu => "AMD",
+      DrmDriver::I915 | DrmDriver::Xe => "Intel",
+    }
+  }
+}
+
+/// A `/sys/class/drm/card<index>` of a supported driver
+#[derive(Debug, Clone)]
+struct DrmCard {
+  index: u32,
+  path: PathBuf,
+  driver: DrmDriver,
+  /// Like `0000:03:00.0`
+  pci_slot: String,
+  /// Vendor and device id like `1002:73BF`
+  pci_id: String,
+}
+
+/// A cumulative counter as last read, for the rates
+type Counter = (Instant, u64);
+
+/// The AMD and Intel GPUs the kernel exposes under `/sys/class/drm`
+#[derive(Debug)]
+pub struct DrmGpus {
+  sysfs: Sysfs,
+  cards: Vec<DrmCard>,
+  /// Milliseconds the Intel GPUs spent idle in RC6, they don't report a utilization
+  idle_counters: HashMap<u32, Counter>,
+  /// Microjoules the Intel GPUs used, they report an energy counter instead of a power draw
+  energy_counters: HashMap<u32, Counter>,
+}
+
+impl DrmGpus {
+  /// Finds the cards of the supported drivers, `None` when there are none
+  pub fn discover(sysfs: Sysfs) -> Option<Self> {
+    let mut cards: Vec<DrmCard> = fs::read_dir(sysfs.root.join("class/drm"))
+      .ok()?
+      .flatten()
+      .filter_map(|entry| {
+        // The connectors like card0-DP-1 and the render nodes are skipped
+        let name = entry.file_name().to_string_lossy().to_string();
+        let index = name.strip_prefix("card")?.parse().ok()?;
+        DrmGpus::read_card(&sysfs, index)
+      })
+      .collect();
+    if cards.is_empty() {
+      return None;
+    }
+    cards.sort_by_key(|card| card.index);
+
+    Some(Self {
+      sysfs,
+      cards,
+      idle_counters: HashMap::new(),
+      energy_counters: HashMap::new(),
+    })
+  }
+
+  fn read_card(sysfs: &Sysfs, index: u32) -> Option<DrmCard> {
+    let path = sysfs.root.join("class/drm").join(format!("card{}", index));
+    let uevent = sysfs.read_string(path.join("device/uevent"))?;
+    let field = |key: &str| {
+      uevent
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .map(str::to_string)
+    };
+
+    Some(DrmCard {
+      index,
+      driver: DrmDriver::from_name(&field("DRIVER")?)?,
+      pci_slot: field("PCI_SLOT_NAME").unwrap_or_default(),
+      pci_id: field("PCI_ID").unwrap_or_default(),
+      path,
+    })
+  }
+
+  fn amdgpu_stats(&self, card: &DrmCard, stats: GPUStats) -> GPUStats {
+    let device = card.path.join("device");
+    let hwmon = find_hwmon(&device);
+    let read_hwmon = |name: &str| {
+      hwmon
+        .as_ref()
+        .and_then(|hwmon| self.sysfs.read_u64(hwmon.join(name)))
+    };
+
+    GPUStats {
+      name: self
+        .sysfs
+        .read_string(device.join("product_name"))
+        .filter(|name| !name.is_empty())
+        .unwrap_or(stats.name),
+      uuid: self
+        .sysfs
+        .read_string(device.join("unique_id"))
+        .filter(|unique_id| !unique_id.is_empty()),
+      gpu_usage: self
+        .sysfs
+        .read_u64(device.join("gpu_busy_percent"))
+        .map(|percent| percent as u32),
+      // The average is missing on newer cards that only have the instant draw
+      power_usage: read_hwmon("power1_average")
+        .or_else(|| read_hwmon("power1_input"))
+        .map(microwatts_to_milliwatts),
+      power_limit: read_hwmon("power1_cap").map(microwatts_to_milliwatts),
+      memory_used: self.sysfs.read_u64(device.join("mem_info_vram_used")),
+      memory_total: self.sysfs.read_u64(device.join("mem_info_vram_total")),
+      memory_usage: self
+        .sysfs
+        .read_u64(device.join("mem_busy_percent"))
+        .map(|percent| percent as u32),
+      temperature: read_hwmon("temp1_input").map(|millidegrees| (millidegrees / 1000) as u32),
+      // The shader and memory clocks are in Hz
+      sm_clock: read_hwmon("freq1_input").map(|hz| (hz / 1_000_000) as u32),
+      memory_clock: read_hwmon("freq2_input").map(|hz| (hz / 1_000_000) as u32),
+      fan_speed: read_hwmon("pwm1").map(|pwm| (pwm * 100 / 255) as u32),
+      ..stats
+    }
+  }
+
+  fn intel_stats(&mut self, card: &DrmCard, stats: GPUStats) -> GPUStats {
+    let now = Instant::now();
+    let hwmon = find_hwmon(&card.path.join("device"));
+    let read_hwmon = |name: &str| {
+      hwmon
+        .as_ref()
+        .and_then(|hwmon| self.sysfs.read_u64(hwmon.join(name)))
+    };
+
+    // i915 moved the GT files under gt/gt0 at some point, xe has them per tile
+    let (idle, frequency) = match card.driver {
+      DrmDriver::Xe => (
+        self
+          .sysfs
+          .read_u64(card.path.join("device/tile0/gt0/gtidle/idle_residency_ms")),
+        self
+          .sysfs
+          .read_u64(card.path.join("device/tile0/gt0/freq0/act_freq")),
+      ),
+      _ => (
+        self
+          .sysfs
+          .read_u64(card.path.join("gt/gt0/rc6_residency_ms"))
+          .or_else(|| {
+            self
+              .sysfs
+              .read_u64(card.path.join("power/rc6_residency_ms"))
+          }),
+        self
+          .sysfs
+          .read_u64(card.path.join("gt_act_freq_mhz"))
+          .or_else(|| self.sysfs.read_u64(card.path.join("gt_cur_freq_mhz"))),
+      ),
+    };
+    let energy = read_hwmon("energy1_input");
+    let power_limit = read_hwmon("power1_max")
+      .or_else(|| read_hwmon("power1_cap"))
+      .map(microwatts_to_milliwatts);
+    let temperature = read_hwmon("temp1_input")
+      .or_else(|| read_hwmon("temp2_input"))
+      .map(|millidegrees| (millidegrees / 1000) as u32);
+
+    let gpu_usage = idle.and_then(|idle| {
+      let previous = self.idle_counters.insert(card.index, (now, idle))?;
+      Some(busy_percent(previous, (now, idle)))
+    });
+    let power_usage = energy.and_then(|energy| {
+      let previous = self.energy_counters.insert(card.index, (now, energy))?;
+      Some(average_milliwatts(previous, (now, energy)))
+    });
+
+    GPUStats {
+      gpu_usage,
+      power_usage,
+      power_limit,
+      temperature,
+      sm_clock: frequency.map(|mhz| mhz as u32),
+      ..stats
+    }
+  }
+}
+
+impl GpuBackend for DrmGpus {
+  fn device_count(&mut self) -> Result<u32> {
+    Ok(self.cards.len() as u32)
+  }
+
+  fn device_stats(&mut self, index: u32) -> Result<GPUStats> {
+    let card = self
+      .cards
+      .get(index as usize)
+      .cloned()
+      .ok_or_else(|| anyhow!("There's no DRM card {}", index))?;
+    if !card.path.exists() {
+      return Err(anyhow!("card{} went away", card.index));
+    }
+
+    let stats = GPUStats {
+      index,
+      name: format!("{} GPU {}", card.driver.brand(), card.pci_id),
+      pci_bus_id: card.pci_slot.clone(),
+      brand: card.driver.brand().to_string(),
+      ..GPUStats::default()
+    };
+    Ok(match card.driver {
+      DrmDriver::Amdgpu => self.amdgpu_stats(&card, stats),
+      DrmDriver::I915 | DrmDriver::Xe => self.intel_stats(&card, stats),
+    })
+  }
+}
+
+/// The hwmon directory of a device, there's one per device that has sensors
+fn find_hwmon(device: &Path) -> Option<PathBuf> {
+  fs::read_dir(device.join("hwmon"))
+    .ok()?
+    .flatten()
+    .map(|entry| entry.path())
+    .find(|path| {
+      path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("hwmon"))
+    })
+}
+
+fn microwatts_to_milliwatts(microwatts: u64) -> u32 {
+  (microwatts / 1000) as u32
+}
+
+/// The percentage of the time the GPU wasn't idle between two reads of its idle counter in milliseconds
+fn busy_percent(previous: Counter, current: Counter) -> u32 {
+  let elapsed = current.0.duration_since(previous.0).as_secs_f64() * 1000.0;
+  if elapsed <= 0.0 {
+    return 0;
+  }
+  let idle = current.1.saturating_sub(previous.1) as f64;
+  (100.0 - (idle / elapsed * 100.0).min(100.0)).round() as u32
+}
+
+/// The average power draw between two reads of an energy counter in microjoules
+fn average_milliwatts(previous: Counter, current: Counter) -> u32 {
+  let elapsed = current.0.duration_since(previous.0).as_secs_f64();
+  if elapsed <= 0.0 {
+    return 0;
+  }
+  (current.1.saturating_sub(previous.1) as f64 / elapsed / 1000.0).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use tempfile::TempDir;
+
+  /// Writes `files` relative to `root`, creating their directories
+  fn write_tree(root: &Path, files: &[(&str, &str)]) {
+    for (path, content) in files {
+      let path = root.join(path);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, format!("{}\n", content)).unwrap();
+    }
+  }
+
+  /// An AMD card, an Intel one, an NVIDIA one NVML takes care of and their connectors
+  fn fake_sysfs() -> (TempDir, Sysfs) {
+    let root = TempDir::new().unwrap();
+    write_tree(
+      root.path(),
+      &[
+        (
+          "class/drm/card0/device/uevent",
+          "DRIVER=amdgpu\nPCI_ID=1002:73BF\nPCI_SLOT_NAME=0000:03:00.0",
+        ),
+        ("class/drm/card0/device/gpu_busy_percent", "42"),
+        ("class/drm/card0/device/unique_id", "7a6b1c2d3e4f5a6b"),
+        ("class/drm/card0/device/mem_busy_percent", "7"),
+        ("class/drm/card0/device/mem_info_vram_used", "1073741824"),
+        ("class/drm/card0/device/mem_info_vram_total", "17163091968"),
+        ("class/drm/card0/device/hwmon/hwmon4/temp1_input", "54000"),
+        (
+          "class/drm/card0/device/hwmon/hwmon4/power1_average",
+          "87000000",
+        ),
+        (
+          "class/drm/card0/device/hwmon/hwmon4/power1_cap",
+          "272000000",
+        ),
+        (
+          "class/drm/card0/device/hwmon/hwmon4/freq1_input",
+          "2100000000",
+        ),
+        (
+          "class/drm/card0/device/hwmon/hwmon4/freq2_input",
+          "1000000000",
+        ),
+        ("class/drm/card0/device/hwmon/hwmon4/pwm1", "51"),
+        ("class/drm/card0-DP-1/status", "connected"),
+        (
+          "class/drm/card1/device/uevent",
+          "DRIVER=i915\nPCI_ID=8086:56A0\nPCI_SLOT_NAME=0000:00:02.0",
+        ),
+        ("class/drm/card1/gt_act_freq_mhz", "1300"),
+        ("class/drm/card1/gt/gt0/rc6_residency_ms", "1000"),
+        (
+          "class/drm/card1/device/hwmon/hwmon5/energy1_input",
+          "5000000",
+        ),
+        (
+          "class/drm/card1/device/hwmon/hwmon5/power1_max",
+          "190000000",
+        ),
+        (
+          "class/drm/card2/device/uevent",
+          "DRIVER=nvidia\nPCI_SLOT_NAME=0000:01:00.0",
+        ),
+        ("class/drm/renderD128/dev", "226:128"),
+      ],
+    );
+    let sysfs = Sysfs::new(root.path());
+    (root, sysfs)
+  }
+
+  #[test]
+  fn discovers_the_supported_cards() {
+    let (_root, sysfs) = fake_sysfs();
+    let drm = DrmGpus::discover(sysfs).unwrap();
+    let cards: Vec<(u32, DrmDriver)> = drm
+      .cards
+      .iter()
+      .map(|card| (card.index, card.driver))
+      .collect();
+    assert_eq!(cards, vec![(0, DrmDriver::Amdgpu), (1, DrmDriver::I915)]);
+  }
+
+  #[test]
+  fn no_supported_cards_is_none() {
+    let root = TempDir::new().unwrap();
+    assert!(DrmGpus::discover(Sysfs::new(root.path())).is_none());
+  }
+
+  #[test]
+  fn reads_amdgpu_stats() {
+    let (_root, sysfs) = fake_sysfs();
+    let gpu = DrmGpus::discover(sysfs).unwrap().device_stats(0).unwrap();
+
+    assert_eq!(gpu.brand, "AMD");
+    assert_eq!(gpu.name, "AMD GPU 1002:73BF");
+    assert_eq!(gpu.pci_bus_id, "0000:03:00.0");
+    assert_eq!(gpu.uuid.as_deref(), Some("7a6b1c2d3e4f5a6b"));
+    assert_eq!(gpu.gpu_usage, Some(42));
+    assert_eq!(gpu.memory_usage, Some(7));
+    assert_eq!(gpu.memory_used, Some(1073741824));
+    assert_eq!(gpu.memory_total, Some(17163091968));
+    assert_eq!(gpu.temperature, Some(54));
+    assert_eq!(gpu.power_usage, Some(87000));
+    assert_eq!(gpu.power_limit, Some(272000));
+    assert_eq!(gpu.sm_clock, Some(2100));
+    assert_eq!(gpu.memory_clock, Some(1000));
+    assert_eq!(gpu.fan_speed, Some(20));
+  }
+
+  #[test]
+  fn reads_intel_stats() {
+    let (_root, sysfs) = fake_sysfs();
+    let mut drm = DrmGpus::discover(sysfs).unwrap();
+    let gpu = drm.device_stats(1).unwrap();
+
+    assert_eq!(gpu.brand, "Intel");
+    assert_eq!(gpu.index, 1);
+    assert_eq!(gpu.uuid, None);
+    assert_eq!(gpu.sm_clock, Some(1300));
+    assert_eq!(gpu.power_limit, Some(190000));
+    // The usage and power draw need a previous read of the counters
+    assert_eq!((gpu.gpu_usage, gpu.power_usage), (None, None));
+    assert!(drm.idle_counters.contains_key(&1));
+    assert!(drm.energy_counters.contains_key(&1));
+  }
+
+  #[test]
+  fn computes_the_usage_and_power_from_the_counters() {
+    let then = Instant::now();
+    let now = then + Duration::from_secs(2);
+
+    // 500ms of the 2s idle
+    assert_eq!(busy_percent((then, 1000), (now, 1500)), 75);
+    assert_eq!(busy_percent((then, 1000), (now, 9000)), 0);
+    // 20J over 2s
+    assert_eq!(average_milliwatts((then, 0), (now, 20_000_000)), 10_000);
+    assert_eq!(average_milliwatts((then, 100), (now, 0)), 0);
+  }
+}
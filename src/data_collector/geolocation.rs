@@ -2,8 +2,6 @@ use super::DataCollector;
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
-const GEOLOCATION_URL: &str = "https://ipwhois.app/json/";
-
 #[derive(Serialize, Deserialize)]
 #[allow(non_snake_case)]
 pub struct GeolocationInfo {
@@ -15,15 +13,15 @@ pub struct GeolocationInfo {
 }
 
 impl DataCollector {
-  /// Gets the geolocation information
-  pub async fn get_geolocation_info() -> Result<GeolocationInfo> {
-    let response = reqwest::get(GEOLOCATION_URL).await?;
+  /// Gets the geolocation information from an ipwhois compatible API
+  pub async fn get_geolocation_info(geolocation_url: &str) -> Result<GeolocationInfo> {
+    let response = reqwest::get(geolocation_url).await?;
 
     if response.status() == reqwest::StatusCode::OK {
       let geolocation_info: GeolocationInfo = response.json().await?;
-      return Ok(geolocation_info);
+      Ok(geolocation_info)
     } else {
-      return Err(anyhow!("Could not get geolocation info"));
+      Err(anyhow!("Could not get geolocation info"))
     }
   }
 }
@@ -1,34 +1,324 @@
 use anyhow::Result;
+use nvml::bitmasks::device::ThrottleReasons;
+use nvml::enum_wrappers::device::{Clock, EccCounter, MemoryError, TemperatureSensor};
+use nvml::enums::device::UsedGpuMemory;
+use nvml::error::NvmlError;
 use nvml::NVML;
+use std::fmt::Debug;
+use sysinfo::{Pid, ProcessExt, SystemExt};
 
-use crate::types::GPUStats;
+use crate::types::{GPUStats, GpuProcessStats};
 
+use super::drm::DrmGpus;
+use super::sysfs::Sysfs;
 use super::{DataCollector, DataCollectorError};
 
+/// The calls made to a GPU vendor's library or the kernel, implemented by NVML,
+/// the DRM drivers' sysfs files and by fakes in the tests
+pub trait GpuBackend: Debug + Send {
+  fn device_count(&mut self) -> Result<u32>;
+  /// The stats of the device at `index` from 0 to `device_count`, the fetcher
+  /// offsets the index so it's unique across the backends
+  fn device_stats(&mut self, index: u32) -> Result<GPUStats>;
+}
+
+impl GpuBackend for NVML {
+  fn device_count(&mut self) -> Result<u32> {
+    Ok(NVML::device_count(self)?)
+  }
+
+  fn device_stats(&mut self, index: u32) -> Result<GPUStats> {
+    let device = self.device_by_index(index)?;
+    let util = supported(device.utilization_rates())?;
+    let memory = device.memory_info().ok();
+
+    // Compute and graphics contexts of the same process are listed in both
+    let mut processes: Vec<GpuProcessStats> = Vec::new();
+    let running = device
+      .running_compute_processes()
+      .into_iter()
+      .chain(device.running_graphics_processes())
+      .flatten();
+    for process in running {
+      if processes.iter().all(|known| known.pid != process.pid) {
+        processes.push(GpuProcessStats {
+          pid: process.pid,
+          name: None,
+          memory_used: match process.used_gpu_memory {
+            UsedGpuMemory::Used(bytes) => Some(bytes),
+            UsedGpuMemory::Unavailable => None,
+          },
+        });
+      }
+    }
+
+    Ok(GPUStats {
+      index,
+      uuid: supported(device.uuid())?,
+      name: supported(device.name())?.unwrap_or_default(),
+      pci_bus_id: supported(device.pci_info())?
+        .map(|pci_info| pci_info.bus_id)
+        .unwrap_or_default(),
+      brand: supported(device.brand())?
+        .map(|brand| format!("{:?}", brand))
+        .unwrap_or_default(),
+      gpu_usage: util.as_ref().map(|util| util.gpu),
+      power_usage: supported(device.power_usage())?,
+      memory_used: memory.as_ref().map(|memory| memory.used),
+      memory_total: memory.as_ref().map(|memory| memory.total),
+      memory_usage: util.as_ref().map(|util| util.memory),
+      temperature: device.temperature(TemperatureSensor::Gpu).ok(),
+      sm_clock: device.clock_info(Clock::SM).ok(),
+      memory_clock: device.clock_info(Clock::Memory).ok(),
+      fan_speed: device.fan_speed(0).ok(),
+      power_limit: device.enforced_power_limit().ok(),
+      throttle_reasons: device
+        .current_throttle_reasons()
+        .ok()
+        .map(throttle_reason_names),
+      ecc_errors_corrected: device
+        .total_ecc_errors(MemoryError::Corrected, EccCounter::Volatile)
+        .ok(),
+      ecc_errors_uncorrected: device
+        .total_ecc_errors(MemoryError::Uncorrected, EccCounter::Volatile)
+        .ok(),
+      processes: Some(processes),
+    })
+  }
+}
+
+/// `None` for what the card doesn't support, like the power draw of most consumer cards,
+/// any other error means the device can't be read at all
+fn supported<T>(result: Result<T, NvmlError>) -> Result<Option<T>> {
+  match result {
+    Ok(value) => Ok(Some(value)),
+    Err(NvmlError::NotSupported) => Ok(None),
+    Err(e) => Err(e.into()),
+  }
+}
+
+/// The names of the reasons the clocks are held down for, empty when they run freely
+fn throttle_reason_names(reasons: ThrottleReasons) -> Vec<String> {
+  [
+    (ThrottleReasons::GPU_IDLE, "gpu_idle"),
+    (
+      ThrottleReasons::APPLICATIONS_CLOCKS_SETTING,
+      "applications_clocks_setting",
+    ),
+    (ThrottleReasons::SW_POWER_CAP, "sw_power_cap"),
+    (ThrottleReasons::HW_SLOWDOWN, "hw_slowdown"),
+    (ThrottleReasons::SYNC_BOOST, "sync_boost"),
+    (ThrottleReasons::SW_THERMAL_SLOWDOWN, "sw_thermal_slowdown"),
+    (ThrottleReasons::HW_THERMAL_SLOWDOWN, "hw_thermal_slowdown"),
+    (
+      ThrottleReasons::HW_POWER_BRAKE_SLOWDOWN,
+      "hw_power_brake_slowdown",
+    ),
+    (
+      ThrottleReasons::DISPLAY_CLOCK_SETTING,
+      "display_clock_setting",
+    ),
+  ]
+  .iter()
+  .filter(|(reason, _)| reasons.contains(*reason))
+  .map(|(_, name)| name.to_string())
+  .collect()
+}
+
 #[derive(Debug)]
 pub struct GPUFetcher {
-  pub nvidia: Option<NVML>,
+  pub backends: Vec<Box<dyn GpuBackend>>,
+  /// Which backends failed to count their devices the last time, so the failure is logged
+  /// once instead of on every collection
+  failing: Vec<bool>,
 }
 
-impl DataCollector {
-  /// Get the current GPU states
-  pub fn get_gpu(&mut self) -> Result<GPUStats> {
-    let gpu_fetcher = &self.gpu_fetcher;
-    match gpu_fetcher.nvidia.as_ref() {
-      Some(nvml) => {
-        let device = nvml.device_by_index(0)?;
-        let (brand, util) = (
-          format!("{:?}", device.brand()?),
-          device.utilization_rates()?,
-        );
-
-        Ok(GPUStats {
-          brand,
-          gpu_usage: util.gpu,
-          power_usage: device.power_usage()?,
-        })
+impl GPUFetcher {
+  pub fn new(backends: Vec<Box<dyn GpuBackend>>) -> Self {
+    Self {
+      failing: vec![false; backends.len()],
+      backends,
+    }
+  }
+
+  /// Loads NVML when it's installed and finds the AMD and Intel GPUs in sysfs
+  pub fn init() -> Self {
+    let mut backends: Vec<Box<dyn GpuBackend>> = Vec::new();
+    if let Ok(nvml) = NVML::init() {
+      backends.push(Box::new(nvml));
+    }
+    if let Some(drm) = DrmGpus::discover(Sysfs::default()) {
+      backends.push(Box::new(drm));
+    }
+    Self::new(backends)
+  }
+
+  /// The stats of every GPU, a device or backend that fails to answer like one that fell
+  /// off the bus is left out instead of hiding the others
+  pub fn get_gpus(&mut self) -> Result<Vec<GPUStats>> {
+    if self.backends.is_empty() {
+      return Err(DataCollectorError::NoGPU.into());
+    }
+    let mut gpus = Vec::new();
+    let mut offset = 0;
+    for (backend, failing) in self.backends.iter_mut().zip(self.failing.iter_mut()) {
+      let device_count = match backend.device_count() {
+        Ok(device_count) => device_count,
+        Err(e) => {
+          if !*failing {
+            eprintln!("Could not count the GPUs: {}", e);
+          }
+          *failing = true;
+          continue;
+        }
+      };
+      *failing = false;
+      for index in 0..device_count {
+        if let Ok(gpu) = backend.device_stats(index) {
+          gpus.push(GPUStats {
+            index: offset + index,
+            ..gpu
+          });
+        }
       }
-      None => Err(DataCollectorError::NoGPU.into())
+      offset += device_count;
     }
+    Ok(gpus)
+  }
+}
+
+impl DataCollector {
+  /// Get the current GPU states, the processes using them are named from the process list
+  pub fn get_gpus(&mut self) -> Result<Vec<GPUStats>> {
+    let mut gpus = self.gpu_fetcher.get_gpus()?;
+    for process in gpus
+      .iter_mut()
+      .flat_map(|gpu| gpu.processes.iter_mut().flatten())
+    {
+      process.name = self
+        .fetcher
+        .process(process.pid as Pid)
+        .map(|known| known.name().to_string());
+    }
+    Ok(gpus)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anyhow::anyhow;
+
+  /// Answers with the stats of every card in order, `None` for one that fails
+  #[derive(Debug)]
+  struct FakeNvml(Vec<Option<&'static str>>);
+
+  /// A backend whose driver stopped answering altogether
+  #[derive(Debug)]
+  struct BrokenBackend;
+
+  impl GpuBackend for BrokenBackend {
+    fn device_count(&mut self) -> Result<u32> {
+      Err(anyhow!("driver not loaded"))
+    }
+
+    fn device_stats(&mut self, _index: u32) -> Result<GPUStats> {
+      unreachable!("there are no devices to ask")
+    }
+  }
+
+  impl GpuBackend for FakeNvml {
+    fn device_count(&mut self) -> Result<u32> {
+      Ok(self.0.len() as u32)
+    }
+
+    fn device_stats(&mut self, index: u32) -> Result<GPUStats> {
+      let name = self.0[index as usize].ok_or_else(|| anyhow!("GPU is lost"))?;
+      Ok(GPUStats {
+        index,
+        uuid: Some(format!("GPU-{}", index)),
+        name: name.to_string(),
+        pci_bus_id: format!("00000000:0{}:00.0", index + 1),
+        brand: "GeForce".to_string(),
+        gpu_usage: Some(50),
+        power_usage: Some(100_000),
+        ..GPUStats::default()
+      })
+    }
+  }
+
+  fn fetcher(cards: Vec<Option<&'static str>>) -> GPUFetcher {
+    GPUFetcher::new(vec![Box::new(FakeNvml(cards))])
+  }
+
+  #[test]
+  fn reports_every_gpu() {
+    let gpus = fetcher(vec![Some("RTX 3090"), Some("RTX 3080")])
+      .get_gpus()
+      .unwrap();
+    assert_eq!(gpus.len(), 2);
+    assert_eq!((gpus[0].index, gpus[0].name.as_str()), (0, "RTX 3090"));
+    assert_eq!((gpus[1].index, gpus[1].name.as_str()), (1, "RTX 3080"));
+    assert_eq!(gpus[1].pci_bus_id, "00000000:02:00.0");
+  }
+
+  #[test]
+  fn a_failing_gpu_doesnt_hide_the_others() {
+    let gpus = fetcher(vec![None, Some("A100")]).get_gpus().unwrap();
+    assert_eq!(gpus.len(), 1);
+    assert_eq!(gpus[0].index, 1);
+  }
+
+  #[test]
+  fn a_failing_backend_doesnt_hide_the_others() {
+    let mut fetcher = GPUFetcher::new(vec![
+      Box::new(BrokenBackend),
+      Box::new(FakeNvml(vec![Some("A100")])),
+    ]);
+    for _ in 0..2 {
+      let gpus = fetcher.get_gpus().unwrap();
+      assert_eq!(gpus.len(), 1);
+      assert_eq!(gpus[0].name, "A100");
+    }
+    // Remembered so it's only logged the first time
+    assert_eq!(fetcher.failing, vec![true, false]);
+  }
+
+  #[test]
+  fn indexes_are_unique_across_backends() {
+    let mut fetcher = GPUFetcher::new(vec![
+      Box::new(FakeNvml(vec![Some("RTX 3090"), None])),
+      Box::new(FakeNvml(vec![Some("Arc A770")])),
+    ]);
+    let gpus = fetcher.get_gpus().unwrap();
+    let indexes: Vec<(u32, &str)> = gpus
+      .iter()
+      .map(|gpu| (gpu.index, gpu.name.as_str()))
+      .collect();
+    assert_eq!(indexes, vec![(0, "RTX 3090"), (2, "Arc A770")]);
+  }
+
+  #[test]
+  fn no_library_is_an_error() {
+    assert!(GPUFetcher::new(vec![]).get_gpus().is_err());
+  }
+
+  #[test]
+  fn unsupported_values_are_none() {
+    assert_eq!(supported(Ok(5)).unwrap(), Some(5));
+    assert_eq!(
+      supported::<u32>(Err(NvmlError::NotSupported)).unwrap(),
+      None
+    );
+    assert!(supported::<u32>(Err(NvmlError::GpuLost)).is_err());
+  }
+
+  #[test]
+  fn names_the_throttle_reasons() {
+    assert_eq!(
+      throttle_reason_names(ThrottleReasons::SW_POWER_CAP | ThrottleReasons::HW_THERMAL_SLOWDOWN),
+      vec!["sw_power_cap", "hw_thermal_slowdown"]
+    );
+    assert!(throttle_reason_names(ThrottleReasons::empty()).is_empty());
   }
 }
@@ -1,20 +1,39 @@
+mod cgroups;
+mod containers;
 mod cpu;
 mod disks;
+mod drm;
 mod geolocation;
 mod gpu;
+mod mounts;
 mod nics;
+mod processes;
 mod ram;
+mod sysfs;
+mod systemd;
 mod temps;
 mod uptimes;
 
+use crate::config_manager::{CollectionConfig, Config};
+use crate::filter::Filter;
 use crate::types::{DynamicData, StaticData};
+use crate::util::unix_millis;
 use anyhow::{anyhow, Result};
-use nvml::NVML;
-use std::{collections::HashMap, time::SystemTime};
-use sysinfo::{ProcessRefreshKind, ProcessorExt, System, SystemExt};
+use regex::Regex;
+use std::{
+  collections::HashMap,
+  path::PathBuf,
+  time::{Duration, Instant, SystemTime},
+};
+use sysinfo::{ProcessorExt, System, SystemExt};
 use thiserror::Error;
 
+use self::cgroups::CgroupSample;
+use self::containers::ContainerCollector;
+use self::disks::DiskCounters;
 use self::gpu::GPUFetcher;
+use self::nics::LinkSpeed;
+use self::sysfs::Sysfs;
 
 #[cfg(target_family = "windows")]
 use windows::Win32::System::Performance::*;
@@ -34,6 +53,9 @@ use windows::Win32::Foundation::ERROR_SUCCESS;
 #[cfg(target_family = "windows")]
 use std::process::exit;
 
+/// The shortest time between two CPU refreshes sysinfo can compute a meaningful usage over
+const MINIMUM_CPU_REFRESH_DELAY: Duration = Duration::from_millis(200);
+
 #[derive(Error, Debug)]
 pub enum DataCollectorError {
   #[error("GPU usage unavailable")]
@@ -48,8 +70,27 @@ pub struct DataCollector {
   pub gpu_fetcher: GPUFetcher,
   pub fetcher: System,
   pub program_iterations: usize,
+  pub collection: CollectionConfig,
+  disk_filter: Filter,
+  disk_fs_type_filter: Filter,
+  deduplicate_disks: bool,
+  network_filter: Filter,
+  exclude_virtual_nics: bool,
   iterator_index: usize,
-  network_interface_speeds: HashMap<String, f32>,
+  network_interface_speeds: HashMap<String, LinkSpeed>,
+  /// When the byte counters of every interface were last read and their values, for the rates
+  network_counters: HashMap<String, (Instant, u64, u64)>,
+  /// When the processes were last refreshed, their disk usage is since then
+  processes_refreshed_at: Option<Instant>,
+  process_redactions: Vec<Regex>,
+  /// Reads the containers off the async loop and keeps their counters between collections
+  containers: ContainerCollector,
+  /// The CPU time of the reporter's cgroup and the slices at the previous collection by directory
+  cgroup_samples: HashMap<PathBuf, CgroupSample>,
+  /// When the counters of every block device were last read and their values, for the rates
+  disk_counters: HashMap<String, (Instant, DiskCounters)>,
+  sysfs: Sysfs,
+  procfs: Sysfs,
   start_timestamp: u128,
 }
 
@@ -73,8 +114,27 @@ pub struct DataCollector {
   pub pdh_proc_util_data_len: usize,
   pub pdh_proc_util_data_capacity: usize,
   pub first_pdh_called: bool,
+  pub collection: CollectionConfig,
+  disk_filter: Filter,
+  disk_fs_type_filter: Filter,
+  deduplicate_disks: bool,
+  network_filter: Filter,
+  exclude_virtual_nics: bool,
   iterator_index: usize,
-  network_interface_speeds: HashMap<String, f32>,
+  network_interface_speeds: HashMap<String, LinkSpeed>,
+  /// When the byte counters of every interface were last read and their values, for the rates
+  network_counters: HashMap<String, (Instant, u64, u64)>,
+  /// When the processes were last refreshed, their disk usage is since then
+  processes_refreshed_at: Option<Instant>,
+  process_redactions: Vec<Regex>,
+  /// Reads the containers off the async loop and keeps their counters between collections
+  containers: ContainerCollector,
+  /// The CPU time of the reporter's cgroup and the slices at the previous collection by directory
+  cgroup_samples: HashMap<PathBuf, CgroupSample>,
+  /// When the counters of every block device were last read and their values, for the rates
+  disk_counters: HashMap<String, (Instant, DiskCounters)>,
+  sysfs: Sysfs,
+  procfs: Sysfs,
   start_timestamp: u128
 }
 
@@ -82,20 +142,35 @@ pub struct DataCollector {
 impl DataCollector {
   /// Creates a new data collector
   #[cfg(target_family = "unix")]
-  pub fn new() -> Result<Self> {
-    let (fetcher, gpu_fetcher) = (
-      System::new_all(),
-      GPUFetcher {
-        nvidia: NVML::init().ok(),
-      }
-    );
+  pub fn new(config: &Config) -> Result<Self> {
+    let (fetcher, gpu_fetcher) = (System::new_all(), GPUFetcher::init());
 
     Ok(Self {
       gpu_fetcher,
       fetcher,
       iterator_index: 0,
-      program_iterations: 60,
+      program_iterations: config.collection.refresh_iterations,
+      collection: config.collection.clone(),
+      disk_filter: config.filters.disks.patterns.compile()?,
+      disk_fs_type_filter: config.filters.disks.fs_types.compile()?,
+      deduplicate_disks: config.filters.disks.deduplicate,
+      network_filter: config.filters.network.patterns.compile()?,
+      exclude_virtual_nics: config.filters.network.exclude_virtual,
       network_interface_speeds: HashMap::new(),
+      network_counters: HashMap::new(),
+      processes_refreshed_at: None,
+      process_redactions: config
+        .collection
+        .top_processes
+        .redact
+        .iter()
+        .map(|pattern| Regex::new(pattern))
+        .collect::<Result<_, _>>()?,
+      containers: ContainerCollector::default(),
+      cgroup_samples: HashMap::new(),
+      disk_counters: HashMap::new(),
+      sysfs: Sysfs::default(),
+      procfs: Sysfs::new("/proc"),
       start_timestamp: SystemTime::now()
           .duration_since(SystemTime::UNIX_EPOCH)?
           .as_millis(),
@@ -104,13 +179,8 @@ impl DataCollector {
 
   /// Creates a new data collector but initializing Windows centric state.
   #[cfg(target_family = "windows")]
-  pub fn new() -> Result<Self> {
-    let (fetcher, gpu_fetcher) = (
-      System::new_all(),
-      GPUFetcher {
-        nvidia: NVML::init().ok(),
-      }
-    );
+  pub fn new(config: &Config) -> Result<Self> {
+    let (fetcher, gpu_fetcher) = (System::new_all(), GPUFetcher::init());
 
     let mut pdh_query = 0 as isize;
     let mut pdh_proc_perf_counter = 0 as isize;
@@ -199,14 +269,63 @@ impl DataCollector {
       pdh_proc_util_data_capacity: 0,
       first_pdh_called: false,
       iterator_index: 0,
-      program_iterations: 60,
+      program_iterations: config.collection.refresh_iterations,
+      collection: config.collection.clone(),
+      disk_filter: config.filters.disks.patterns.compile()?,
+      disk_fs_type_filter: config.filters.disks.fs_types.compile()?,
+      deduplicate_disks: config.filters.disks.deduplicate,
+      network_filter: config.filters.network.patterns.compile()?,
+      exclude_virtual_nics: config.filters.network.exclude_virtual,
       network_interface_speeds: HashMap::new(),
+      network_counters: HashMap::new(),
+      processes_refreshed_at: None,
+      process_redactions: config
+        .collection
+        .top_processes
+        .redact
+        .iter()
+        .map(|pattern| Regex::new(pattern))
+        .collect::<Result<_, _>>()?,
+      containers: ContainerCollector::default(),
+      cgroup_samples: HashMap::new(),
+      disk_counters: HashMap::new(),
+      sysfs: Sysfs::default(),
+      procfs: Sysfs::new("/proc"),
       start_timestamp: SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)?
         .as_millis(),
     })
   }
 
+  /// The CPU usage, network throughput, disk I/O and every other rate are computed from the
+  /// difference between two refreshes, this makes the next `get_all_dynamic_data` report them
+  /// over `delay` instead of over the few milliseconds since the collector was created
+  pub async fn prime(&mut self, delay: Duration) -> Result<()> {
+    self.fetcher.refresh_cpu();
+    // The first collection refreshes the list of interfaces and their link speeds
+    self.get_network()?;
+    self.get_disks()?;
+    let collectors = self.collection.collectors.clone();
+    if collectors.top_processes {
+      self.refresh_processes();
+      self.get_top_processes(&[]);
+    }
+    // The Intel GPUs report counters for their usage and power draw
+    if collectors.gpu {
+      let _ = self.get_gpus();
+    }
+    if collectors.containers {
+      self.get_containers().await;
+    }
+    if collectors.cgroup {
+      self.get_cgroup();
+    }
+    self.increment_iterator_index();
+    tokio::time::sleep(delay.max(MINIMUM_CPU_REFRESH_DELAY)).await;
+    self.fetcher.refresh_cpu();
+    Ok(())
+  }
+
   /// Increments the iterator index by one or resets it to 0 if it reaches the program iterations
   pub fn increment_iterator_index(&mut self) {
     self.iterator_index += 1;
@@ -215,7 +334,7 @@ impl DataCollector {
     }
   }
 
-  pub fn get_all_dynamic_data(&mut self) -> Result<DynamicData> {
+  pub async fn get_all_dynamic_data(&mut self) -> Result<DynamicData> {
     #[cfg(target_family = "windows")]
     unsafe {
       let ret = PdhCollectQueryData(self.pdh_query);
@@ -225,17 +344,64 @@ impl DataCollector {
       }
     }
 
+    // Disabled collectors report nothing instead of being left out so the shape of the data stays the same
+    let collectors = self.collection.collectors.clone();
+    if collectors.processes || collectors.top_processes {
+      self.refresh_processes();
+    }
+    let gpus = if collectors.gpu {
+      self.get_gpus().unwrap_or_default()
+    } else {
+      Vec::new()
+    };
     Ok(DynamicData {
       cpu: self.get_cpu()?,
       ram: self.get_ram()?,
       swap: self.get_swap()?,
-      gpu: self.get_gpu().ok(),
-      process_count: self.get_total_process_count()? as i32,
-      disks: self.get_disks()?,
-      temps: self.get_temps().ok(),
-      network: self.get_network()?,
+      process_count: if collectors.processes {
+        self.get_total_process_count()? as i32
+      } else {
+        0
+      },
+      top_processes: if collectors.top_processes {
+        self.get_top_processes(&gpus)
+      } else {
+        Vec::new()
+      },
+      gpus,
+      disks: if collectors.disks {
+        self.get_disks()?
+      } else {
+        Vec::new()
+      },
+      temps: if collectors.temps {
+        self.get_temps().ok()
+      } else {
+        None
+      },
+      network: if collectors.network {
+        self.get_network()?
+      } else {
+        Vec::new()
+      },
+      systemd: if collectors.systemd {
+        self.get_systemd().ok()
+      } else {
+        None
+      },
+      containers: if collectors.containers {
+        self.get_containers().await
+      } else {
+        Vec::new()
+      },
+      cgroup: if collectors.cgroup {
+        self.get_cgroup()
+      } else {
+        None
+      },
       host_uptime: self.get_uptime()?,
       reporter_uptime: self.get_reporter_uptime()?,
+      timestamp: unix_millis(),
     })
   }
 
@@ -250,17 +416,18 @@ impl DataCollector {
 
   /// Gets the total amount of processes running
   pub fn get_total_process_count(&mut self) -> Result<usize> {
-    self
-      .fetcher
-      .refresh_processes_specifics(ProcessRefreshKind::new());
-    return Ok(self.fetcher.processes().len());
+    Ok(self.fetcher.processes().len())
   }
 
   /// Gets all the static information about the system
   /// that can't change in runtime
   pub async fn get_statics(&self) -> Result<StaticData> {
     let processor_info = self.fetcher.global_processor_info();
-    let geolocation_stuff = DataCollector::get_geolocation_info().await;
+    let geolocation_stuff = if self.collection.collectors.geolocation {
+      DataCollector::get_geolocation_info(&self.collection.geolocation_url).await
+    } else {
+      Err(anyhow!("Geolocation is disabled"))
+    };
 
     if geolocation_stuff.is_err() {
       return Ok(StaticData {
@@ -282,7 +449,7 @@ impl DataCollector {
 
     let geolocation_stuff = geolocation_stuff?;
 
-    return Ok(StaticData {
+    Ok(StaticData {
       cpu_model: processor_info.brand().trim().to_string(),
       public_ip: Some(geolocation_stuff.ip),
       country: Some(geolocation_stuff.country_code),
@@ -296,6 +463,6 @@ impl DataCollector {
       cpu_threads: self.fetcher.processors().len(),
       total_mem: self.fetcher.total_memory(),
       reporter_version: env!("CARGO_PKG_VERSION").to_string(),
-    });
+    })
   }
 }
@@ -0,0 +1,163 @@
+use std::path::Path;
+
+use super::sysfs::Sysfs;
+
+/// A line of `/proc/self/mounts`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mount {
+  pub device: String,
+  pub mount_point: String,
+  pub fs_type: String,
+  pub options: Vec<String>,
+}
+
+impl Mount {
+  /// The mounts in the order they were mounted, empty outside of Linux
+  pub fn from_procfs(procfs: &Sysfs) -> Vec<Self> {
+    procfs
+      .read_string("self/mounts")
+      .unwrap_or_default()
+      .lines()
+      .filter_map(Mount::parse_line)
+      .collect()
+  }
+
+  /// `device mount_point fs_type options dump pass`, see fstab(5)
+  fn parse_line(line: &str) -> Option<Self> {
+    let mut fields = line.split_whitespace();
+    Some(Self {
+      device: unescape(fields.next()?),
+      mount_point: unescape(fields.next()?),
+      fs_type: fields.next()?.to_string(),
+      options: fields
+        .next()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::to_string)
+        .collect(),
+    })
+  }
+}
+
+/// The kernel writes spaces, tabs, newlines and backslashes in the paths as octal escapes like `\040`
+fn unescape(field: &str) -> String {
+  let mut bytes = Vec::with_capacity(field.len());
+  let mut rest = field.as_bytes();
+  while let Some((&byte, tail)) = rest.split_first() {
+    let octal = tail
+      .get(..3)
+      .and_then(|digits| std::str::from_utf8(digits).ok())
+      .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+    match octal {
+      Some(escaped) if byte == b'\\' => {
+        bytes.push(escaped);
+        rest = &tail[3..];
+      }
+      _ => {
+        bytes.push(byte);
+        rest = tail;
+      }
+    }
+  }
+  String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// The space of a mounted filesystem in bytes and its inodes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FsUsage {
+  pub total: u64,
+  pub available: u64,
+  /// `None` for filesystems like btrfs that allocate inodes as they go
+  pub inodes_total: Option<u64>,
+  pub inodes_free: Option<u64>,
+  /// Mounted read-only, also after the kernel remounted it like that because of errors
+  pub read_only: bool,
+}
+
+impl FsUsage {
+  /// Asks the filesystem mounted at `mount_point`, pseudo filesystems report a total of 0
+  #[cfg(target_family = "unix")]
+  pub fn from_statvfs(mount_point: &Path) -> Option<Self> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(mount_point.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+      return None;
+    }
+    let fragment_size = stat.f_frsize as u64;
+    let inodes_total = Some(stat.f_files as u64).filter(|inodes| *inodes > 0);
+    Some(Self {
+      total: stat.f_blocks as u64 * fragment_size,
+      // The blocks reserved for root aren't available to anyone else
+      available: stat.f_bavail as u64 * fragment_size,
+      inodes_total,
+      inodes_free: inodes_total.map(|_| stat.f_ffree as u64),
+      read_only: stat.f_flag & libc::ST_RDONLY != 0,
+    })
+  }
+
+  #[cfg(target_family = "windows")]
+  pub fn from_statvfs(_mount_point: &Path) -> Option<Self> {
+    None
+  }
+}
+
+/// The id of the filesystem a path is on, the same for every bind mount of it
+#[cfg(target_family = "unix")]
+pub fn device_id(path: &Path) -> Option<u64> {
+  use std::os::unix::fs::MetadataExt;
+  std::fs::metadata(path).ok().map(|metadata| metadata.dev())
+}
+
+#[cfg(target_family = "windows")]
+pub fn device_id(_path: &Path) -> Option<u64> {
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use tempfile::TempDir;
+
+  #[test]
+  fn parses_the_mount_table() {
+    let root = TempDir::new().unwrap();
+    fs::create_dir(root.path().join("self")).unwrap();
+    fs::write(
+      root.path().join("self/mounts"),
+      "/dev/sda2 / ext4 rw,relatime 0 0
+/dev/sdb1 /mnt/My\\040Files vfat ro,nosuid 0 0
+incomplete
+",
+    )
+    .unwrap();
+
+    assert_eq!(
+      Mount::from_procfs(&Sysfs::new(root.path())),
+      vec![
+        Mount {
+          device: "/dev/sda2".to_string(),
+          mount_point: "/".to_string(),
+          fs_type: "ext4".to_string(),
+          options: vec!["rw".to_string(), "relatime".to_string()],
+        },
+        Mount {
+          device: "/dev/sdb1".to_string(),
+          mount_point: "/mnt/My Files".to_string(),
+          fs_type: "vfat".to_string(),
+          options: vec!["ro".to_string(), "nosuid".to_string()],
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn unescapes_octal_sequences_only() {
+    assert_eq!(unescape("a\\011b\\134c"), "a\tb\\c");
+    assert_eq!(unescape("trailing\\04"), "trailing\\04");
+    assert_eq!(unescape("not\\999octal"), "not\\999octal");
+  }
+}
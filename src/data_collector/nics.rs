@@ -1,12 +1,165 @@
-use crate::types::NetworkInterfaceStats;
+use crate::types::{NetworkInterfaceStats, NetworkInterfaceType};
 use crate::util::parse_speed;
 use anyhow::Result;
+use if_addrs::IfAddr;
 use serde::{Deserialize, Serialize};
 use std::process::Command;
 use std::str::FromStr;
 
+use std::collections::HashMap;
 use std::env;
-use sysinfo::{NetworkExt, SystemExt};
+use std::time::Instant;
+use sysinfo::{NetworkData, NetworkExt, SystemExt};
+
+/// The cumulative counters of an interface
+#[derive(Debug, Default)]
+struct NicCounters {
+  rx_bytes: u64,
+  tx_bytes: u64,
+  rx_packets: u64,
+  tx_packets: u64,
+  rx_errors: u64,
+  tx_errors: u64,
+  rx_dropped: u64,
+  tx_dropped: u64,
+}
+
+impl NicCounters {
+  /// Reads `/sys/class/net/<interface>/statistics`, only there on Linux
+  fn from_sysfs(sysfs: &Sysfs, interface_name: &str) -> Option<Self> {
+    let statistics = sysfs.net_path(interface_name).join("statistics");
+    let read = |name: &str| sysfs.read_u64(statistics.join(name));
+    Some(Self {
+      rx_bytes: read("rx_bytes")?,
+      tx_bytes: read("tx_bytes")?,
+      rx_packets: read("rx_packets").unwrap_or_default(),
+      tx_packets: read("tx_packets").unwrap_or_default(),
+      rx_errors: read("rx_errors").unwrap_or_default(),
+      tx_errors: read("tx_errors").unwrap_or_default(),
+      rx_dropped: read("rx_dropped").unwrap_or_default(),
+      tx_dropped: read("tx_dropped").unwrap_or_default(),
+    })
+  }
+
+  fn from_sysinfo(data: &NetworkData) -> Self {
+    Self {
+      rx_bytes: data.total_received(),
+      tx_bytes: data.total_transmitted(),
+      rx_packets: data.total_packets_received(),
+      tx_packets: data.total_packets_transmitted(),
+      rx_errors: data.total_errors_on_received(),
+      tx_errors: data.total_errors_on_transmitted(),
+      ..Self::default()
+    }
+  }
+}
+
+/// What the kernel tells about an interface apart from its counters, empty outside of Linux
+#[derive(Debug, Default)]
+struct NicMetadata {
+  operstate: Option<String>,
+  mac: Option<String>,
+  mtu: Option<u32>,
+  alias: Option<String>,
+  duplex: Option<String>,
+  r#type: NetworkInterfaceType,
+}
+
+impl NicMetadata {
+  fn from_sysfs(sysfs: &Sysfs, interface_name: &str) -> Self {
+    let path = sysfs.net_path(interface_name);
+    let read = |name: &str| {
+      sysfs
+        .read_string(path.join(name))
+        .filter(|value| !value.is_empty())
+    };
+    Self {
+      operstate: read("operstate"),
+      // Interfaces without a hardware address like tun report an empty one
+      mac: read("address").filter(|mac| mac != "00:00:00:00:00:00"),
+      mtu: read("mtu").and_then(|mtu| mtu.parse().ok()),
+      alias: read("ifalias"),
+      // Reading the duplex of a link that's down fails with EINVAL
+      duplex: read("duplex").filter(|duplex| duplex != "unknown"),
+      r#type: NicMetadata::get_type(sysfs, interface_name),
+    }
+  }
+
+  /// Whether there's no device behind the interface, false when there's no sysfs to tell
+  fn is_virtual(sysfs: &Sysfs, interface_name: &str) -> bool {
+    let path = sysfs.net_path(interface_name);
+    path.exists() && !path.join("device").exists()
+  }
+
+  fn get_type(sysfs: &Sysfs, interface_name: &str) -> NetworkInterfaceType {
+    let path = sysfs.net_path(interface_name);
+    if !path.exists() {
+      return NetworkInterfaceType::Unknown;
+    }
+
+    // ARPHRD_LOOPBACK from include/uapi/linux/if_arp.h
+    if sysfs.read_u64(path.join("type")) == Some(772) {
+      return NetworkInterfaceType::Loopback;
+    }
+
+    let devtype = sysfs.read_string(path.join("uevent")).and_then(|uevent| {
+      uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("DEVTYPE=").map(str::to_string))
+    });
+    match devtype.as_deref() {
+      Some("bridge") => return NetworkInterfaceType::Bridge,
+      Some("vlan") => return NetworkInterfaceType::Vlan,
+      Some("bond") => return NetworkInterfaceType::Bond,
+      Some("wireguard") => return NetworkInterfaceType::Wireguard,
+      _ => {}
+    }
+
+    // IFF_TAP from include/uapi/linux/if_tun.h
+    if let Some(tun_flags) = sysfs.read_string(path.join("tun_flags")) {
+      let tun_flags = u64::from_str_radix(tun_flags.trim_start_matches("0x"), 16).unwrap_or(0);
+      return if tun_flags & 0x0002 != 0 {
+        NetworkInterfaceType::Tap
+      } else {
+        NetworkInterfaceType::Tun
+      };
+    }
+
+    if path.join("bridge").exists() {
+      NetworkInterfaceType::Bridge
+    } else if path.join("bonding").exists() {
+      NetworkInterfaceType::Bond
+    } else if path.join("device").exists() {
+      NetworkInterfaceType::Physical
+    } else {
+      NetworkInterfaceType::Virtual
+    }
+  }
+}
+
+/// The link speed of an interface as last read and the operstate it was read in,
+/// a different operstate means the link was renegotiated and the speed has to be read again
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkSpeed {
+  operstate: Option<String>,
+  mbps: Option<f32>,
+}
+
+impl LinkSpeed {
+  /// Reads `/sys/class/net/<interface>/speed`, the kernel reports -1 when the speed is unknown
+  /// and fails the read with EINVAL when the link is down
+  fn from_sysfs(sysfs: &Sysfs, interface_name: &str, operstate: Option<&str>) -> Self {
+    let mbps = sysfs
+      .read_string(sysfs.net_path(interface_name).join("speed"))
+      .and_then(|speed| speed.parse::<i64>().ok())
+      .filter(|speed| *speed >= 0)
+      .map(|speed| speed as f32);
+    Self {
+      operstate: operstate.map(str::to_string),
+      mbps,
+    }
+  }
+}
 
 #[allow(non_snake_case)] // https://github.com/xornet-cloud/Reporter/pull/24#pullrequestreview-927780254
 #[derive(Serialize, Deserialize, Debug)]
@@ -15,11 +168,11 @@ pub struct WindowsNetworkInterface {
   pub LinkSpeed: String,
 }
 
+use super::sysfs::Sysfs;
 use super::DataCollector;
 
 impl DataCollector {
   /// Gets the current network stats
-
   pub fn get_network(&mut self) -> Result<Vec<NetworkInterfaceStats>> {
     let mut nics = Vec::new();
 
@@ -29,67 +182,166 @@ impl DataCollector {
       self.fetcher.refresh_networks();
     }
 
-    let nicspeeds = if self.iterator_index == 0 && env::consts::OS == "windows" {
+    let nicspeeds: Vec<(String, f32)> = if self.iterator_index == 0 && env::consts::OS == "windows"
+    {
       DataCollector::get_nic_linkspeeds()?
+        .into_iter()
+        .filter(|(name, _)| self.is_nic_reported(name))
+        .collect()
     } else {
       vec![]
     };
 
+    let mut addresses = DataCollector::get_nic_addresses();
+
     for (interface_name, data) in self.fetcher.networks() {
-      // Ignore bullshit loopback interfaces, no one cares
-      if interface_name.contains("NPCAP")
-        || interface_name.starts_with("lo")
-        || interface_name.starts_with("loopback")
-      {
+      // Loopback interfaces are excluded by the default filter, no one cares
+      if !self.is_nic_reported(interface_name) {
         continue;
       };
 
-      if self.iterator_index == 0 {
-        // Get the speed of the interface on linux otherwise it's 0
-        let speed = match env::consts::OS {
-          "linux" => DataCollector::get_nic_linkspeed(interface_name)?,
-          "windows" => {
-            let nic_index = nicspeeds
-              .iter()
-              .position(|(name, _)| name == interface_name);
-
-            if let Some(nic_index) = nic_index {
-              nicspeeds[nic_index].1
-            } else {
-              0.0
-            }
-          }
-          _ => 0.0,
-        };
+      let counters = NicCounters::from_sysfs(&self.sysfs, interface_name)
+        .unwrap_or_else(|| NicCounters::from_sysinfo(data));
+      let (rx, tx) = DataCollector::get_nic_rates(
+        &mut self.network_counters,
+        interface_name,
+        &counters,
+        Instant::now(),
+      );
+      let metadata = NicMetadata::from_sysfs(&self.sysfs, interface_name);
+
+      let speed = if env::consts::OS == "windows" {
+        if self.iterator_index == 0 {
+          let mbps = nicspeeds
+            .iter()
+            .find(|(name, _)| name == interface_name)
+            .map(|(_, speed)| *speed);
+          self.network_interface_speeds.insert(
+            interface_name.to_string(),
+            LinkSpeed {
+              operstate: None,
+              mbps,
+            },
+          );
+        }
         self
           .network_interface_speeds
-          .insert(interface_name.to_string(), speed);
-      }
+          .get(interface_name)
+          .and_then(|speed| speed.mbps)
+      } else {
+        DataCollector::get_nic_linkspeed(
+          &mut self.network_interface_speeds,
+          &self.sysfs,
+          interface_name,
+          metadata.operstate.as_deref(),
+          self.iterator_index == 0,
+        )
+      };
 
       let nic = NetworkInterfaceStats {
         n: interface_name.to_string(),
-        tx: data.transmitted() * 8,
-        rx: data.received() * 8,
-        s: self
-          .network_interface_speeds
-          .get(&interface_name.to_string())
-          .unwrap_or(&0.0)
-          .to_owned(),
+        tx,
+        rx,
+        s: speed,
+        rx_bytes: counters.rx_bytes,
+        tx_bytes: counters.tx_bytes,
+        rx_packets: counters.rx_packets,
+        tx_packets: counters.tx_packets,
+        rx_errors: counters.rx_errors,
+        tx_errors: counters.tx_errors,
+        rx_dropped: counters.rx_dropped,
+        tx_dropped: counters.tx_dropped,
+        operstate: metadata.operstate,
+        mac: metadata.mac,
+        addresses: addresses.remove(interface_name).unwrap_or_default(),
+        mtu: metadata.mtu,
+        alias: metadata.alias,
+        duplex: metadata.duplex,
+        r#type: metadata.r#type,
       };
 
       nics.push(nic);
     }
 
+    // Forget the interfaces that went away so one coming back doesn't get a rate over the whole gap
+    let names: Vec<&String> = nics.iter().map(|nic| &nic.n).collect();
+    self
+      .network_counters
+      .retain(|name, _| names.contains(&name));
+    self
+      .network_interface_speeds
+      .retain(|name, _| names.contains(&name));
+
     Ok(nics)
   }
 
-  fn get_nic_linkspeed(interface_name: &str) -> Result<f32> {
-    let interface_path = format!("/sys/class/net/{}/speed", interface_name);
-    let interface_speed = Command::new("cat").arg(&interface_path).output()?;
-    let interface_speed =
-      f32::from_str(&String::from_utf8_lossy(&interface_speed.stdout).replace("\n", ""))
-        .unwrap_or(0.0);
-    Ok(interface_speed)
+  /// Whether an interface passes the network filter, checked before anything is read about it
+  fn is_nic_reported(&self, interface_name: &str) -> bool {
+    self.network_filter.allows(&[interface_name])
+      && !(self.exclude_virtual_nics && NicMetadata::is_virtual(&self.sysfs, interface_name))
+  }
+
+  /// The addresses of every interface in CIDR notation
+  fn get_nic_addresses() -> HashMap<String, Vec<String>> {
+    let mut addresses: HashMap<String, Vec<String>> = HashMap::new();
+    for interface in if_addrs::get_if_addrs().unwrap_or_default() {
+      let address = match interface.addr {
+        IfAddr::V4(v4) => format!("{}/{}", v4.ip, u32::from(v4.netmask).count_ones()),
+        IfAddr::V6(v6) => format!("{}/{}", v6.ip, u128::from(v6.netmask).count_ones()),
+      };
+      addresses.entry(interface.name).or_default().push(address);
+    }
+    addresses
+  }
+
+  /// Received and transmitted bits per second since the counters were last read at `now`,
+  /// 0 on the first read and when the counters went backwards after a reset or wrapping around
+  fn get_nic_rates(
+    network_counters: &mut HashMap<String, (Instant, u64, u64)>,
+    interface_name: &str,
+    counters: &NicCounters,
+    now: Instant,
+  ) -> (u64, u64) {
+    let previous = network_counters.insert(
+      interface_name.to_string(),
+      (now, counters.rx_bytes, counters.tx_bytes),
+    );
+
+    match previous {
+      Some((then, rx_bytes, tx_bytes)) => {
+        let elapsed = now.saturating_duration_since(then).as_secs_f64();
+        if elapsed <= 0.0 {
+          return (0, 0);
+        }
+        let rate = |current: u64, previous: u64| {
+          (current.saturating_sub(previous) as f64 * 8.0 / elapsed).round() as u64
+        };
+        (
+          rate(counters.rx_bytes, rx_bytes),
+          rate(counters.tx_bytes, tx_bytes),
+        )
+      }
+      None => (0, 0),
+    }
+  }
+
+  /// The link speed in Mb/s, only read again every refresh or when the operstate changed
+  fn get_nic_linkspeed(
+    network_interface_speeds: &mut HashMap<String, LinkSpeed>,
+    sysfs: &Sysfs,
+    interface_name: &str,
+    operstate: Option<&str>,
+    refresh: bool,
+  ) -> Option<f32> {
+    match network_interface_speeds.get(interface_name) {
+      Some(speed) if !refresh && speed.operstate.as_deref() == operstate => speed.mbps,
+      _ => {
+        let speed = LinkSpeed::from_sysfs(sysfs, interface_name, operstate);
+        let mbps = speed.mbps;
+        network_interface_speeds.insert(interface_name.to_string(), speed);
+        mbps
+      }
+    }
   }
 
   fn get_nic_linkspeeds() -> Result<Vec<(String, f32)>> {
@@ -122,3 +374,240 @@ impl DataCollector {
     Ok(nics)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::time::Duration;
+  use tempfile::TempDir;
+
+  /// A sysfs tree with a single `eth0` interface
+  fn fake_sysfs(speed: Option<&str>, operstate: &str) -> (TempDir, Sysfs) {
+    let root = TempDir::new().unwrap();
+    let sysfs = Sysfs::new(root.path());
+    fs::create_dir_all(sysfs.net_path("eth0")).unwrap();
+    write(&sysfs, "operstate", operstate);
+    if let Some(speed) = speed {
+      write(&sysfs, "speed", speed);
+    }
+    (root, sysfs)
+  }
+
+  fn write(sysfs: &Sysfs, name: &str, value: &str) {
+    fs::write(sysfs.net_path("eth0").join(name), format!("{}\n", value)).unwrap();
+  }
+
+  fn linkspeed(
+    speeds: &mut HashMap<String, LinkSpeed>,
+    sysfs: &Sysfs,
+    refresh: bool,
+  ) -> Option<f32> {
+    let operstate = sysfs.read_string(sysfs.net_path("eth0").join("operstate"));
+    DataCollector::get_nic_linkspeed(speeds, sysfs, "eth0", operstate.as_deref(), refresh)
+  }
+
+  #[test]
+  fn reads_the_link_speed() {
+    let (_root, sysfs) = fake_sysfs(Some("1000"), "up");
+    assert_eq!(linkspeed(&mut HashMap::new(), &sysfs, true), Some(1000.0));
+  }
+
+  #[test]
+  fn zero_is_a_known_speed() {
+    let (_root, sysfs) = fake_sysfs(Some("0"), "up");
+    assert_eq!(linkspeed(&mut HashMap::new(), &sysfs, true), Some(0.0));
+  }
+
+  #[test]
+  fn unknown_speeds_are_none() {
+    for speed in [Some("-1"), Some(""), Some("garbage"), None] {
+      let (_root, sysfs) = fake_sysfs(speed, "down");
+      assert_eq!(
+        linkspeed(&mut HashMap::new(), &sysfs, true),
+        None,
+        "{:?}",
+        speed
+      );
+    }
+  }
+
+  #[test]
+  fn the_speed_is_cached_until_the_next_refresh() {
+    let (_root, sysfs) = fake_sysfs(Some("1000"), "up");
+    let mut speeds = HashMap::new();
+    assert_eq!(linkspeed(&mut speeds, &sysfs, true), Some(1000.0));
+
+    write(&sysfs, "speed", "100");
+    assert_eq!(linkspeed(&mut speeds, &sysfs, false), Some(1000.0));
+    assert_eq!(linkspeed(&mut speeds, &sysfs, true), Some(100.0));
+  }
+
+  #[test]
+  fn an_operstate_change_reads_the_speed_again() {
+    let (_root, sysfs) = fake_sysfs(Some("1000"), "up");
+    let mut speeds = HashMap::new();
+    assert_eq!(linkspeed(&mut speeds, &sysfs, true), Some(1000.0));
+
+    // The link goes down and comes back renegotiated at a lower speed
+    write(&sysfs, "operstate", "down");
+    write(&sysfs, "speed", "-1");
+    assert_eq!(linkspeed(&mut speeds, &sysfs, false), None);
+
+    write(&sysfs, "operstate", "up");
+    write(&sysfs, "speed", "100");
+    assert_eq!(linkspeed(&mut speeds, &sysfs, false), Some(100.0));
+  }
+
+  fn counters(rx_bytes: u64, tx_bytes: u64) -> NicCounters {
+    NicCounters {
+      rx_bytes,
+      tx_bytes,
+      ..NicCounters::default()
+    }
+  }
+
+  #[test]
+  fn the_first_sample_has_no_rate() {
+    let mut previous = HashMap::new();
+    let rates =
+      DataCollector::get_nic_rates(&mut previous, "eth0", &counters(5000, 7000), Instant::now());
+    assert_eq!(rates, (0, 0));
+    assert!(previous.contains_key("eth0"));
+  }
+
+  #[test]
+  fn rates_are_in_bits_per_second() {
+    let mut previous = HashMap::new();
+    let then = Instant::now();
+    DataCollector::get_nic_rates(&mut previous, "eth0", &counters(1000, 2000), then);
+    // 1000 bytes received and 250 sent over 2 seconds
+    let rates = DataCollector::get_nic_rates(
+      &mut previous,
+      "eth0",
+      &counters(2000, 2250),
+      then + Duration::from_secs(2),
+    );
+    assert_eq!(rates, (4000, 1000));
+  }
+
+  #[test]
+  fn counters_going_backwards_are_no_traffic() {
+    let mut previous = HashMap::new();
+    let then = Instant::now();
+    // A 32 bit counter wrapping around or the interface being recreated
+    DataCollector::get_nic_rates(&mut previous, "eth0", &counters(u32::MAX as u64, 500), then);
+    let rates = DataCollector::get_nic_rates(
+      &mut previous,
+      "eth0",
+      &counters(100, 900),
+      then + Duration::from_secs(1),
+    );
+    assert_eq!(rates, (0, 3200));
+
+    // The rate picks up from the new counters
+    let rates = DataCollector::get_nic_rates(
+      &mut previous,
+      "eth0",
+      &counters(1100, 900),
+      then + Duration::from_secs(2),
+    );
+    assert_eq!(rates, (8000, 0));
+  }
+
+  #[test]
+  fn no_time_passing_is_no_rate() {
+    let mut previous = HashMap::new();
+    let now = Instant::now();
+    DataCollector::get_nic_rates(&mut previous, "eth0", &counters(0, 0), now);
+    let rates = DataCollector::get_nic_rates(&mut previous, "eth0", &counters(1000, 1000), now);
+    assert_eq!(rates, (0, 0));
+  }
+
+  #[test]
+  fn reads_the_counters_from_sysfs() {
+    let (_root, sysfs) = fake_sysfs(None, "up");
+    let statistics = sysfs.net_path("eth0").join("statistics");
+    fs::create_dir_all(&statistics).unwrap();
+    assert!(NicCounters::from_sysfs(&sysfs, "eth0").is_none());
+
+    for (name, value) in [
+      ("rx_bytes", "123"),
+      ("tx_bytes", "456"),
+      ("rx_dropped", "7"),
+    ] {
+      fs::write(statistics.join(name), format!("{}\n", value)).unwrap();
+    }
+    let counters = NicCounters::from_sysfs(&sysfs, "eth0").unwrap();
+    assert_eq!(counters.rx_bytes, 123);
+    assert_eq!(counters.tx_bytes, 456);
+    assert_eq!(counters.rx_dropped, 7);
+    assert_eq!(counters.tx_packets, 0);
+  }
+
+  /// An `if0` interface with the given files and directories in its sysfs directory
+  fn interface(files: &[(&str, &str)], dirs: &[&str]) -> (TempDir, Sysfs) {
+    let root = TempDir::new().unwrap();
+    let sysfs = Sysfs::new(root.path());
+    let path = sysfs.net_path("if0");
+    fs::create_dir_all(&path).unwrap();
+    for (name, content) in files {
+      fs::write(path.join(name), format!("{}\n", content)).unwrap();
+    }
+    for dir in dirs {
+      fs::create_dir_all(path.join(dir)).unwrap();
+    }
+    (root, sysfs)
+  }
+
+  fn interface_type(files: &[(&str, &str)], dirs: &[&str]) -> NetworkInterfaceType {
+    let (_root, sysfs) = interface(files, dirs);
+    NicMetadata::get_type(&sysfs, "if0")
+  }
+
+  #[test]
+  fn classifies_the_interfaces() {
+    use NetworkInterfaceType::*;
+
+    assert_eq!(interface_type(&[("type", "772")], &[]), Loopback);
+    for (devtype, expected) in [
+      ("bridge", Bridge),
+      ("vlan", Vlan),
+      ("bond", Bond),
+      ("wireguard", Wireguard),
+    ] {
+      let uevent = format!("DEVTYPE={}\nINTERFACE=if0\nIFINDEX=3", devtype);
+      assert_eq!(
+        interface_type(&[("type", "1"), ("uevent", &uevent)], &[]),
+        expected,
+        "{}",
+        devtype
+      );
+    }
+    // IFF_TUN and IFF_TAP, both with IFF_NO_PI
+    assert_eq!(interface_type(&[("tun_flags", "0x1001")], &[]), Tun);
+    assert_eq!(interface_type(&[("tun_flags", "0x1002")], &[]), Tap);
+    // Older kernels don't put a DEVTYPE in the uevent of bridges and bonds
+    assert_eq!(
+      interface_type(&[("uevent", "INTERFACE=if0")], &["bridge"]),
+      Bridge
+    );
+    assert_eq!(interface_type(&[], &["bonding"]), Bond);
+    assert_eq!(interface_type(&[("type", "1")], &["device"]), Physical);
+    assert_eq!(interface_type(&[("type", "1")], &[]), Virtual);
+
+    let (_root, sysfs) = interface(&[], &[]);
+    assert_eq!(NicMetadata::get_type(&sysfs, "missing"), Unknown);
+  }
+
+  #[test]
+  fn interfaces_without_a_device_are_virtual() {
+    let (_root, sysfs) = interface(&[], &["device"]);
+    assert!(!NicMetadata::is_virtual(&sysfs, "if0"));
+
+    let (_root, sysfs) = interface(&[], &[]);
+    assert!(NicMetadata::is_virtual(&sysfs, "if0"));
+    // Without sysfs there's no telling
+    assert!(!NicMetadata::is_virtual(&sysfs, "missing"));
+  }
+}
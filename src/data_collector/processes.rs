@@ -0,0 +1,244 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::time::Instant;
+use sysinfo::{Pid, Process, ProcessExt, ProcessRefreshKind, SystemExt};
+
+use crate::types::{GPUStats, ProcessStats};
+
+use super::DataCollector;
+
+/// What the top processes are picked by
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ProcessUsage {
+  pid: Pid,
+  cpu: f32,
+  memory: u64,
+  disk: u64,
+  gpu_memory: u64,
+}
+
+impl DataCollector {
+  /// Refreshes the process list once per collection, the CPU and disk usage of every
+  /// process are only read when the top processes are reported
+  pub fn refresh_processes(&mut self) {
+    let kind = if self.collection.collectors.top_processes {
+      ProcessRefreshKind::everything()
+    } else {
+      ProcessRefreshKind::new()
+    };
+    self.fetcher.refresh_processes_specifics(kind);
+  }
+
+  /// The processes using the most CPU, memory, disk I/O and GPU memory as of the last
+  /// `refresh_processes`, sorted by their CPU usage
+  pub fn get_top_processes(&mut self, gpus: &[GPUStats]) -> Vec<ProcessStats> {
+    let now = Instant::now();
+    let elapsed = self
+      .processes_refreshed_at
+      .replace(now)
+      .map(|then| now.duration_since(then).as_secs_f64())
+      .unwrap_or_default();
+
+    let mut gpu_memory: HashMap<u32, u64> = HashMap::new();
+    for process in gpus.iter().flat_map(|gpu| gpu.processes.iter().flatten()) {
+      *gpu_memory.entry(process.pid).or_default() += process.memory_used.unwrap_or_default();
+    }
+
+    let usages: Vec<ProcessUsage> = self
+      .fetcher
+      .processes()
+      .values()
+      .map(|process| {
+        let disk = process.disk_usage();
+        ProcessUsage {
+          pid: process.pid(),
+          cpu: process.cpu_usage(),
+          memory: process.memory(),
+          disk: disk.read_bytes + disk.written_bytes,
+          gpu_memory: gpu_memory
+            .get(&(process.pid() as u32))
+            .copied()
+            .unwrap_or_default(),
+        }
+      })
+      .collect();
+
+    let config = &self.collection.top_processes;
+    let mut top: Vec<ProcessStats> = top_pids(&usages, config.count)
+      .into_iter()
+      .filter_map(|pid| self.fetcher.process(pid))
+      .map(|process| {
+        let disk = process.disk_usage();
+        let per_second = |bytes: u64| {
+          if elapsed > 0.0 {
+            (bytes as f64 / elapsed).round() as u64
+          } else {
+            0
+          }
+        };
+        ProcessStats {
+          pid: process.pid() as u32,
+          name: process.name().to_string(),
+          user: self.get_process_user(process),
+          command: Some(process.cmd().join(" "))
+            .filter(|_| config.command_line)
+            .map(|command| redact(&command, &self.process_redactions)),
+          start_time: process.start_time(),
+          cpu_usage: process.cpu_usage(),
+          // sysinfo reports memory in kilobytes
+          memory: process.memory() * 1000,
+          disk_read_bytes_per_second: per_second(disk.read_bytes),
+          disk_write_bytes_per_second: per_second(disk.written_bytes),
+          gpu_memory: gpu_memory.get(&(process.pid() as u32)).copied(),
+        }
+      })
+      .collect();
+    top.sort_by(|a, b| b.cpu_usage.total_cmp(&a.cpu_usage));
+    top
+  }
+
+  #[cfg(target_os = "linux")]
+  fn get_process_user(&self, process: &Process) -> Option<String> {
+    use sysinfo::UserExt;
+
+    let user = self
+      .fetcher
+      .users()
+      .iter()
+      .find(|user| *user.uid() == process.uid);
+    Some(match user {
+      Some(user) => user.name().to_string(),
+      None => process.uid.to_string(),
+    })
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  fn get_process_user(&self, _process: &Process) -> Option<String> {
+    None
+  }
+}
+
+/// The `count` processes using the most of each resource, a process only counts
+/// for disk I/O and GPU memory when it uses any
+fn top_pids(usages: &[ProcessUsage], count: usize) -> Vec<Pid> {
+  let mut pids: Vec<Pid> = Vec::new();
+  let mut pick = |compare: fn(&ProcessUsage, &ProcessUsage) -> std::cmp::Ordering,
+                  uses: fn(&ProcessUsage) -> bool| {
+    let mut sorted: Vec<&ProcessUsage> = usages.iter().filter(|usage| uses(usage)).collect();
+    sorted.sort_by(|a, b| compare(b, a));
+    for usage in sorted.into_iter().take(count) {
+      if !pids.contains(&usage.pid) {
+        pids.push(usage.pid);
+      }
+    }
+  };
+  pick(|a, b| a.cpu.total_cmp(&b.cpu), |_| true);
+  pick(|a, b| a.memory.cmp(&b.memory), |_| true);
+  pick(|a, b| a.disk.cmp(&b.disk), |usage| usage.disk > 0);
+  pick(
+    |a, b| a.gpu_memory.cmp(&b.gpu_memory),
+    |usage| usage.gpu_memory > 0,
+  );
+  pids
+}
+
+/// Masks what the patterns match in a command line, only their `secret` group when they have one
+fn redact(command: &str, patterns: &[Regex]) -> String {
+  let mut command = command.to_string();
+  for pattern in patterns {
+    let mut redacted = String::with_capacity(command.len());
+    let mut last = 0;
+    for captures in pattern.captures_iter(&command) {
+      let secret = captures
+        .name("secret")
+        .or_else(|| captures.get(0))
+        .expect("the whole match is always there");
+      redacted.push_str(&command[last..secret.start()]);
+      redacted.push_str("***");
+      last = secret.end();
+    }
+    redacted.push_str(&command[last..]);
+    command = redacted;
+  }
+  command
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config_manager::TopProcessesConfig;
+
+  fn usage(pid: Pid, cpu: f32, memory: u64, disk: u64, gpu_memory: u64) -> ProcessUsage {
+    ProcessUsage {
+      pid,
+      cpu,
+      memory,
+      disk,
+      gpu_memory,
+    }
+  }
+
+  #[test]
+  fn picks_the_top_processes_of_every_resource() {
+    let usages = [
+      usage(1, 90.0, 10, 0, 0),
+      usage(2, 50.0, 10, 0, 0),
+      usage(3, 1.0, 9000, 0, 0),
+      usage(4, 0.0, 10, 500, 0),
+      usage(5, 0.0, 10, 0, 0),
+      usage(6, 0.0, 10, 0, 4096),
+    ];
+    assert_eq!(top_pids(&usages, 1), vec![1, 3, 4, 6]);
+    assert_eq!(top_pids(&usages, 2), vec![1, 2, 3, 4, 6]);
+  }
+
+  #[test]
+  fn idle_disks_and_gpus_pick_nothing() {
+    let usages = [usage(1, 0.0, 10, 0, 0), usage(2, 0.0, 20, 0, 0)];
+    assert_eq!(top_pids(&usages, 1), vec![1, 2]);
+  }
+
+  #[test]
+  fn redacts_the_default_patterns() {
+    let patterns: Vec<Regex> = TopProcessesConfig::default()
+      .redact
+      .iter()
+      .map(|pattern| Regex::new(pattern).unwrap())
+      .collect();
+
+    assert_eq!(
+      redact("mysqld --password=hunter2 --port 3306", &patterns),
+      "mysqld --password=*** --port 3306"
+    );
+    assert_eq!(
+      redact("app --api-key abc123 -v", &patterns),
+      "app --api-key *** -v"
+    );
+    assert_eq!(
+      redact("worker postgres://app:s3cret@db:5432/app", &patterns),
+      "worker postgres://app:***@db:5432/app"
+    );
+    assert_eq!(
+      redact("env DB_PASSWORD=hunter2 MYSQL_PWD:x app", &patterns),
+      "env DB_PASSWORD=*** MYSQL_PWD:*** app"
+    );
+    assert_eq!(
+      redact("gh --auth-token ghp_abc pr list", &patterns),
+      "gh --auth-token *** pr list"
+    );
+    assert_eq!(
+      redact("make authors tokenizer", &patterns),
+      "make authors tokenizer"
+    );
+    assert_eq!(
+      redact("nginx -g daemon off; http://localhost:8080/", &patterns),
+      "nginx -g daemon off; http://localhost:8080/"
+    );
+  }
+
+  #[test]
+  fn patterns_without_a_secret_group_mask_the_whole_match() {
+    let patterns = [Regex::new(r"\d{4}-\d{4}").unwrap()];
+    assert_eq!(redact("card 1234-5678 ok", &patterns), "card *** ok");
+  }
+}
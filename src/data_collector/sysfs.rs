@@ -0,0 +1,46 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Reads the files the Linux kernel exposes under `/sys`, or `/proc` when rooted there,
+/// the root can be pointed somewhere else to read a fake tree
+#[derive(Debug, Clone)]
+pub struct Sysfs {
+  pub root: PathBuf,
+}
+
+impl Default for Sysfs {
+  fn default() -> Self {
+    Self::new("/sys")
+  }
+}
+
+impl Sysfs {
+  pub fn new(root: impl AsRef<Path>) -> Self {
+    Self {
+      root: root.as_ref().to_path_buf(),
+    }
+  }
+
+  /// `class/net/<interface>` under the root
+  pub fn net_path(&self, interface: &str) -> PathBuf {
+    self.root.join("class/net").join(interface)
+  }
+
+  /// Reads a file relative to the root without the trailing newline,
+  /// `None` if it doesn't exist or can't be read
+  pub fn read_string(&self, path: impl AsRef<Path>) -> Option<String> {
+    let path = path.as_ref();
+    let path = if path.is_absolute() {
+      path.to_path_buf()
+    } else {
+      self.root.join(path)
+    };
+    fs::read_to_string(path)
+      .ok()
+      .map(|content| content.trim_end().to_string())
+  }
+
+  pub fn read_u64(&self, path: impl AsRef<Path>) -> Option<u64> {
+    self.read_string(path)?.parse().ok()
+  }
+}
@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::process::Command;
+
+use crate::types::{SystemdStats, SystemdUnitStats};
+
+use super::DataCollector;
+
+/// The properties read from `systemctl show` for every unit
+const UNIT_PROPERTIES: &str =
+  "Id,LoadState,ActiveState,SubState,NRestarts,StateChangeTimestampMonotonic";
+
+impl DataCollector {
+  /// Reads the state of the configured units and the number of failed units from `systemctl`
+  pub fn get_systemd(&self) -> Result<SystemdStats> {
+    let failed_units = parse_properties(&systemctl(&["show", "--property=NFailedUnits"])?)
+      .get("NFailedUnits")
+      .and_then(|count| count.parse().ok())
+      .ok_or_else(|| anyhow!("systemctl didn't report the number of failed units"))?;
+
+    let units = if self.collection.systemd_units.is_empty() {
+      Vec::new()
+    } else {
+      let mut args = vec!["show", "--property", UNIT_PROPERTIES, "--"];
+      args.extend(self.collection.systemd_units.iter().map(String::as_str));
+      parse_units(&systemctl(&args)?, boot_time_micros())
+    };
+
+    Ok(SystemdStats {
+      failed_units,
+      units,
+    })
+  }
+}
+
+fn systemctl(args: &[&str]) -> Result<String> {
+  let output = Command::new("systemctl").args(args).output()?;
+  if !output.status.success() {
+    return Err(anyhow!(
+      "systemctl failed: {}",
+      String::from_utf8_lossy(&output.stderr).trim()
+    ));
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The `Key=value` lines of a single unit
+fn parse_properties(block: &str) -> HashMap<&str, &str> {
+  block
+    .lines()
+    .filter_map(|line| line.split_once('='))
+    .collect()
+}
+
+/// Parses `systemctl show` for several units, their properties are separated by a blank line.
+/// `boot_time` is the wall clock time of the monotonic clock's zero in microseconds
+fn parse_units(output: &str, boot_time: Option<u64>) -> Vec<SystemdUnitStats> {
+  output
+    .split("\n\n")
+    .map(parse_properties)
+    .filter(|properties| !properties.is_empty())
+    .map(|properties| {
+      let property = |key: &str| properties.get(key).copied().unwrap_or_default().to_string();
+      SystemdUnitStats {
+        name: property("Id"),
+        load_state: property("LoadState"),
+        active_state: property("ActiveState"),
+        sub_state: property("SubState"),
+        restarts: properties.get("NRestarts").and_then(|n| n.parse().ok()),
+        since: properties
+          .get("StateChangeTimestampMonotonic")
+          .and_then(|timestamp| timestamp.parse::<u64>().ok())
+          .filter(|timestamp| *timestamp > 0)
+          .zip(boot_time)
+          .map(|(timestamp, boot_time)| (boot_time + timestamp) / 1_000_000),
+      }
+    })
+    .collect()
+}
+
+/// systemd timestamps its state changes with the monotonic clock, which starts at boot
+#[cfg(target_family = "unix")]
+fn boot_time_micros() -> Option<u64> {
+  use crate::util::unix_millis;
+
+  let mut monotonic = libc::timespec {
+    tv_sec: 0,
+    tv_nsec: 0,
+  };
+  if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut monotonic) } != 0 {
+    return None;
+  }
+  let monotonic = monotonic.tv_sec as u64 * 1_000_000 + monotonic.tv_nsec as u64 / 1000;
+  (unix_millis() * 1000).checked_sub(monotonic)
+}
+
+#[cfg(not(target_family = "unix"))]
+fn boot_time_micros() -> Option<u64> {
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SHOW: &str = "\
+Id=nginx.service
+LoadState=loaded
+ActiveState=active
+SubState=running
+NRestarts=2
+StateChangeTimestampMonotonic=5000000
+
+Id=backup.timer
+LoadState=loaded
+ActiveState=failed
+SubState=failed
+StateChangeTimestampMonotonic=0
+
+Id=nope.service
+LoadState=not-found
+ActiveState=inactive
+SubState=dead
+NRestarts=0
+StateChangeTimestampMonotonic=0
+";
+
+  #[test]
+  fn parses_the_units() {
+    let units = parse_units(SHOW, Some(1_700_000_000_000_000));
+    assert_eq!(
+      units,
+      vec![
+        SystemdUnitStats {
+          name: "nginx.service".to_string(),
+          load_state: "loaded".to_string(),
+          active_state: "active".to_string(),
+          sub_state: "running".to_string(),
+          restarts: Some(2),
+          since: Some(1_700_000_005),
+        },
+        SystemdUnitStats {
+          name: "backup.timer".to_string(),
+          load_state: "loaded".to_string(),
+          active_state: "failed".to_string(),
+          sub_state: "failed".to_string(),
+          restarts: None,
+          since: None,
+        },
+        SystemdUnitStats {
+          name: "nope.service".to_string(),
+          load_state: "not-found".to_string(),
+          active_state: "inactive".to_string(),
+          sub_state: "dead".to_string(),
+          restarts: Some(0),
+          since: None,
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn unknown_boot_time_leaves_since_out() {
+    let units = parse_units(SHOW, None);
+    assert_eq!(units[0].since, None);
+    assert_eq!(units[0].restarts, Some(2));
+  }
+
+  #[test]
+  fn values_can_hold_equal_signs() {
+    let properties = parse_properties("NFailedUnits=3\nEnvironment=A=1 B=2\n");
+    assert_eq!(properties.get("NFailedUnits"), Some(&"3"));
+    assert_eq!(properties.get("Environment"), Some(&"A=1 B=2"));
+  }
+}
@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// The prefix that marks a pattern as a regular expression instead of a glob
+const REGEX_PREFIX: &str = "re:";
+
+/// Include and exclude patterns from the config, globs like `eth*` or `/mnt/*` and
+/// unanchored regular expressions prefixed with `re:` like `re:^veth[0-9a-f]+$`.
+/// An empty `include` list lets everything through that isn't excluded
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PatternFilter {
+  #[serde(default)]
+  pub include: Vec<String>,
+  #[serde(default)]
+  pub exclude: Vec<String>,
+}
+
+impl PatternFilter {
+  pub fn new(include: &[&str], exclude: &[&str]) -> Self {
+    Self {
+      include: include.iter().map(|pattern| pattern.to_string()).collect(),
+      exclude: exclude.iter().map(|pattern| pattern.to_string()).collect(),
+    }
+  }
+
+  /// Compiles the patterns, the error names the list and index of the bad pattern
+  pub fn compile(&self) -> Result<Filter> {
+    Ok(Filter {
+      include: compile_patterns("include", &self.include)?,
+      exclude: compile_patterns("exclude", &self.exclude)?,
+    })
+  }
+}
+
+fn compile_patterns(list: &str, patterns: &[String]) -> Result<Vec<Matcher>> {
+  patterns
+    .iter()
+    .enumerate()
+    .map(|(index, pattern)| {
+      let matcher = match pattern.strip_prefix(REGEX_PREFIX) {
+        Some(regex) => Regex::new(regex)
+          .map(Matcher::Regex)
+          .map_err(|e| e.to_string()),
+        None => Pattern::new(pattern)
+          .map(Matcher::Glob)
+          .map_err(|e| e.to_string()),
+      };
+      matcher.map_err(|e| {
+        anyhow!(
+          "{}[{}]: {} is not a valid pattern: {}",
+          list,
+          index,
+          pattern,
+          e
+        )
+      })
+    })
+    .collect()
+}
+
+/// A single compiled pattern
+#[derive(Clone, Debug)]
+enum Matcher {
+  Glob(Pattern),
+  Regex(Regex),
+}
+
+impl Matcher {
+  fn matches(&self, name: &str) -> bool {
+    match self {
+      Matcher::Glob(pattern) => pattern.matches(name),
+      Matcher::Regex(regex) => regex.is_match(name),
+    }
+  }
+}
+
+/// A compiled `PatternFilter`
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+  include: Vec<Matcher>,
+  exclude: Vec<Matcher>,
+}
+
+impl Filter {
+  /// Whether something known by any of `names` (a disk's name and mount point...) should be kept
+  pub fn allows(&self, names: &[&str]) -> bool {
+    let matches = |patterns: &[Matcher]| {
+      patterns
+        .iter()
+        .any(|pattern| names.iter().any(|name| pattern.matches(name)))
+    };
+    (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn filter(include: &[&str], exclude: &[&str]) -> Filter {
+    PatternFilter::new(include, exclude).compile().unwrap()
+  }
+
+  #[test]
+  fn globs_match_the_whole_name() {
+    let filter = filter(&["eth*"], &[]);
+    assert!(filter.allows(&["eth0"]));
+    assert!(!filter.allows(&["veth0"]));
+  }
+
+  #[test]
+  fn regexes_are_unanchored() {
+    let unanchored = filter(&["re:eth[0-9]"], &[]);
+    assert!(unanchored.allows(&["eth0"]));
+    assert!(unanchored.allows(&["veth0"]));
+    assert!(!unanchored.allows(&["ethX"]));
+
+    let anchored = filter(&[], &["re:^veth[0-9a-f]+$"]);
+    assert!(!anchored.allows(&["veth1a2b"]));
+    assert!(anchored.allows(&["myveth1"]));
+  }
+
+  #[test]
+  fn re_is_only_a_prefix() {
+    // Without the prefix `re:` is part of a glob
+    let filter = filter(&["*re:*"], &[]);
+    assert!(filter.allows(&["are:you"]));
+    assert!(!filter.allows(&["eth0"]));
+  }
+
+  #[test]
+  fn an_empty_include_list_lets_everything_through() {
+    let filter = filter(&[], &["lo"]);
+    assert!(filter.allows(&["eth0"]));
+    assert!(!filter.allows(&["lo"]));
+    assert!(Filter::default().allows(&["anything"]));
+  }
+
+  #[test]
+  fn excludes_win_over_includes() {
+    let filter = filter(&["/mnt/*"], &["/mnt/backup"]);
+    assert!(filter.allows(&["/mnt/data"]));
+    assert!(!filter.allows(&["/mnt/backup"]));
+    assert!(!filter.allows(&["/home"]));
+  }
+
+  #[test]
+  fn any_of_the_names_can_match() {
+    let filter = filter(&["/data"], &["/dev/loop*"]);
+    assert!(filter.allows(&["/dev/sdb1", "/data"]));
+    // An excluded device hides the mount even when the mount point is included
+    assert!(!filter.allows(&["/dev/loop0", "/data"]));
+  }
+
+  #[test]
+  fn invalid_patterns_name_their_list_and_index() {
+    let error = PatternFilter::new(&["eth*", "re:("], &[])
+      .compile()
+      .unwrap_err();
+    assert!(error
+      .to_string()
+      .starts_with("include[1]: re:( is not a valid pattern: "));
+
+    let error = PatternFilter::new(&[], &["[a-"]).compile().unwrap_err();
+    assert!(error
+      .to_string()
+      .starts_with("exclude[0]: [a- is not a valid pattern: "));
+  }
+}
@@ -1,41 +1,70 @@
 use anyhow::Result;
 use core::time;
-use std::thread;
 use std::time::Instant;
-use util::arcmutex;
 
 extern crate nvml_wrapper as nvml;
 
 mod arg_parser;
 mod auth_manager;
+mod commands;
 mod config_manager;
 mod data_collector;
+mod filter;
+mod metric_buffer;
 mod reporter;
+mod sinks;
+mod tui;
 mod types;
 mod util;
 mod websocket_manager;
+use crate::arg_parser::{ArgParser, Command, ConfigCommand, RunArgs};
 use crate::reporter::Reporter;
+use crate::tui::Dashboard;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+  let args = ArgParser::new();
+  let config_path = args.config.as_deref();
+
+  match args.subcommand() {
+    Command::Run(run_args) => run(config_path, run_args).await,
+    Command::Signup { key } => commands::signup(config_path, &key).await,
+    Command::Config(ConfigCommand::Check) => commands::config_check(config_path),
+    Command::Config(ConfigCommand::Print) => commands::config_print(config_path),
+    Command::Collect { format } => commands::collect(config_path, format).await,
+    Command::Status => commands::status(config_path),
+    Command::Completions { shell } => ArgParser::print_completions(shell),
+    Command::Man => {
+      print!("{}", ArgParser::ronn());
+      Ok(())
+    }
+  }
+}
+
+/// Collects and sends the data every interval until the process is stopped
+async fn run(config_path: Option<&str>, args: RunArgs) -> Result<()> {
+  if args.tui {
+    return Dashboard::run(config_path, args.interval).await;
+  }
+
   // Create a new instance of the reporter
-  let reporter = arcmutex(Reporter::new().await?);
+  let mut reporter = Reporter::new(config_path, args).await?;
 
   loop {
     let start_time = Instant::now();
 
     let fetch_start_time = Instant::now();
-    match reporter.lock().update_dynamic_data().await {
+    match reporter.update_dynamic_data().await {
       Ok(_) => {}
       Err(e) => {
-        println!("{}", e);
-        thread::sleep(time::Duration::from_secs(1));
+        eprintln!("{}", e);
+        tokio::time::sleep(time::Duration::from_secs(1)).await;
       }
     }
     let fetch_elapsed = fetch_start_time.elapsed();
 
     let send_start_time = Instant::now();
-    match reporter.lock().send_dynamic_data().await {
+    match reporter.send_dynamic_data().await {
       Ok(_) => {}
       Err(e) => {
         eprintln!("Error while sending dynamic data: {}", e);
@@ -45,19 +74,22 @@ async fn main() -> Result<()> {
 
     let total_elapsed = start_time.elapsed();
 
-    let mut rest_time = reporter.lock().args.interval - total_elapsed.as_secs_f64();
+    let mut rest_time = reporter.interval - total_elapsed.as_secs_f64();
     if rest_time < 0.0 {
       rest_time = 0.0;
     }
 
-    println!(
-      "Fetch: [{}ms] Send: [{}ms] Total: [{}ms] - Rest: [{}s]",
-      fetch_elapsed.as_millis().to_string(),
-      send_elapsed.as_millis().to_string(),
-      total_elapsed.as_millis().to_string(),
-      rest_time.to_string()
-    );
+    if !reporter.stdout_output {
+      println!(
+        "Fetch: [{}ms] Send: [{}ms] Total: [{}ms] - Rest: [{}s]",
+        fetch_elapsed.as_millis(),
+        send_elapsed.as_millis(),
+        total_elapsed.as_millis(),
+        rest_time
+      );
+    }
 
-    thread::sleep(time::Duration::from_secs_f64(rest_time));
+    // Sleep without blocking the runtime so the Prometheus exporter can keep serving
+    tokio::time::sleep(time::Duration::from_secs_f64(rest_time)).await;
   }
 }
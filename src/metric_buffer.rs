@@ -0,0 +1,282 @@
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::types::DynamicData;
+use crate::util::unix_millis;
+
+/// A bounded queue of the dynamic data samples that couldn't be sent to the backend.
+/// Samples are kept in memory and mirrored to a JSON lines file so they survive a restart,
+/// the oldest ones get dropped once the queue grows over its byte or age limit.
+/// New samples are appended to the file and the dropped ones are only cleared out of it
+/// once they take up more than half of it, since a full buffer drops one on every push
+#[derive(Debug)]
+pub struct MetricBuffer {
+  path: PathBuf,
+  samples: VecDeque<(DynamicData, usize)>,
+  size: usize,
+  /// Bytes at the start of the file taken by samples that were dropped since it was written
+  stale: usize,
+  max_bytes: usize,
+  max_age: Duration,
+}
+
+impl MetricBuffer {
+  /// Creates a new buffer and loads the samples left over from a previous run
+  pub fn new(path: impl AsRef<Path>, max_bytes: usize, max_age: Duration) -> Result<Self> {
+    let mut this = Self {
+      path: path.as_ref().to_path_buf(),
+      samples: VecDeque::new(),
+      size: 0,
+      stale: 0,
+      max_bytes,
+      max_age,
+    };
+
+    if this.path.exists() {
+      let file = File::open(&this.path)?;
+      for line in BufReader::new(file).lines() {
+        // A partially written line from a crash shouldn't take the whole buffer with it
+        if let Ok(sample) = serde_json::from_str::<DynamicData>(&line?) {
+          this.push_in_memory(sample)?;
+        }
+      }
+      this.evict();
+      this.persist()?;
+    }
+
+    Ok(this)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.samples.is_empty()
+  }
+
+  pub fn len(&self) -> usize {
+    self.samples.len()
+  }
+
+  /// The oldest sample in the buffer
+  pub fn front(&self) -> Option<&DynamicData> {
+    self.samples.front().map(|(sample, _)| sample)
+  }
+
+  /// Removes the oldest sample, call `persist` once done draining so the
+  /// samples that were sent aren't replayed after a restart
+  pub fn pop_front(&mut self) -> Option<DynamicData> {
+    let (sample, size) = self.samples.pop_front()?;
+    self.size -= size;
+    self.stale += size;
+    Some(sample)
+  }
+
+  /// Adds a sample to the end of the buffer, dropping the oldest ones if it's full
+  pub fn push(&mut self, sample: DynamicData) -> Result<()> {
+    let line = self.push_in_memory(sample)?;
+    self.evict();
+
+    if self.stale > self.size {
+      return self.persist();
+    }
+
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+  }
+
+  /// Rewrites the file on disk to match the samples in memory
+  pub fn persist(&mut self) -> Result<()> {
+    self.stale = 0;
+    if self.samples.is_empty() {
+      if self.path.exists() {
+        fs::remove_file(&self.path)?;
+      }
+      return Ok(());
+    }
+
+    let temp_path = self.path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    for (sample, _) in &self.samples {
+      serde_json::to_writer(&mut writer, sample)?;
+      writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    fs::rename(temp_path, &self.path)?;
+    Ok(())
+  }
+
+  fn push_in_memory(&mut self, sample: DynamicData) -> Result<String> {
+    let line = format!("{}\n", serde_json::to_string(&sample)?);
+    self.size += line.len();
+    self.samples.push_back((sample, line.len()));
+    Ok(line)
+  }
+
+  /// Drops the samples that are too old or don't fit. A dropped sample that's left in the
+  /// file is dropped again when it's replayed since the limits apply then as well
+  fn evict(&mut self) {
+    let oldest_allowed = unix_millis().saturating_sub(self.max_age.as_millis() as u64);
+
+    while let Some((sample, _)) = self.samples.front() {
+      if self.size <= self.max_bytes && sample.timestamp >= oldest_allowed {
+        break;
+      }
+      self.pop_front();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::{CPUStats, RAMStats, SwapStats};
+  use tempfile::TempDir;
+
+  const HOUR: Duration = Duration::from_secs(3600);
+
+  fn sample(timestamp: u64) -> DynamicData {
+    DynamicData {
+      cpu: CPUStats {
+        usage: vec![10],
+        freq: vec![3000],
+      },
+      ram: RAMStats { used: 1, total: 2 },
+      gpus: Vec::new(),
+      process_count: 1,
+      top_processes: Vec::new(),
+      swap: SwapStats { used: 0, total: 0 },
+      disks: Vec::new(),
+      temps: None,
+      network: Vec::new(),
+      systemd: None,
+      containers: Vec::new(),
+      cgroup: None,
+      host_uptime: 1,
+      reporter_uptime: 1,
+      timestamp,
+    }
+  }
+
+  fn line_len(sample: &DynamicData) -> usize {
+    serde_json::to_string(sample).unwrap().len() + 1
+  }
+
+  fn timestamps(buffer: &mut MetricBuffer) -> Vec<u64> {
+    std::iter::from_fn(|| buffer.pop_front())
+      .map(|sample| sample.timestamp)
+      .collect()
+  }
+
+  #[test]
+  fn drops_the_oldest_samples_over_the_size_limit() {
+    let dir = TempDir::new().unwrap();
+    let now = unix_millis();
+    let max_bytes = line_len(&sample(now)) * 2;
+    let mut buffer = MetricBuffer::new(dir.path().join("buffer.jsonl"), max_bytes, HOUR).unwrap();
+
+    for offset in 0..3 {
+      buffer.push(sample(now + offset)).unwrap();
+    }
+    assert_eq!(buffer.len(), 2);
+    assert_eq!(timestamps(&mut buffer), vec![now + 1, now + 2]);
+  }
+
+  #[test]
+  fn a_full_buffer_appends_until_the_dropped_samples_take_up_half_the_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("buffer.jsonl");
+    let now = unix_millis();
+    let max_bytes = line_len(&sample(now)) * 2;
+    let mut buffer = MetricBuffer::new(&path, max_bytes, HOUR).unwrap();
+    let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+
+    for offset in 0..4 {
+      buffer.push(sample(now + offset)).unwrap();
+    }
+    // Two dropped samples and two kept ones
+    assert_eq!(lines(&path), 4);
+
+    // Replaying the file drops the stale samples again
+    let mut replayed = MetricBuffer::new(&path, max_bytes, HOUR).unwrap();
+    assert_eq!(timestamps(&mut replayed), vec![now + 2, now + 3]);
+
+    let mut buffer = MetricBuffer::new(&path, max_bytes, HOUR).unwrap();
+    for offset in 4..7 {
+      buffer.push(sample(now + offset)).unwrap();
+    }
+    // The third dropped sample tipped it over and the file was compacted
+    assert_eq!(lines(&path), 2);
+    assert_eq!(timestamps(&mut buffer), vec![now + 5, now + 6]);
+  }
+
+  #[test]
+  fn drops_samples_older_than_the_age_limit() {
+    let dir = TempDir::new().unwrap();
+    let now = unix_millis();
+    let mut buffer = MetricBuffer::new(dir.path().join("buffer.jsonl"), usize::MAX, HOUR).unwrap();
+
+    buffer.push(sample(now - 2 * 3_600_000)).unwrap();
+    buffer.push(sample(now)).unwrap();
+    assert_eq!(timestamps(&mut buffer), vec![now]);
+  }
+
+  #[test]
+  fn replays_the_samples_of_a_previous_run() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("buffer.jsonl");
+    let now = unix_millis();
+    {
+      let mut buffer = MetricBuffer::new(&path, usize::MAX, HOUR).unwrap();
+      buffer.push(sample(now)).unwrap();
+      buffer.push(sample(now + 1)).unwrap();
+    }
+
+    let mut buffer = MetricBuffer::new(&path, usize::MAX, HOUR).unwrap();
+    assert_eq!(buffer.front().map(|sample| sample.timestamp), Some(now));
+    assert_eq!(timestamps(&mut buffer), vec![now, now + 1]);
+    buffer.persist().unwrap();
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn skips_corrupted_lines() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("buffer.jsonl");
+    let now = unix_millis();
+    let line = serde_json::to_string(&sample(now)).unwrap();
+    fs::write(
+      &path,
+      format!("{}\nnot json\n{}", line, &line[..line.len() / 2]),
+    )
+    .unwrap();
+
+    let mut buffer = MetricBuffer::new(&path, usize::MAX, HOUR).unwrap();
+    assert_eq!(buffer.len(), 1);
+    // The file is rewritten without the corrupted lines
+    assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", line));
+    assert_eq!(timestamps(&mut buffer), vec![now]);
+  }
+
+  #[test]
+  fn replay_applies_the_limits() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("buffer.jsonl");
+    let now = unix_millis();
+    {
+      let mut buffer = MetricBuffer::new(&path, usize::MAX, HOUR).unwrap();
+      for offset in 0..3 {
+        buffer.push(sample(now + offset)).unwrap();
+      }
+    }
+
+    let max_bytes = line_len(&sample(now));
+    let mut buffer = MetricBuffer::new(&path, max_bytes, HOUR).unwrap();
+    assert_eq!(timestamps(&mut buffer), vec![now + 2]);
+  }
+}
@@ -1,114 +1,121 @@
-use crate::arg_parser::ArgParser;
+use crate::arg_parser::RunArgs;
 use crate::config_manager::ConfigManager;
 use crate::data_collector::DataCollector;
+use crate::sinks::{default_file_max_bytes, default_file_max_files, Sink, SinkConfig};
 use crate::types::DynamicData;
-use crate::websocket_manager::{WebsocketEvent, WebsocketManager};
 use anyhow::Result;
 
 pub struct Reporter {
   pub data_collector: DataCollector,
-  pub version: String,
   pub config_manager: ConfigManager,
-  pub websocket_manager: Option<WebsocketManager>,
-  pub args: ArgParser,
+  pub sinks: Vec<Box<dyn Sink>>,
+  pub args: RunArgs,
   pub dynamic_data: DynamicData,
+  /// Seconds between two collections, from the config unless `--interval` is given
+  pub interval: f64,
+  /// Whether stdout is taken by the data so nothing else should be printed there
+  pub stdout_output: bool,
 }
 
 impl Reporter {
-  pub async fn new() -> Result<Self> {
-    let args = ArgParser::new().await?;
-    let websocket_manager: Option<WebsocketManager> = None;
-
-    let config_manager: ConfigManager = ConfigManager::new()?;
-    let mut data_collector: DataCollector = DataCollector::new()?;
-    let version: String = env!("CARGO_PKG_VERSION").to_string();
-    let dynamic_data: DynamicData = data_collector.get_all_dynamic_data()?;
+  pub async fn new(config_path: Option<&str>, args: RunArgs) -> Result<Self> {
+    let config_manager: ConfigManager = ConfigManager::new(config_path)?;
+    let mut data_collector: DataCollector = DataCollector::new(&config_manager.config)?;
+    let interval = args
+      .interval
+      .unwrap_or(config_manager.config.collection.interval);
+    let dynamic_data: DynamicData = data_collector.get_all_dynamic_data().await?;
 
     let mut this = Self {
       data_collector,
-      version,
-      websocket_manager,
+      sinks: Vec::new(),
       config_manager,
       args,
       dynamic_data,
+      interval,
+      stdout_output: false,
     };
 
-    if !this.args.offline {
-      this.init_connection()?;
-      this.send_static_data().await?;
-    }
+    this.init_sinks().await?;
+    this.send_static_data().await?;
 
     Ok(this)
   }
 
-  pub fn init_connection(&mut self) -> Result<()> {
-    let websocket_url: String = format!(
-      "wss://{}/reporter",
-      self.config_manager.config.backend_hostname.to_owned()
-    );
-    self.websocket_manager = Some(WebsocketManager::new(&websocket_url)?);
-    self.login()?;
-    Ok(())
-  }
+  /// Creates the sinks from the config, the launch parameters can drop
+  /// the backend with `--offline` or add a Prometheus exporter and JSON lines output
+  pub async fn init_sinks(&mut self) -> Result<()> {
+    let mut sink_configs = self.config_manager.config.sinks.clone();
+
+    if self.args.offline {
+      sink_configs.retain(|sink_config| *sink_config != SinkConfig::Websocket);
+    }
 
-  pub fn login(&mut self) -> Result<()> {
-    if let Some(websocket_manager) = self.websocket_manager.as_mut() {
-      websocket_manager.send(WebsocketEvent::Login {
-        auth_token: self.config_manager.config.access_token.to_string(),
-      })?;
+    if let Some(address) = self.args.prometheus.as_ref() {
+      let sink_config = SinkConfig::Prometheus {
+        address: address.to_string(),
+      };
+      if !sink_configs.contains(&sink_config) {
+        sink_configs.push(sink_config);
+      }
+    }
+
+    if let Some(output) = self.args.output.as_ref() {
+      let sink_config = match output.as_str() {
+        "-" | "stdout" => SinkConfig::Stdout,
+        path => SinkConfig::File {
+          path: path.to_string(),
+          max_bytes: default_file_max_bytes(),
+          max_files: default_file_max_files(),
+        },
+      };
+      if !sink_configs.contains(&sink_config) {
+        sink_configs.push(sink_config);
+      }
+    }
+
+    self.stdout_output = sink_configs.contains(&SinkConfig::Stdout);
+
+    // The backend would just keep refusing the login
+    if sink_configs.contains(&SinkConfig::Websocket) {
+      self.config_manager.check_access_token()?;
+    }
+
+    for sink_config in &sink_configs {
+      let sink = sink_config.create(&self.config_manager).await?;
+      self.sinks.push(sink);
     }
 
     Ok(())
   }
 
   pub async fn send_static_data(&mut self) -> Result<()> {
-    if let Some(websocket_manager) = self.websocket_manager.as_mut() {
-      let static_data = self.data_collector.get_statics().await?;
-
-      websocket_manager.send(WebsocketEvent::StaticData {
-        hostname: static_data.hostname,
-        public_ip: static_data.public_ip,
-        country: static_data.country,
-        city: static_data.city,
-        isp: static_data.isp,
-        timezone: static_data.timezone,
-        cpu_model: static_data.cpu_model,
-        os_version: static_data.os_version,
-        os_name: static_data.os_name,
-        cpu_cores: static_data.cpu_cores,
-        cpu_threads: static_data.cpu_threads,
-        total_mem: static_data.total_mem,
-        reporter_version: self.version.clone(),
-      })?;
+    if self.sinks.is_empty() {
+      return Ok(());
+    }
+
+    let static_data = self.data_collector.get_statics().await?;
+
+    // A failing sink shouldn't keep the data from the others
+    for sink in self.sinks.iter_mut() {
+      if let Err(e) = sink.send_static_data(&static_data).await {
+        eprintln!("Error while sending static data to {}: {}", sink.name(), e);
+      }
     }
 
     Ok(())
   }
 
   pub async fn update_dynamic_data(&mut self) -> Result<()> {
-    self.dynamic_data = self.data_collector.get_all_dynamic_data()?;
+    self.dynamic_data = self.data_collector.get_all_dynamic_data().await?;
     self.data_collector.increment_iterator_index();
     Ok(())
   }
 
   pub async fn send_dynamic_data(&mut self) -> Result<()> {
-    if let Some(websocket_manager) = self.websocket_manager.as_mut() {
-      let dd = self.dynamic_data.clone();
-      if let Err(e) = websocket_manager.send(WebsocketEvent::DynamicData {
-        cpu: dd.cpu,
-        ram: dd.ram,
-        swap: dd.swap,
-        gpu: dd.gpu,
-        process_count: dd.process_count,
-        disks: dd.disks,
-        temps: dd.temps,
-        network: dd.network,
-        host_uptime: dd.host_uptime,
-        reporter_uptime: dd.reporter_uptime,
-      }) {
-        eprintln!("Websocket error: {}", e);
-        self.init_connection()?;
-        self.send_static_data().await?;
+    for sink in self.sinks.iter_mut() {
+      if let Err(e) = sink.send_dynamic_data(&self.dynamic_data).await {
+        eprintln!("Error while sending dynamic data to {}: {}", sink.name(), e);
       }
     }
 
@@ -0,0 +1,203 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::types::{DynamicData, StaticData};
+
+use super::{JsonLinesRecord, Sink};
+
+/// Appends every snapshot to a file as a line of JSON, rotating it like logrotate does
+/// (`out.jsonl` -> `out.jsonl.1` -> `out.jsonl.2`...) once it grows over `max_bytes`
+pub struct FileSink {
+  pub path: String,
+  pub max_bytes: u64,
+  pub max_files: usize,
+  file: File,
+  size: u64,
+  /// The static data record as it was first written, repeated on top of every rotated file
+  static_line: Option<String>,
+}
+
+impl FileSink {
+  pub fn new(path: &str, max_bytes: u64, max_files: usize) -> Result<Self> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(Self {
+      path: path.to_string(),
+      max_bytes,
+      max_files,
+      file,
+      size,
+      static_line: None,
+    })
+  }
+
+  fn write_line(&mut self, line: &str) -> Result<()> {
+    self.file.write_all(line.as_bytes())?;
+    self.size += line.len() as u64;
+    Ok(())
+  }
+
+  /// Shifts the rotated files by one, dropping the oldest, and starts a new file
+  /// with the static data on top so every file can be read on its own
+  fn rotate(&mut self) -> Result<()> {
+    for index in (1..self.max_files).rev() {
+      let from = format!("{}.{}", self.path, index);
+      if Path::new(&from).exists() {
+        replace(&from, &format!("{}.{}", self.path, index + 1))?;
+      }
+    }
+
+    if self.max_files > 0 {
+      replace(&self.path, &format!("{}.1", self.path))?;
+    }
+
+    self.file = File::create(&self.path)?;
+    self.size = 0;
+
+    if let Some(line) = self.static_line.clone() {
+      self.write_line(&line)?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Renames `from` to `to`, removing `to` first since Windows doesn't rename over a file
+fn replace(from: &str, to: &str) -> Result<()> {
+  if Path::new(to).exists() {
+    fs::remove_file(to)?;
+  }
+  fs::rename(from, to)?;
+  Ok(())
+}
+
+#[async_trait]
+impl Sink for FileSink {
+  fn name(&self) -> String {
+    format!("file ({})", self.path)
+  }
+
+  async fn send_static_data(&mut self, static_data: &StaticData) -> Result<()> {
+    let line = JsonLinesRecord::static_data(static_data).to_line()?;
+    self.static_line = Some(line.clone());
+    self.write_line(&line)
+  }
+
+  async fn send_dynamic_data(&mut self, dynamic_data: &DynamicData) -> Result<()> {
+    let line = JsonLinesRecord::dynamic_data(dynamic_data).to_line()?;
+    if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+      self.rotate()?;
+    }
+    self.write_line(&line)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::{json, Value};
+  use tempfile::TempDir;
+
+  fn static_data() -> StaticData {
+    serde_json::from_value(json!({
+      "hostname": "box",
+      "os_version": "12",
+      "os_name": "Debian",
+      "cpu_cores": 2,
+      "public_ip": null,
+      "isp": null,
+      "country": null,
+      "city": null,
+      "timezone": null,
+      "cpu_model": "Ryzen 5",
+      "cpu_threads": 4,
+      "total_mem": 8000000,
+      "reporter_version": "1.0.0",
+    }))
+    .unwrap()
+  }
+
+  fn dynamic_data(timestamp: u64) -> DynamicData {
+    serde_json::from_value(json!({
+      "cpu": { "usage": [12], "freq": [3600] },
+      "ram": { "used": 2000, "total": 8000 },
+      "process_count": 120,
+      "swap": { "used": 0, "total": 1000 },
+      "disks": [],
+      "temps": null,
+      "network": [],
+      "host_uptime": 90500,
+      "reporter_uptime": 1000,
+      "timestamp": timestamp,
+    }))
+    .unwrap()
+  }
+
+  fn records(path: &str) -> Vec<Value> {
+    fs::read_to_string(path)
+      .unwrap()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect()
+  }
+
+  /// The timestamps of the dynamic data in every file, newest file first
+  fn timestamps(path: &str, max_files: usize) -> Vec<Vec<u64>> {
+    std::iter::once(path.to_string())
+      .chain((1..=max_files).map(|index| format!("{}.{}", path, index)))
+      .filter(|path| Path::new(path).exists())
+      .map(|path| {
+        records(&path)
+          .iter()
+          .filter(|record| record["type"] == "dynamic-data")
+          .map(|record| record["timestamp"].as_u64().unwrap())
+          .collect()
+      })
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn rotates_and_keeps_max_files() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("out.jsonl").display().to_string();
+    let line_len = JsonLinesRecord::dynamic_data(&dynamic_data(1))
+      .to_line()
+      .unwrap()
+      .len() as u64;
+    // Two samples fit in a file
+    let mut sink = FileSink::new(&path, line_len * 2, 2).unwrap();
+
+    for timestamp in 1..=7 {
+      sink
+        .send_dynamic_data(&dynamic_data(timestamp))
+        .await
+        .unwrap();
+    }
+    assert_eq!(timestamps(&path, 3), vec![vec![7], vec![5, 6], vec![3, 4]]);
+    assert!(!Path::new(&format!("{}.3", path)).exists());
+  }
+
+  #[tokio::test]
+  async fn every_file_starts_with_the_original_static_data() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("out.jsonl").display().to_string();
+    let mut sink = FileSink::new(&path, 1, 5).unwrap();
+
+    sink.send_static_data(&static_data()).await.unwrap();
+    let static_record = records(&path).remove(0);
+    assert_eq!(static_record["type"], "static-data");
+
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    sink.send_dynamic_data(&dynamic_data(1)).await.unwrap();
+    sink.send_dynamic_data(&dynamic_data(2)).await.unwrap();
+
+    for path in [path.clone(), format!("{}.1", path)] {
+      let records = records(&path);
+      assert_eq!(records[0], static_record, "{}", path);
+      assert_eq!(records[1]["type"], "dynamic-data", "{}", path);
+    }
+  }
+}
@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::types::{DynamicData, StaticData};
+
+use super::Sink;
+
+/// Posts every snapshot to an HTTP endpoint using the same
+/// `{ "e": <event>, "d": <data> }` envelope as the websocket messages
+pub struct HttpSink {
+  pub url: String,
+  client: reqwest::Client,
+}
+
+impl HttpSink {
+  pub fn new(url: &str, headers: &HashMap<String, String>) -> Result<Self> {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers {
+      header_map.insert(
+        HeaderName::from_bytes(name.as_bytes())?,
+        HeaderValue::from_str(value)?,
+      );
+    }
+
+    let client = reqwest::Client::builder()
+      .default_headers(header_map)
+      .timeout(Duration::from_secs(10))
+      .build()?;
+
+    Ok(Self {
+      url: url.to_string(),
+      client,
+    })
+  }
+
+  async fn post(&self, event: &str, data: &impl Serialize) -> Result<()> {
+    let response = self
+      .client
+      .post(&self.url)
+      .json(&json!({ "e": event, "d": data }))
+      .send()
+      .await?;
+
+    if !response.status().is_success() {
+      return Err(anyhow!("Unexpected response: {}", response.status()));
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Sink for HttpSink {
+  fn name(&self) -> String {
+    format!("http ({})", self.url)
+  }
+
+  async fn send_static_data(&mut self, static_data: &StaticData) -> Result<()> {
+    self.post("static-data", static_data).await
+  }
+
+  async fn send_dynamic_data(&mut self, dynamic_data: &DynamicData) -> Result<()> {
+    self.post("dynamic-data", dynamic_data).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::Value;
+  use std::io::{BufRead, BufReader, Read, Write};
+  use std::net::TcpListener;
+  use std::sync::mpsc;
+
+  /// An endpoint that answers every request with `status` and hands over its head and body
+  fn endpoint(status: &'static str) -> (String, mpsc::Receiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/ingest", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+          if reader.read_line(&mut head).unwrap() == 0 {
+            break;
+          }
+        }
+        let length = head
+          .lines()
+          .find_map(|line| {
+            line
+              .to_lowercase()
+              .strip_prefix("content-length: ")
+              .map(str::to_string)
+          })
+          .and_then(|length| length.trim().parse().ok())
+          .unwrap_or(0);
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        write!(
+          stream,
+          "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+          status
+        )
+        .unwrap();
+        let _ = sender.send((head, String::from_utf8(body).unwrap()));
+      }
+    });
+    (url, receiver)
+  }
+
+  fn dynamic_data() -> DynamicData {
+    serde_json::from_value(json!({
+      "cpu": { "usage": [12], "freq": [3600] },
+      "ram": { "used": 2000, "total": 8000 },
+      "process_count": 120,
+      "swap": { "used": 0, "total": 1000 },
+      "disks": [],
+      "temps": null,
+      "network": [],
+      "host_uptime": 90500,
+      "reporter_uptime": 1000,
+      "timestamp": 1700000000000u64,
+    }))
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn posts_the_event_with_the_configured_headers() {
+    let (url, requests) = endpoint("200 OK");
+    let headers = [
+      ("Authorization".to_string(), "Bearer key".to_string()),
+      ("X-Source".to_string(), "reporter".to_string()),
+    ]
+    .into();
+    let mut sink = HttpSink::new(&url, &headers).unwrap();
+
+    sink.send_dynamic_data(&dynamic_data()).await.unwrap();
+    let (head, body) = requests.recv().unwrap();
+    let head = head.to_lowercase();
+    assert!(head.starts_with("post /ingest http/1.1\r\n"), "{}", head);
+    assert!(
+      head.contains("\r\nauthorization: bearer key\r\n"),
+      "{}",
+      head
+    );
+    assert!(head.contains("\r\nx-source: reporter\r\n"), "{}", head);
+    assert!(
+      head.contains("\r\ncontent-type: application/json\r\n"),
+      "{}",
+      head
+    );
+
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["e"], "dynamic-data");
+    assert_eq!(body["d"]["timestamp"], 1700000000000u64);
+    assert_eq!(body["d"]["cpu"]["usage"], json!([12]));
+  }
+
+  #[tokio::test]
+  async fn error_responses_fail_the_send() {
+    let (url, _requests) = endpoint("503 Service Unavailable");
+    let mut sink = HttpSink::new(&url, &HashMap::new()).unwrap();
+    let error = sink.send_dynamic_data(&dynamic_data()).await.unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "Unexpected response: 503 Service Unavailable"
+    );
+  }
+
+  #[test]
+  fn invalid_headers_are_refused() {
+    let headers = [("Bad Name".to_string(), "value".to_string())].into();
+    assert!(HttpSink::new("http://127.0.0.1/", &headers).is_err());
+    let headers = [("X-Token".to_string(), "line\nbreak".to_string())].into();
+    assert!(HttpSink::new("http://127.0.0.1/", &headers).is_err());
+  }
+}
@@ -0,0 +1,168 @@
+mod file;
+mod http;
+mod prometheus;
+mod stdout;
+mod websocket;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::config_manager::{ConfigManager, REDACTED};
+use crate::types::{DynamicData, StaticData};
+use crate::util::unix_millis;
+
+pub use self::file::FileSink;
+pub use self::http::HttpSink;
+pub use self::prometheus::PrometheusSink;
+pub use self::stdout::StdoutSink;
+pub use self::websocket::WebsocketSink;
+
+/// A destination the reporter fans every collected snapshot out to
+#[async_trait]
+pub trait Sink: Send {
+  /// Name of the sink used when logging its errors
+  fn name(&self) -> String;
+
+  /// Called once at startup with the data that can't change in runtime
+  async fn send_static_data(&mut self, static_data: &StaticData) -> Result<()>;
+
+  /// Called with every new sample of the dynamic data
+  async fn send_dynamic_data(&mut self, dynamic_data: &DynamicData) -> Result<()>;
+}
+
+/// The sinks that can be set up in the `sinks` list of the config
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum SinkConfig {
+  /// The Opal backend
+  Websocket,
+  /// JSON lines printed to stdout
+  Stdout,
+  /// JSON lines appended to a file that's rotated once it grows over `max_bytes`
+  File {
+    path: String,
+    #[serde(default = "default_file_max_bytes")]
+    max_bytes: u64,
+    #[serde(default = "default_file_max_files")]
+    max_files: usize,
+  },
+  /// JSON posted to an HTTP endpoint
+  Http {
+    url: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+  },
+  /// Prometheus metrics served on `http://<address>/metrics`
+  Prometheus { address: String },
+}
+
+pub fn default_file_max_bytes() -> u64 {
+  64 * 1024 * 1024
+}
+
+pub fn default_file_max_files() -> usize {
+  5
+}
+
+impl SinkConfig {
+  /// Creates the sink described by this config
+  pub async fn create(&self, config_manager: &ConfigManager) -> Result<Box<dyn Sink>> {
+    Ok(match self {
+      SinkConfig::Websocket => Box::new(WebsocketSink::new(config_manager)?),
+      SinkConfig::Stdout => Box::new(StdoutSink::new()),
+      SinkConfig::File {
+        path,
+        max_bytes,
+        max_files,
+      } => Box::new(FileSink::new(path, *max_bytes, *max_files)?),
+      SinkConfig::Http { url, headers } => Box::new(HttpSink::new(url, headers)?),
+      SinkConfig::Prometheus { address } => Box::new(PrometheusSink::start(address).await?),
+    })
+  }
+
+  /// A copy with the HTTP header values redacted since they usually hold credentials
+  pub fn redacted(&self) -> SinkConfig {
+    match self {
+      SinkConfig::Http { url, headers } => SinkConfig::Http {
+        url: url.clone(),
+        headers: headers
+          .keys()
+          .map(|name| (name.clone(), REDACTED.to_string()))
+          .collect(),
+      },
+      sink_config => sink_config.clone(),
+    }
+  }
+}
+
+/// A line written by the stdout and file sinks, tagged with the same
+/// event names the websocket messages use so the lines are easy to filter
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum JsonLinesRecord<'a> {
+  StaticData {
+    timestamp: u64,
+    data: &'a StaticData,
+  },
+  DynamicData {
+    timestamp: u64,
+    data: &'a DynamicData,
+  },
+}
+
+impl<'a> JsonLinesRecord<'a> {
+  pub fn static_data(data: &'a StaticData) -> Self {
+    JsonLinesRecord::StaticData {
+      timestamp: unix_millis(),
+      data,
+    }
+  }
+
+  pub fn dynamic_data(data: &'a DynamicData) -> Self {
+    JsonLinesRecord::DynamicData {
+      timestamp: data.timestamp,
+      data,
+    }
+  }
+
+  /// Serializes the record into a single line including the newline
+  pub fn to_line(&self) -> Result<String> {
+    Ok(format!("{}\n", serde_json::to_string(self)?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::{json, Value};
+
+  #[test]
+  fn json_lines_records_are_tagged_single_lines() {
+    let dynamic_data: DynamicData = serde_json::from_value(json!({
+      "cpu": { "usage": [12], "freq": [3600] },
+      "ram": { "used": 2000, "total": 8000 },
+      "process_count": 120,
+      "swap": { "used": 0, "total": 1000 },
+      "disks": [],
+      "temps": null,
+      "network": [],
+      "host_uptime": 90500,
+      "reporter_uptime": 1000,
+      "timestamp": 1700000000000u64,
+    }))
+    .unwrap();
+
+    let line = JsonLinesRecord::dynamic_data(&dynamic_data)
+      .to_line()
+      .unwrap();
+    assert_eq!(line.matches('\n').count(), 1);
+    assert!(line.ends_with('\n'));
+
+    let record: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(record["type"], "dynamic-data");
+    assert_eq!(record["timestamp"], 1700000000000u64);
+    assert_eq!(record["data"]["ram"]["used"], 2000);
+  }
+}
@@ -0,0 +1,1115 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::types::{
+  CgroupStats, ContainerStats, DiskIoStats, DiskStats, DynamicData, GPUStats,
+  NetworkInterfaceStats, PressureAverages, ProcessStats, StaticData,
+};
+use crate::util::arcmutex;
+
+use super::Sink;
+
+/// How long a client can take to send its request before the connection is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The latest data that gets rendered on every scrape
+#[derive(Debug, Default)]
+struct ExporterState {
+  static_data: Option<StaticData>,
+  dynamic_data: Option<DynamicData>,
+}
+
+/// Serves the collected data on `/metrics` in the Prometheus text exposition format
+#[derive(Debug, Clone)]
+pub struct PrometheusSink {
+  pub address: String,
+  state: Arc<Mutex<ExporterState>>,
+}
+
+impl PrometheusSink {
+  /// Binds the listener and starts serving scrapes in the background
+  pub async fn start(address: &str) -> Result<Self> {
+    let listener = TcpListener::bind(address).await?;
+    let state = arcmutex(ExporterState::default());
+
+    eprintln!("Serving Prometheus metrics on http://{}/metrics", address);
+
+    let task_state = state.clone();
+    tokio::spawn(async move {
+      loop {
+        match listener.accept().await {
+          Ok((stream, _)) => {
+            let state = task_state.clone();
+            tokio::spawn(async move {
+              if let Err(e) = handle_connection(stream, state, REQUEST_TIMEOUT).await {
+                eprintln!("Prometheus exporter error: {}", e);
+              }
+            });
+          }
+          Err(e) => eprintln!("Prometheus exporter could not accept a connection: {}", e),
+        }
+      }
+    });
+
+    Ok(Self {
+      address: address.to_string(),
+      state,
+    })
+  }
+}
+
+#[async_trait]
+impl Sink for PrometheusSink {
+  fn name(&self) -> String {
+    format!("prometheus ({})", self.address)
+  }
+
+  async fn send_static_data(&mut self, static_data: &StaticData) -> Result<()> {
+    self.state.lock().static_data = Some(static_data.clone());
+    Ok(())
+  }
+
+  async fn send_dynamic_data(&mut self, dynamic_data: &DynamicData) -> Result<()> {
+    self.state.lock().dynamic_data = Some(dynamic_data.clone());
+    Ok(())
+  }
+}
+
+async fn handle_connection(
+  mut stream: TcpStream,
+  state: Arc<Mutex<ExporterState>>,
+  timeout: Duration,
+) -> Result<()> {
+  // We only care about the request line so the headers are read up to a sane limit and dropped
+  let mut request = Vec::new();
+  let mut buffer = [0u8; 1024];
+  let read_request = async {
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+      let read = stream.read(&mut buffer).await?;
+      if read == 0 {
+        break;
+      }
+      request.extend_from_slice(&buffer[..read]);
+    }
+    Ok::<_, std::io::Error>(())
+  };
+  // An idle client like a browser's preconnect is dropped without an answer
+  match tokio::time::timeout(timeout, read_request).await {
+    Ok(read) => read?,
+    Err(_) => return Ok(()),
+  }
+
+  let request = String::from_utf8_lossy(&request);
+  let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+  let (method, path) = (request_line.next(), request_line.next());
+
+  let (status, body) = match (method, path) {
+    (Some("GET"), Some("/metrics")) => {
+      let state = state.lock();
+      (
+        "200 OK",
+        render_metrics(state.static_data.as_ref(), state.dynamic_data.as_ref()),
+      )
+    }
+    (Some("GET"), _) => (
+      "404 Not Found",
+      "Not found, metrics are on /metrics\n".to_string(),
+    ),
+    _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+  };
+
+  let response = format!(
+    "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status,
+    body.len(),
+    body
+  );
+  stream.write_all(response.as_bytes()).await?;
+  stream.shutdown().await?;
+  Ok(())
+}
+
+/// Helper for writing metric families in the text exposition format
+struct MetricsWriter {
+  output: String,
+}
+
+/// Reads one of the cumulative counters of an interface
+type NicCounter = fn(&NetworkInterfaceStats) -> u64;
+
+/// Reads one of the values of a GPU
+type GpuValue = fn(&GPUStats) -> Option<f64>;
+
+/// Reads one of the usages of a top process
+type ProcessValue = fn(&ProcessStats) -> f64;
+
+/// Reads one of the values of a container, `None` while it isn't running
+type ContainerValue = fn(&ContainerStats) -> Option<f64>;
+
+/// Reads one of the values of a cgroup, `None` when it has no such file or limit
+type CgroupValue = fn(&CgroupStats) -> Option<f64>;
+
+/// Reads one of the inode counts of a filesystem
+type DiskInodes = fn(&DiskStats) -> Option<u64>;
+
+/// Reads one of the I/O rates of a block device
+type DiskIoRate = fn(&DiskIoStats) -> f64;
+
+impl MetricsWriter {
+  fn family(&mut self, name: &str, metric_type: &str, help: &str) -> &mut Self {
+    let _ = writeln!(self.output, "# HELP opal_{} {}", name, help);
+    let _ = writeln!(self.output, "# TYPE opal_{} {}", name, metric_type);
+    self
+  }
+
+  fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Into<f64>) -> &mut Self {
+    let _ = write!(self.output, "opal_{}", name);
+    if !labels.is_empty() {
+      let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+        .collect();
+      let _ = write!(self.output, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(self.output, " {}", value.into());
+    self
+  }
+}
+
+fn escape_label_value(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+/// Renders the data as Prometheus metrics, memory is reported by sysinfo in kilobytes
+/// and uptimes are in milliseconds so they are converted to bytes and seconds here
+pub fn render_metrics(
+  static_data: Option<&StaticData>,
+  dynamic_data: Option<&DynamicData>,
+) -> String {
+  let mut writer = MetricsWriter {
+    output: String::new(),
+  };
+
+  if let Some(sd) = static_data {
+    writer
+      .family(
+        "info",
+        "gauge",
+        "Information about the host and the reporter",
+      )
+      .sample(
+        "info",
+        &[
+          ("hostname", sd.hostname.as_deref().unwrap_or("")),
+          ("os_name", sd.os_name.as_deref().unwrap_or("")),
+          ("os_version", sd.os_version.as_deref().unwrap_or("")),
+          ("cpu_model", &sd.cpu_model),
+          ("reporter_version", &sd.reporter_version),
+        ],
+        1,
+      );
+    writer
+      .family("cpu_threads", "gauge", "Number of logical processors")
+      .sample("cpu_threads", &[], sd.cpu_threads as f64);
+    if let Some(cpu_cores) = sd.cpu_cores {
+      writer
+        .family("cpu_cores", "gauge", "Number of physical cores")
+        .sample("cpu_cores", &[], cpu_cores as f64);
+    }
+  }
+
+  let dd = match dynamic_data {
+    Some(dd) => dd,
+    None => return writer.output,
+  };
+
+  writer.family(
+    "cpu_usage_percent",
+    "gauge",
+    "CPU usage per logical processor",
+  );
+  for (core, usage) in dd.cpu.usage.iter().enumerate() {
+    writer.sample("cpu_usage_percent", &[("core", &core.to_string())], *usage);
+  }
+  writer.family(
+    "cpu_frequency_mhz",
+    "gauge",
+    "CPU frequency per logical processor",
+  );
+  for (core, freq) in dd.cpu.freq.iter().enumerate() {
+    writer.sample("cpu_frequency_mhz", &[("core", &core.to_string())], *freq);
+  }
+
+  writer
+    .family("memory_used_bytes", "gauge", "Used memory")
+    .sample("memory_used_bytes", &[], (dd.ram.used * 1000) as f64);
+  writer
+    .family("memory_total_bytes", "gauge", "Total memory")
+    .sample("memory_total_bytes", &[], (dd.ram.total * 1000) as f64);
+  writer
+    .family("swap_used_bytes", "gauge", "Used swap")
+    .sample("swap_used_bytes", &[], (dd.swap.used * 1000) as f64);
+  writer
+    .family("swap_total_bytes", "gauge", "Total swap")
+    .sample("swap_total_bytes", &[], (dd.swap.total * 1000) as f64);
+
+  writer.family("disk_used_bytes", "gauge", "Used space of the filesystem");
+  for disk in &dd.disks {
+    writer.sample(
+      "disk_used_bytes",
+      &[
+        ("name", &disk.name),
+        ("mount", &disk.mount),
+        ("fs", &disk.fs),
+      ],
+      disk.used as f64,
+    );
+  }
+  writer.family("disk_total_bytes", "gauge", "Total space of the filesystem");
+  for disk in &dd.disks {
+    writer.sample(
+      "disk_total_bytes",
+      &[
+        ("name", &disk.name),
+        ("mount", &disk.mount),
+        ("fs", &disk.fs),
+      ],
+      disk.total as f64,
+    );
+  }
+  let inodes: [(&str, &str, DiskInodes); 2] = [
+    (
+      "disk_inodes_total",
+      "Inodes of the filesystem, missing when it allocates them as it goes",
+      |disk| disk.inodes_total,
+    ),
+    (
+      "disk_inodes_used",
+      "Used inodes of the filesystem",
+      |disk| disk.inodes_used,
+    ),
+  ];
+  for (name, help, value) in inodes {
+    writer.family(name, "gauge", help);
+    for disk in &dd.disks {
+      if let Some(value) = value(disk) {
+        writer.sample(
+          name,
+          &[
+            ("name", &disk.name),
+            ("mount", &disk.mount),
+            ("fs", &disk.fs),
+          ],
+          value as f64,
+        );
+      }
+    }
+  }
+  writer.family(
+    "disk_read_only",
+    "gauge",
+    "1 when the filesystem is mounted read-only",
+  );
+  for disk in &dd.disks {
+    writer.sample(
+      "disk_read_only",
+      &[
+        ("name", &disk.name),
+        ("mount", &disk.mount),
+        ("fs", &disk.fs),
+      ],
+      disk.read_only as u8,
+    );
+  }
+
+  // Filesystems on the same block device share its I/O, it's only reported once per device
+  let mut devices: Vec<(&str, &DiskIoStats)> = Vec::new();
+  for disk in &dd.disks {
+    if let Some(io) = disk.io.as_ref() {
+      if !devices.iter().any(|(name, _)| *name == disk.name) {
+        devices.push((&disk.name, io));
+      }
+    }
+  }
+  let rates: [(&str, &str, DiskIoRate); 6] = [
+    (
+      "disk_read_bytes_per_second",
+      "Bytes per second read from the device since the previous collection",
+      |io| io.read_bytes_per_second as f64,
+    ),
+    (
+      "disk_write_bytes_per_second",
+      "Bytes per second written to the device since the previous collection",
+      |io| io.write_bytes_per_second as f64,
+    ),
+    (
+      "disk_reads_per_second",
+      "Completed reads per second since the previous collection",
+      |io| io.read_iops,
+    ),
+    (
+      "disk_writes_per_second",
+      "Completed writes per second since the previous collection",
+      |io| io.write_iops,
+    ),
+    (
+      "disk_io_latency_milliseconds",
+      "Average time a read or write took since the previous collection",
+      |io| io.latency_ms,
+    ),
+    (
+      "disk_utilization_percent",
+      "Percentage of the time the device was busy since the previous collection",
+      |io| io.utilization,
+    ),
+  ];
+  for (name, help, value) in rates {
+    writer.family(name, "gauge", help);
+    for (device, io) in &devices {
+      writer.sample(name, &[("name", device)], value(io));
+    }
+  }
+
+  writer.family(
+    "network_receive_bits_per_second",
+    "gauge",
+    "Bits per second received by the interface since the previous collection",
+  );
+  for nic in &dd.network {
+    writer.sample(
+      "network_receive_bits_per_second",
+      &[("interface", &nic.n)],
+      nic.rx as f64,
+    );
+  }
+  writer.family(
+    "network_transmit_bits_per_second",
+    "gauge",
+    "Bits per second transmitted by the interface since the previous collection",
+  );
+  for nic in &dd.network {
+    writer.sample(
+      "network_transmit_bits_per_second",
+      &[("interface", &nic.n)],
+      nic.tx as f64,
+    );
+  }
+  let counters: [(&str, &str, NicCounter); 8] = [
+    (
+      "network_receive_bytes_total",
+      "Bytes received by the interface",
+      |nic| nic.rx_bytes,
+    ),
+    (
+      "network_transmit_bytes_total",
+      "Bytes transmitted by the interface",
+      |nic| nic.tx_bytes,
+    ),
+    (
+      "network_receive_packets_total",
+      "Packets received by the interface",
+      |nic| nic.rx_packets,
+    ),
+    (
+      "network_transmit_packets_total",
+      "Packets transmitted by the interface",
+      |nic| nic.tx_packets,
+    ),
+    (
+      "network_receive_errors_total",
+      "Receive errors of the interface",
+      |nic| nic.rx_errors,
+    ),
+    (
+      "network_transmit_errors_total",
+      "Transmit errors of the interface",
+      |nic| nic.tx_errors,
+    ),
+    (
+      "network_receive_dropped_total",
+      "Received packets dropped by the interface",
+      |nic| nic.rx_dropped,
+    ),
+    (
+      "network_transmit_dropped_total",
+      "Transmitted packets dropped by the interface",
+      |nic| nic.tx_dropped,
+    ),
+  ];
+  for (name, help, value) in counters {
+    writer.family(name, "counter", help);
+    for nic in &dd.network {
+      writer.sample(name, &[("interface", &nic.n)], value(nic) as f64);
+    }
+  }
+  writer.family(
+    "network_link_speed_mbps",
+    "gauge",
+    "Link speed of the interface, missing when it's unknown",
+  );
+  for nic in &dd.network {
+    if let Some(speed) = nic.s {
+      writer.sample("network_link_speed_mbps", &[("interface", &nic.n)], speed);
+    }
+  }
+  writer.family(
+    "network_info",
+    "gauge",
+    "Always 1, the labels describe the interface",
+  );
+  for nic in &dd.network {
+    let r#type = format!("{:?}", nic.r#type).to_lowercase();
+    writer.sample(
+      "network_info",
+      &[
+        ("interface", &nic.n),
+        ("type", &r#type),
+        ("operstate", nic.operstate.as_deref().unwrap_or_default()),
+        ("mac", nic.mac.as_deref().unwrap_or_default()),
+        ("duplex", nic.duplex.as_deref().unwrap_or_default()),
+        ("alias", nic.alias.as_deref().unwrap_or_default()),
+      ],
+      1.0,
+    );
+  }
+  writer.family(
+    "network_mtu_bytes",
+    "gauge",
+    "Maximum transmission unit of the interface",
+  );
+  for nic in dd.network.iter() {
+    if let Some(mtu) = nic.mtu {
+      writer.sample("network_mtu_bytes", &[("interface", &nic.n)], mtu);
+    }
+  }
+
+  if let Some(temps) = dd.temps.as_ref() {
+    writer.family(
+      "temperature_celsius",
+      "gauge",
+      "Temperature of the component",
+    );
+    // Sensors can share a label, like the `Core 0` of every socket, which would make their series
+    // collide so they're told apart by how many sensors with the same label came before them
+    for (i, temp) in temps.iter().enumerate() {
+      let sensor_index = temps[..i]
+        .iter()
+        .filter(|other| other.label == temp.label)
+        .count()
+        .to_string();
+      writer.sample(
+        "temperature_celsius",
+        &[("label", &temp.label), ("sensor_index", &sensor_index)],
+        temp.value,
+      );
+    }
+  }
+
+  let gpu_values: [(&str, &str, GpuValue); 13] = [
+    ("gpu_usage_percent", "GPU utilization", |gpu| {
+      gpu.gpu_usage.map(f64::from)
+    }),
+    ("gpu_power_milliwatts", "GPU power draw", |gpu| {
+      gpu.power_usage.map(f64::from)
+    }),
+    ("gpu_power_limit_milliwatts", "GPU power limit", |gpu| {
+      gpu.power_limit.map(f64::from)
+    }),
+    ("gpu_memory_used_bytes", "Used GPU memory", |gpu| {
+      gpu.memory_used.map(|bytes| bytes as f64)
+    }),
+    ("gpu_memory_total_bytes", "Total GPU memory", |gpu| {
+      gpu.memory_total.map(|bytes| bytes as f64)
+    }),
+    (
+      "gpu_memory_usage_percent",
+      "Percentage of the time the GPU memory was being read or written",
+      |gpu| gpu.memory_usage.map(f64::from),
+    ),
+    ("gpu_temperature_celsius", "GPU temperature", |gpu| {
+      gpu.temperature.map(f64::from)
+    }),
+    (
+      "gpu_sm_clock_mhz",
+      "GPU streaming multiprocessor clock",
+      |gpu| gpu.sm_clock.map(f64::from),
+    ),
+    ("gpu_memory_clock_mhz", "GPU memory clock", |gpu| {
+      gpu.memory_clock.map(f64::from)
+    }),
+    ("gpu_fan_speed_percent", "GPU fan speed", |gpu| {
+      gpu.fan_speed.map(f64::from)
+    }),
+    (
+      "gpu_throttled",
+      "1 when the GPU clocks are held down for any reason but being idle",
+      |gpu| {
+        gpu
+          .throttle_reasons
+          .as_ref()
+          .map(|reasons| reasons.iter().any(|reason| reason != "gpu_idle") as u8 as f64)
+      },
+    ),
+    (
+      "gpu_ecc_errors_corrected",
+      "Corrected ECC errors since the driver was loaded",
+      |gpu| gpu.ecc_errors_corrected.map(|errors| errors as f64),
+    ),
+    (
+      "gpu_ecc_errors_uncorrected",
+      "Uncorrected ECC errors since the driver was loaded",
+      |gpu| gpu.ecc_errors_uncorrected.map(|errors| errors as f64),
+    ),
+  ];
+  for (name, help, value) in gpu_values {
+    if dd.gpus.iter().all(|gpu| value(gpu).is_none()) {
+      continue;
+    }
+    writer.family(name, "gauge", help);
+    for gpu in &dd.gpus {
+      if let Some(value) = value(gpu) {
+        let index = gpu.index.to_string();
+        writer.sample(
+          name,
+          &[
+            ("index", &index),
+            ("uuid", gpu.uuid.as_deref().unwrap_or_default()),
+            ("name", &gpu.name),
+            ("brand", &gpu.brand),
+          ],
+          value,
+        );
+      }
+    }
+  }
+  if dd
+    .gpus
+    .iter()
+    .any(|gpu| gpu.processes.iter().flatten().next().is_some())
+  {
+    writer.family(
+      "gpu_process_memory_used_bytes",
+      "gauge",
+      "GPU memory used by a process",
+    );
+    for gpu in &dd.gpus {
+      let index = gpu.index.to_string();
+      for process in gpu.processes.iter().flatten() {
+        if let Some(memory_used) = process.memory_used {
+          let pid = process.pid.to_string();
+          writer.sample(
+            "gpu_process_memory_used_bytes",
+            &[
+              ("index", &index),
+              ("uuid", gpu.uuid.as_deref().unwrap_or_default()),
+              ("pid", &pid),
+              ("process", process.name.as_deref().unwrap_or_default()),
+            ],
+            memory_used as f64,
+          );
+        }
+      }
+    }
+  }
+
+  writer
+    .family("process_count", "gauge", "Number of running processes")
+    .sample("process_count", &[], dd.process_count);
+  if !dd.top_processes.is_empty() {
+    let families: [(&str, &str, ProcessValue); 4] = [
+      (
+        "process_cpu_usage_percent",
+        "CPU usage of a top process",
+        |process| process.cpu_usage as f64,
+      ),
+      (
+        "process_memory_bytes",
+        "Memory used by a top process",
+        |process| process.memory as f64,
+      ),
+      (
+        "process_disk_read_bytes_per_second",
+        "Disk read throughput of a top process",
+        |process| process.disk_read_bytes_per_second as f64,
+      ),
+      (
+        "process_disk_write_bytes_per_second",
+        "Disk write throughput of a top process",
+        |process| process.disk_write_bytes_per_second as f64,
+      ),
+    ];
+    for (name, help, value) in families {
+      writer.family(name, "gauge", help);
+      for process in &dd.top_processes {
+        let pid = process.pid.to_string();
+        writer.sample(
+          name,
+          &[("pid", &pid), ("process", &process.name)],
+          value(process),
+        );
+      }
+    }
+  }
+  if let Some(systemd) = dd.systemd.as_ref() {
+    writer
+      .family(
+        "systemd_failed_units",
+        "gauge",
+        "Number of failed systemd units",
+      )
+      .sample("systemd_failed_units", &[], systemd.failed_units);
+    if !systemd.units.is_empty() {
+      writer.family(
+        "systemd_unit_active",
+        "gauge",
+        "Whether a systemd unit is active, labelled with its state",
+      );
+      for unit in &systemd.units {
+        writer.sample(
+          "systemd_unit_active",
+          &[
+            ("unit", &unit.name),
+            ("state", &unit.active_state),
+            ("sub_state", &unit.sub_state),
+          ],
+          if unit.active_state == "active" { 1 } else { 0 },
+        );
+      }
+    }
+    if systemd.units.iter().any(|unit| unit.restarts.is_some()) {
+      writer.family(
+        "systemd_unit_restarts_total",
+        "counter",
+        "Times systemd restarted a service",
+      );
+      for unit in &systemd.units {
+        if let Some(restarts) = unit.restarts {
+          writer.sample(
+            "systemd_unit_restarts_total",
+            &[("unit", &unit.name)],
+            restarts,
+          );
+        }
+      }
+    }
+    if systemd.units.iter().any(|unit| unit.since.is_some()) {
+      writer.family(
+        "systemd_unit_state_change_timestamp_seconds",
+        "gauge",
+        "When a systemd unit entered its current state",
+      );
+      for unit in &systemd.units {
+        if let Some(since) = unit.since {
+          writer.sample(
+            "systemd_unit_state_change_timestamp_seconds",
+            &[("unit", &unit.name)],
+            since as f64,
+          );
+        }
+      }
+    }
+  }
+  if !dd.containers.is_empty() {
+    writer.family(
+      "container_info",
+      "gauge",
+      "Always 1, labelled with the image, state and health of a container",
+    );
+    for container in &dd.containers {
+      writer.sample(
+        "container_info",
+        &[
+          ("id", &container.id),
+          ("name", &container.name),
+          ("image", &container.image),
+          ("runtime", &container.runtime),
+          ("state", &container.state),
+          ("health", container.health.as_deref().unwrap_or_default()),
+        ],
+        1,
+      );
+    }
+    let container_values: [(&str, &str, &str, ContainerValue); 8] = [
+      (
+        "container_restarts_total",
+        "counter",
+        "Times the runtime restarted a container",
+        |container| Some(container.restart_count as f64),
+      ),
+      (
+        "container_cpu_usage_percent",
+        "gauge",
+        "CPU usage of a container as a percentage of a single core",
+        |container| container.cpu_usage,
+      ),
+      (
+        "container_memory_used_bytes",
+        "gauge",
+        "Memory used by a container without the page cache",
+        |container| container.memory_used.map(|bytes| bytes as f64),
+      ),
+      (
+        "container_memory_limit_bytes",
+        "gauge",
+        "Memory limit of a container",
+        |container| container.memory_limit.map(|bytes| bytes as f64),
+      ),
+      (
+        "container_network_receive_bytes_total",
+        "counter",
+        "Bytes received by a container",
+        |container| container.network_rx_bytes.map(|bytes| bytes as f64),
+      ),
+      (
+        "container_network_transmit_bytes_total",
+        "counter",
+        "Bytes transmitted by a container",
+        |container| container.network_tx_bytes.map(|bytes| bytes as f64),
+      ),
+      (
+        "container_network_receive_bits_per_second",
+        "gauge",
+        "Receive throughput of a container since the previous collection",
+        |container| container.network_rx.map(|bits| bits as f64),
+      ),
+      (
+        "container_network_transmit_bits_per_second",
+        "gauge",
+        "Transmit throughput of a container since the previous collection",
+        |container| container.network_tx.map(|bits| bits as f64),
+      ),
+    ];
+    for (name, metric_type, help, value) in container_values {
+      if dd
+        .containers
+        .iter()
+        .all(|container| value(container).is_none())
+      {
+        continue;
+      }
+      writer.family(name, metric_type, help);
+      for container in &dd.containers {
+        if let Some(value) = value(container) {
+          writer.sample(
+            name,
+            &[("id", &container.id), ("name", &container.name)],
+            value,
+          );
+        }
+      }
+    }
+  }
+  if let Some(cgroup) = dd.cgroup.as_ref() {
+    let cgroup_values: [(&str, &str, &str, CgroupValue); 8] = [
+      (
+        "cgroup_memory_current_bytes",
+        "gauge",
+        "Memory used by the reporter's cgroup, the page cache included",
+        |cgroup| cgroup.memory_current.map(|bytes| bytes as f64),
+      ),
+      (
+        "cgroup_memory_max_bytes",
+        "gauge",
+        "Memory limit of the reporter's cgroup",
+        |cgroup| cgroup.memory_max.map(|bytes| bytes as f64),
+      ),
+      (
+        "cgroup_swap_current_bytes",
+        "gauge",
+        "Swap used by the reporter's cgroup",
+        |cgroup| cgroup.swap_current.map(|bytes| bytes as f64),
+      ),
+      (
+        "cgroup_swap_max_bytes",
+        "gauge",
+        "Swap limit of the reporter's cgroup",
+        |cgroup| cgroup.swap_max.map(|bytes| bytes as f64),
+      ),
+      (
+        "cgroup_oom_kills_total",
+        "counter",
+        "Processes of the reporter's cgroup killed by the OOM killer",
+        |cgroup| cgroup.oom_kills.map(|kills| kills as f64),
+      ),
+      (
+        "cgroup_cpu_usage_percent",
+        "gauge",
+        "CPU usage of the reporter's cgroup as a percentage of a single core",
+        |cgroup| cgroup.cpu_usage,
+      ),
+      (
+        "cgroup_cpu_limit_cores",
+        "gauge",
+        "Cores the reporter's cgroup can use at most",
+        |cgroup| cgroup.cpu_limit,
+      ),
+      (
+        "cgroup_cpu_throttled_seconds_total",
+        "counter",
+        "Time the reporter's cgroup was throttled for hitting its CPU limit",
+        |cgroup| {
+          cgroup
+            .cpu_throttled_usec
+            .map(|usec| usec as f64 / 1_000_000.0)
+        },
+      ),
+    ];
+    for (name, metric_type, help, value) in cgroup_values {
+      if let Some(value) = value(cgroup) {
+        writer
+          .family(name, metric_type, help)
+          .sample(name, &[("cgroup", &cgroup.path)], value);
+      }
+    }
+
+    let pressures: Vec<(&str, &str, &PressureAverages)> = [
+      ("cpu", &cgroup.cpu_pressure),
+      ("memory", &cgroup.memory_pressure),
+      ("io", &cgroup.io_pressure),
+    ]
+    .iter()
+    .filter_map(|(resource, pressure)| pressure.as_ref().map(|pressure| (*resource, pressure)))
+    .flat_map(|(resource, pressure)| {
+      std::iter::once((resource, "some", &pressure.some))
+        .chain(pressure.full.as_ref().map(|full| (resource, "full", full)))
+    })
+    .collect();
+    if !pressures.is_empty() {
+      writer.family(
+        "cgroup_pressure_avg10_percent",
+        "gauge",
+        "Percentage of the last 10 seconds tasks of the reporter's cgroup were stalled on a resource",
+      );
+      for (resource, kind, averages) in &pressures {
+        writer.sample(
+          "cgroup_pressure_avg10_percent",
+          &[
+            ("cgroup", &cgroup.path),
+            ("resource", resource),
+            ("kind", kind),
+          ],
+          averages.avg10,
+        );
+      }
+      writer.family(
+        "cgroup_pressure_stalled_seconds_total",
+        "counter",
+        "Time tasks of the reporter's cgroup were stalled on a resource",
+      );
+      for (resource, kind, averages) in &pressures {
+        writer.sample(
+          "cgroup_pressure_stalled_seconds_total",
+          &[
+            ("cgroup", &cgroup.path),
+            ("resource", resource),
+            ("kind", kind),
+          ],
+          averages.total as f64 / 1_000_000.0,
+        );
+      }
+    }
+
+    if cgroup
+      .slices
+      .iter()
+      .any(|slice| slice.memory_current.is_some())
+    {
+      writer.family(
+        "cgroup_slice_memory_current_bytes",
+        "gauge",
+        "Memory used by a top level systemd slice",
+      );
+      for slice in &cgroup.slices {
+        if let Some(memory_current) = slice.memory_current {
+          writer.sample(
+            "cgroup_slice_memory_current_bytes",
+            &[("slice", &slice.name)],
+            memory_current as f64,
+          );
+        }
+      }
+    }
+    if cgroup.slices.iter().any(|slice| slice.cpu_usage.is_some()) {
+      writer.family(
+        "cgroup_slice_cpu_usage_percent",
+        "gauge",
+        "CPU usage of a top level systemd slice as a percentage of a single core",
+      );
+      for slice in &cgroup.slices {
+        if let Some(cpu_usage) = slice.cpu_usage {
+          writer.sample(
+            "cgroup_slice_cpu_usage_percent",
+            &[("slice", &slice.name)],
+            cpu_usage,
+          );
+        }
+      }
+    }
+  }
+  writer
+    .family("host_uptime_seconds", "gauge", "Uptime of the host")
+    .sample("host_uptime_seconds", &[], dd.host_uptime as f64 / 1000.0);
+  writer
+    .family("reporter_uptime_seconds", "gauge", "Uptime of the reporter")
+    .sample(
+      "reporter_uptime_seconds",
+      &[],
+      dd.reporter_uptime as f64 / 1000.0,
+    );
+
+  writer.output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn static_data() -> StaticData {
+    serde_json::from_value(json!({
+      "hostname": "box",
+      "os_version": "12",
+      "os_name": "Debian",
+      "cpu_cores": 2,
+      "public_ip": null,
+      "isp": null,
+      "country": null,
+      "city": null,
+      "timezone": null,
+      "cpu_model": "Ryzen \"5\"",
+      "cpu_threads": 4,
+      "total_mem": 8000000,
+      "reporter_version": "1.0.0",
+    }))
+    .unwrap()
+  }
+
+  fn dynamic_data(temps: serde_json::Value) -> DynamicData {
+    serde_json::from_value(json!({
+      "cpu": { "usage": [12, 30], "freq": [3600, 3400] },
+      "ram": { "used": 2000, "total": 8000 },
+      "process_count": 120,
+      "swap": { "used": 0, "total": 1000 },
+      "disks": [{
+        "name": "nvme0n1p2",
+        "mount": "/media/backup \"old\"",
+        "fs": "ext4",
+        "type": "SSD",
+        "total": 500000000,
+        "used": 125000000,
+        "io": {
+          "read_bytes_per_second": 4096,
+          "write_bytes_per_second": 8192,
+          "read_iops": 1.5,
+          "write_iops": 2.0,
+          "latency_ms": 0.25,
+          "utilization": 3.5,
+        },
+        "inodes_total": 1000,
+        "inodes_used": 250,
+        "inodes_free": 750,
+        "read_only": false,
+      }],
+      "temps": temps,
+      "network": [{
+        "n": "eth0",
+        "tx": 8000,
+        "rx": 16000,
+        "s": 1000.0,
+        "rx_bytes": 1000000,
+        "tx_bytes": 500000,
+        "rx_packets": 900,
+        "tx_packets": 800,
+        "rx_errors": 1,
+        "tx_errors": 0,
+        "rx_dropped": 2,
+        "tx_dropped": 0,
+        "operstate": "up",
+        "mac": "52:54:00:12:34:56",
+        "mtu": 1500,
+        "alias": "uplink\\isp \"a\"",
+        "duplex": "full",
+        "type": "physical",
+      }],
+      "host_uptime": 90500,
+      "reporter_uptime": 1000,
+      "timestamp": 1700000000000u64,
+    }))
+    .unwrap()
+  }
+
+  #[test]
+  fn renders_the_static_data() {
+    assert_eq!(
+      render_metrics(Some(&static_data()), None),
+      r#"# HELP opal_info Information about the host and the reporter
+# TYPE opal_info gauge
+opal_info{hostname="box",os_name="Debian",os_version="12",cpu_model="Ryzen \"5\"",reporter_version="1.0.0"} 1
+# HELP opal_cpu_threads Number of logical processors
+# TYPE opal_cpu_threads gauge
+opal_cpu_threads 4
+# HELP opal_cpu_cores Number of physical cores
+# TYPE opal_cpu_cores gauge
+opal_cpu_cores 2
+"#
+    );
+  }
+
+  #[test]
+  fn renders_the_dynamic_data() {
+    assert_eq!(
+      render_metrics(None, Some(&dynamic_data(json!(null)))),
+      include_str!("testdata/dynamic_data.prom")
+    );
+  }
+
+  #[tokio::test]
+  async fn idle_clients_are_dropped() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap())
+      .await
+      .unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let state = arcmutex(ExporterState::default());
+
+    let started = std::time::Instant::now();
+    handle_connection(stream, state, Duration::from_millis(100))
+      .await
+      .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1));
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert!(response.is_empty());
+  }
+
+  #[test]
+  fn sensors_sharing_a_label_get_their_own_series() {
+    let temps = json!([
+      { "label": "Core 0", "value": 40.0 },
+      { "label": "Core 1", "value": 41.0 },
+      { "label": "Core 0", "value": 50.5 },
+    ]);
+    let output = render_metrics(None, Some(&dynamic_data(temps)));
+    let series: Vec<&str> = output
+      .lines()
+      .filter(|line| line.starts_with("opal_temperature_celsius"))
+      .collect();
+    assert_eq!(
+      series,
+      vec![
+        r#"opal_temperature_celsius{label="Core 0",sensor_index="0"} 40"#,
+        r#"opal_temperature_celsius{label="Core 1",sensor_index="0"} 41"#,
+        r#"opal_temperature_celsius{label="Core 0",sensor_index="1"} 50.5"#,
+      ]
+    );
+  }
+}
@@ -0,0 +1,39 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::io::Write;
+
+use crate::types::{DynamicData, StaticData};
+
+use super::{JsonLinesRecord, Sink};
+
+/// Prints every snapshot as a line of JSON so it can be piped into jq and friends
+pub struct StdoutSink {}
+
+impl StdoutSink {
+  pub fn new() -> Self {
+    Self {}
+  }
+
+  fn write_line(&self, record: JsonLinesRecord) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(record.to_line()?.as_bytes())?;
+    // Flush every line so the consumer isn't left waiting on a full buffer
+    stdout.flush()?;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Sink for StdoutSink {
+  fn name(&self) -> String {
+    "stdout".to_string()
+  }
+
+  async fn send_static_data(&mut self, static_data: &StaticData) -> Result<()> {
+    self.write_line(JsonLinesRecord::static_data(static_data))
+  }
+
+  async fn send_dynamic_data(&mut self, dynamic_data: &DynamicData) -> Result<()> {
+    self.write_line(JsonLinesRecord::dynamic_data(dynamic_data))
+  }
+}
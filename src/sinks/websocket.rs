@@ -0,0 +1,134 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config_manager::ConfigManager;
+use crate::metric_buffer::MetricBuffer;
+use crate::types::{DynamicData, StaticData};
+use crate::websocket_manager::WebsocketManager;
+
+use super::Sink;
+
+/// Where samples are kept while the backend is unreachable, next to the config file
+const BUFFER_FILE_NAME: &str = "buffer.jsonl";
+
+/// How many buffered samples are sent per collection interval
+const BUFFER_DRAIN_BATCH: usize = 500;
+
+/// Sends the data to the Opal backend, buffering it while the backend is unreachable
+pub struct WebsocketSink {
+  pub websocket_manager: WebsocketManager,
+  pub metric_buffer: MetricBuffer,
+}
+
+impl WebsocketSink {
+  /// Sets up the websocket manager, the actual connection is made lazily
+  /// and re-established by the manager whenever it drops
+  pub fn new(config_manager: &ConfigManager) -> Result<Self> {
+    let config = &config_manager.config;
+    let transport = &config.transport;
+    let websocket_url: String =
+      transport
+        .tls
+        .backend_url(true, &config.backend_hostname, "/reporter");
+    let websocket_manager = WebsocketManager::new(
+      &websocket_url,
+      &config.access_token,
+      Duration::from_secs(transport.reconnect_max_delay),
+      transport.tls.connector()?,
+    );
+
+    let metric_buffer = MetricBuffer::new(
+      WebsocketSink::buffer_path(config_manager),
+      transport.buffer_max_bytes,
+      Duration::from_secs(transport.buffer_max_age),
+    )?;
+    if !metric_buffer.is_empty() {
+      eprintln!(
+        "Loaded {} buffered samples from a previous run",
+        metric_buffer.len()
+      );
+    }
+
+    Ok(Self {
+      websocket_manager,
+      metric_buffer,
+    })
+  }
+
+  /// Where the samples are buffered while the backend is unreachable
+  pub fn buffer_path(config_manager: &ConfigManager) -> PathBuf {
+    config_manager.path.with_file_name(BUFFER_FILE_NAME)
+  }
+
+  /// Sends the buffered samples oldest first, a batch at a time so a long
+  /// outage doesn't stall the collection loop for the whole back-fill
+  fn drain_buffer(&mut self) -> Result<()> {
+    let mut sent = 0;
+    while let Some(sample) = self.metric_buffer.front() {
+      if sent >= BUFFER_DRAIN_BATCH || self.websocket_manager.send(sample.clone().into()).is_err() {
+        break;
+      }
+      self.metric_buffer.pop_front();
+      sent += 1;
+    }
+
+    if sent > 0 {
+      self.metric_buffer.persist()?;
+      eprintln!(
+        "Back-filled {} buffered samples, {} left",
+        sent,
+        self.metric_buffer.len()
+      );
+    }
+
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Sink for WebsocketSink {
+  fn name(&self) -> String {
+    format!("websocket ({})", self.websocket_manager.websocket_url)
+  }
+
+  async fn send_static_data(&mut self, static_data: &StaticData) -> Result<()> {
+    // The manager keeps the static data around to replay it after every reconnect
+    self
+      .websocket_manager
+      .set_static_data(static_data.clone().into());
+
+    // Connect eagerly so a bad token or hostname shows up right away,
+    // a failure is logged and retried on the next send
+    let _ = self.websocket_manager.ensure_connected();
+    Ok(())
+  }
+
+  /// Sends the latest dynamic data to the backend, buffering it if the backend
+  /// is unreachable and back-filling the buffered samples once it's reachable again
+  async fn send_dynamic_data(&mut self, dynamic_data: &DynamicData) -> Result<()> {
+    // Connection failures are logged by the manager as its state changes and the
+    // reconnect is attempted on a later send once the backoff delay has passed.
+    // Until the backend accepted the login the samples wait in the buffer so
+    // a rejected login doesn't lose them
+    let authenticated = self.websocket_manager.ensure_connected().is_ok()
+      && self.websocket_manager.is_authenticated();
+    if authenticated && !self.metric_buffer.is_empty() {
+      self.drain_buffer()?;
+    }
+
+    // Anything still in the buffer is older so we queue behind it to keep the order
+    if authenticated
+      && self.metric_buffer.is_empty()
+      && self
+        .websocket_manager
+        .send(dynamic_data.clone().into())
+        .is_ok()
+    {
+      return Ok(());
+    }
+
+    self.metric_buffer.push(dynamic_data.clone())
+  }
+}
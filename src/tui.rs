@@ -0,0 +1,397 @@
+use anyhow::Result;
+use crossterm::cursor::Show;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+  disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{BarChart, Block, Borders, Gauge, Paragraph, Row, Sparkline, Table};
+use ratatui::{Frame, Terminal};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{stdout, Stdout};
+use std::time::{Duration, Instant};
+
+use crate::config_manager::ConfigManager;
+use crate::data_collector::DataCollector;
+use crate::types::{DynamicData, StaticData};
+use crate::util::format_bytes;
+
+/// How many samples of network throughput are kept for the graphs
+const NETWORK_HISTORY: usize = 120;
+
+/// Puts the terminal back the way it was, even if the dashboard bails with an error
+struct TerminalGuard {
+  terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl TerminalGuard {
+  fn new() -> Result<Self> {
+    // Release builds abort on panic so the guard never gets dropped, the hook restores it instead
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+      restore_terminal();
+      previous_hook(info);
+    }));
+
+    enable_raw_mode()?;
+    execute!(stdout(), EnterAlternateScreen)?;
+    let terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+    Ok(Self { terminal })
+  }
+}
+
+impl Drop for TerminalGuard {
+  fn drop(&mut self) {
+    let _ = disable_raw_mode();
+    let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+    let _ = self.terminal.show_cursor();
+  }
+}
+
+/// Leaves raw mode and the alternate screen without a terminal handle, for the panic hook
+fn restore_terminal() {
+  let _ = disable_raw_mode();
+  let _ = execute!(stdout(), LeaveAlternateScreen, Show);
+}
+
+/// Live terminal view of the same data the reporter sends to Opal
+pub struct Dashboard {
+  pub static_data: StaticData,
+  pub dynamic_data: DynamicData,
+  /// Received and transmitted bits per second of every interface, oldest first
+  network_history: BTreeMap<String, (VecDeque<u64>, VecDeque<u64>)>,
+}
+
+impl Dashboard {
+  /// Runs the dashboard until `q`, `Esc` or `Ctrl+C` is pressed
+  pub async fn run(config_path: Option<&str>, interval: Option<f64>) -> Result<()> {
+    let config = ConfigManager::new(config_path)?.config;
+    let interval = interval.unwrap_or(config.collection.interval);
+    let mut data_collector = DataCollector::new(&config)?;
+    let static_data = data_collector.get_statics().await?;
+    let dynamic_data = data_collector.get_all_dynamic_data().await?;
+
+    let mut dashboard = Self {
+      static_data,
+      dynamic_data,
+      network_history: BTreeMap::new(),
+    };
+
+    let mut guard = TerminalGuard::new()?;
+    let interval = Duration::from_secs_f64(interval);
+
+    loop {
+      let start_time = Instant::now();
+      dashboard.dynamic_data = data_collector.get_all_dynamic_data().await?;
+      data_collector.increment_iterator_index();
+      dashboard.update_network_history();
+
+      guard.terminal.draw(|frame| dashboard.draw(frame))?;
+
+      // Wait out the rest of the interval while staying responsive to key presses
+      while let Some(timeout) = interval.checked_sub(start_time.elapsed()) {
+        if !event::poll(timeout)? {
+          break;
+        }
+        if let Event::Key(key) = event::read()? {
+          let quit = key.kind == KeyEventKind::Press
+            && (matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+              || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)));
+          if quit {
+            return Ok(());
+          }
+        }
+      }
+    }
+  }
+
+  fn update_network_history(&mut self) {
+    for nic in &self.dynamic_data.network {
+      let (rx, tx) = self
+        .network_history
+        .entry(nic.n.clone())
+        .or_insert_with(|| (VecDeque::new(), VecDeque::new()));
+      rx.push_back(nic.rx);
+      tx.push_back(nic.tx);
+      if rx.len() > NETWORK_HISTORY {
+        rx.pop_front();
+        tx.pop_front();
+      }
+    }
+
+    // Forget the interfaces that went away
+    let names: Vec<&String> = self.dynamic_data.network.iter().map(|nic| &nic.n).collect();
+    self.network_history.retain(|name, _| names.contains(&name));
+  }
+
+  fn draw(&self, frame: &mut Frame) {
+    let rows = Layout::default()
+      .direction(Direction::Vertical)
+      .constraints([
+        Constraint::Length(3),
+        Constraint::Length(12),
+        Constraint::Min(6),
+        Constraint::Length(1),
+      ])
+      .split(frame.size());
+
+    self.draw_header(frame, rows[0]);
+
+    let middle = Layout::default()
+      .direction(Direction::Horizontal)
+      .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+      .split(rows[1]);
+    self.draw_cpu(frame, middle[0]);
+    self.draw_memory(frame, middle[1]);
+
+    let bottom = Layout::default()
+      .direction(Direction::Horizontal)
+      .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+      .split(rows[2]);
+    self.draw_disks(frame, bottom[0]);
+    self.draw_network(frame, bottom[1]);
+
+    frame.render_widget(
+      Paragraph::new("q: quit").style(Style::default().fg(Color::DarkGray)),
+      rows[3],
+    );
+  }
+
+  fn draw_header(&self, frame: &mut Frame, area: Rect) {
+    let (sd, dd) = (&self.static_data, &self.dynamic_data);
+    let uptime = Duration::from_millis(dd.host_uptime).as_secs();
+    let line = Line::from(vec![
+      Span::styled(
+        sd.hostname.clone().unwrap_or_default(),
+        Style::default().add_modifier(Modifier::BOLD),
+      ),
+      Span::raw(format!(
+        "  {} {}  {}  up {}d {:02}h {:02}m  {} processes",
+        sd.os_name.clone().unwrap_or_default(),
+        sd.os_version.clone().unwrap_or_default(),
+        sd.cpu_model,
+        uptime / 86400,
+        uptime % 86400 / 3600,
+        uptime % 3600 / 60,
+        dd.process_count
+      )),
+    ]);
+    frame.render_widget(
+      Paragraph::new(line).block(
+        Block::default()
+          .borders(Borders::ALL)
+          .title("Opal Connector"),
+      ),
+      area,
+    );
+  }
+
+  fn draw_cpu(&self, frame: &mut Frame, area: Rect) {
+    let labels: Vec<String> = (0..self.dynamic_data.cpu.usage.len())
+      .map(|core| core.to_string())
+      .collect();
+    let data: Vec<(&str, u64)> = labels
+      .iter()
+      .zip(self.dynamic_data.cpu.usage.iter())
+      .map(|(label, usage)| (label.as_str(), *usage as u64))
+      .collect();
+
+    let average = self
+      .dynamic_data
+      .cpu
+      .usage
+      .iter()
+      .map(|usage| *usage as u64)
+      .sum::<u64>()
+      / data.len().max(1) as u64;
+
+    let chart = BarChart::default()
+      .block(
+        Block::default()
+          .borders(Borders::ALL)
+          .title(format!("CPU {}%", average)),
+      )
+      .data(&data)
+      .max(100)
+      .bar_width(3)
+      .bar_style(Style::default().fg(Color::Green))
+      .value_style(Style::default().fg(Color::Black).bg(Color::Green));
+    frame.render_widget(chart, area);
+  }
+
+  fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+    let dd = &self.dynamic_data;
+    let block = Block::default()
+      .borders(Borders::ALL)
+      .title("Memory / GPU / Temperatures");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let rows = Layout::default()
+      .direction(Direction::Vertical)
+      .constraints([
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Min(0),
+      ])
+      .split(inner);
+
+    // sysinfo reports memory in kilobytes
+    frame.render_widget(
+      usage_gauge("RAM", dd.ram.used * 1000, dd.ram.total * 1000, Color::Blue),
+      rows[0],
+    );
+    frame.render_widget(
+      usage_gauge(
+        "Swap",
+        dd.swap.used * 1000,
+        dd.swap.total * 1000,
+        Color::Magenta,
+      ),
+      rows[1],
+    );
+
+    let mut lines = Vec::new();
+    for gpu in &dd.gpus {
+      lines.push(Line::from(format!(
+        "GPU {} {}:{}{}{}{}",
+        gpu.index,
+        gpu.name,
+        gpu
+          .gpu_usage
+          .map(|usage| format!(" {}%", usage))
+          .unwrap_or_default(),
+        gpu
+          .power_usage
+          .map(|milliwatts| format!(" {:.1}W", milliwatts as f64 / 1000.0))
+          .unwrap_or_default(),
+        gpu
+          .memory_used
+          .zip(gpu.memory_total)
+          .map(|(used, total)| format!(" {} / {}", format_bytes(used), format_bytes(total)))
+          .unwrap_or_default(),
+        gpu
+          .temperature
+          .map(|temperature| format!(" {}°C", temperature))
+          .unwrap_or_default()
+      )));
+    }
+    for temp in dd.temps.iter().flatten() {
+      lines.push(Line::from(format!("{}: {:.1}°C", temp.label, temp.value)));
+    }
+    frame.render_widget(Paragraph::new(lines), rows[3]);
+  }
+
+  fn draw_disks(&self, frame: &mut Frame, area: Rect) {
+    let rows: Vec<Row> = self
+      .dynamic_data
+      .disks
+      .iter()
+      .map(|disk| {
+        let percent = disk.used as f64 / disk.total.max(1) as f64 * 100.0;
+        let io = disk.io.clone().unwrap_or_default();
+        Row::new(vec![
+          disk.mount.clone(),
+          disk.fs.clone(),
+          format_bytes(disk.used),
+          format_bytes(disk.total),
+          format!("{:.0}%", percent),
+          format_bytes(io.read_bytes_per_second),
+          format_bytes(io.write_bytes_per_second),
+          format!("{:.0}%", io.utilization),
+        ])
+      })
+      .collect();
+
+    let table = Table::new(
+      rows,
+      [
+        Constraint::Percentage(22),
+        Constraint::Percentage(10),
+        Constraint::Percentage(12),
+        Constraint::Percentage(12),
+        Constraint::Percentage(8),
+        Constraint::Percentage(13),
+        Constraint::Percentage(13),
+        Constraint::Percentage(10),
+      ],
+    )
+    .header(
+      Row::new(vec![
+        "Mount", "FS", "Used", "Total", "Use", "Read/s", "Write/s", "Busy",
+      ])
+      .style(Style::default().add_modifier(Modifier::BOLD)),
+    )
+    .block(Block::default().borders(Borders::ALL).title("Disks"));
+    frame.render_widget(table, area);
+  }
+
+  fn draw_network(&self, frame: &mut Frame, area: Rect) {
+    let block = Block::default().borders(Borders::ALL).title("Network");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    // Every interface gets a label line and a graph line each for rx and tx
+    let visible = (inner.height / 3) as usize;
+    let constraints: Vec<Constraint> = (0..visible * 3).map(|_| Constraint::Length(1)).collect();
+    let rows = Layout::default()
+      .direction(Direction::Vertical)
+      .constraints(constraints)
+      .split(inner);
+
+    for (index, (name, (rx, tx))) in self.network_history.iter().take(visible).enumerate() {
+      let nic = self.dynamic_data.network.iter().find(|nic| &nic.n == name);
+      let state = nic
+        .and_then(|nic| nic.operstate.clone())
+        .unwrap_or_default();
+      let (rx, tx): (Vec<u64>, Vec<u64>) =
+        (rx.iter().copied().collect(), tx.iter().copied().collect());
+      let width = inner.width as usize;
+      let per_second = |bits: &Vec<u64>| format_bytes(bits.last().copied().unwrap_or(0) / 8);
+      frame.render_widget(
+        Paragraph::new(format!(
+          "{} {}  rx {}/s  tx {}/s",
+          name,
+          state,
+          per_second(&rx),
+          per_second(&tx)
+        )),
+        rows[index * 3],
+      );
+      frame.render_widget(
+        Sparkline::default()
+          .data(&rx[rx.len().saturating_sub(width)..])
+          .style(Style::default().fg(Color::Cyan)),
+        rows[index * 3 + 1],
+      );
+      frame.render_widget(
+        Sparkline::default()
+          .data(&tx[tx.len().saturating_sub(width)..])
+          .style(Style::default().fg(Color::Yellow)),
+        rows[index * 3 + 2],
+      );
+    }
+  }
+}
+
+fn usage_gauge(label: &str, used: u64, total: u64, color: Color) -> Gauge<'static> {
+  let ratio = if total == 0 {
+    0.0
+  } else {
+    (used as f64 / total as f64).min(1.0)
+  };
+  Gauge::default()
+    .gauge_style(Style::default().fg(color))
+    .ratio(ratio)
+    .label(format!(
+      "{} {} / {}",
+      label,
+      format_bytes(used),
+      format_bytes(total)
+    ))
+}
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StaticData {
   pub hostname: Option<String>,
   pub os_version: Option<String>,
@@ -20,22 +20,95 @@ pub struct StaticData {
 pub struct DynamicData {
   pub cpu: CPUStats,
   pub ram: RAMStats,
-  pub gpu: Option<GPUStats>,
+  /// One entry per GPU, empty when there are none or the GPU collector is off
+  #[serde(default)]
+  pub gpus: Vec<GPUStats>,
   pub process_count: i32,
+  /// Empty unless the `top_processes` collector is on
+  #[serde(default)]
+  pub top_processes: Vec<ProcessStats>,
   pub swap: SwapStats,
   pub disks: Vec<DiskStats>,
   pub temps: Option<Vec<TempStats>>,
   pub network: Vec<NetworkInterfaceStats>,
+  /// `None` unless the `systemd` collector is on and systemd is running
+  #[serde(default)]
+  pub systemd: Option<SystemdStats>,
+  /// Empty unless the `containers` collector is on
+  #[serde(default)]
+  pub containers: Vec<ContainerStats>,
+  /// The limits and usage of the cgroup the reporter runs in, `None` without cgroup v2
+  #[serde(default)]
+  pub cgroup: Option<CgroupStats>,
   pub host_uptime: u64,
   pub reporter_uptime: u64,
+  /// Unix timestamp in milliseconds of when the data was collected
+  pub timestamp: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NetworkInterfaceStats {
   pub n: String,
+  /// Transmitted bits per second since the previous collection
   pub tx: u64,
+  /// Received bits per second since the previous collection
   pub rx: u64,
-  pub s: f32,
+  /// Link speed in Mb/s, `None` when the driver doesn't know it or the link is down
+  pub s: Option<f32>,
+  /// Counters since the interface came up, the drops are only known on Linux
+  #[serde(default)]
+  pub rx_bytes: u64,
+  #[serde(default)]
+  pub tx_bytes: u64,
+  #[serde(default)]
+  pub rx_packets: u64,
+  #[serde(default)]
+  pub tx_packets: u64,
+  #[serde(default)]
+  pub rx_errors: u64,
+  #[serde(default)]
+  pub tx_errors: u64,
+  #[serde(default)]
+  pub rx_dropped: u64,
+  #[serde(default)]
+  pub tx_dropped: u64,
+  /// `up`, `down`, `dormant`... as reported by the kernel
+  #[serde(default)]
+  pub operstate: Option<String>,
+  #[serde(default)]
+  pub mac: Option<String>,
+  /// IPv4 and IPv6 addresses in CIDR notation
+  #[serde(default)]
+  pub addresses: Vec<String>,
+  #[serde(default)]
+  pub mtu: Option<u32>,
+  /// The description set with `ip link set <interface> alias <alias>`
+  #[serde(default)]
+  pub alias: Option<String>,
+  /// `full` or `half`
+  #[serde(default)]
+  pub duplex: Option<String>,
+  #[serde(default)]
+  pub r#type: NetworkInterfaceType,
+}
+
+/// What kind of device is behind a network interface
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkInterfaceType {
+  /// Backed by a real device, including wireless cards
+  Physical,
+  Loopback,
+  Bridge,
+  Vlan,
+  Bond,
+  Wireguard,
+  Tun,
+  Tap,
+  /// Any other software interface like veth pairs or dummies
+  Virtual,
+  #[default]
+  Unknown,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -56,11 +129,187 @@ pub struct SwapStats {
   pub total: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct GPUStats {
+  /// The position of the device among the GPUs of every vendor, the NVIDIA ones first,
+  /// not stable across reboots
+  pub index: u32,
+  /// `None` for the Intel GPUs and the AMD ones that don't expose their unique id
+  #[serde(default)]
+  pub uuid: Option<String>,
+  pub name: String,
+  /// Like `00000000:01:00.0`
+  pub pci_bus_id: String,
   pub brand: String,
-  pub gpu_usage: u32,
-  pub power_usage: u32,
+  /// The rest is `None` when the driver or the card doesn't support it
+  #[serde(default)]
+  pub gpu_usage: Option<u32>,
+  /// Milliwatts
+  #[serde(default)]
+  pub power_usage: Option<u32>,
+  #[serde(default)]
+  pub memory_used: Option<u64>,
+  #[serde(default)]
+  pub memory_total: Option<u64>,
+  /// Percentage of the time the memory was being read or written
+  #[serde(default)]
+  pub memory_usage: Option<u32>,
+  /// Degrees Celsius
+  #[serde(default)]
+  pub temperature: Option<u32>,
+  /// MHz
+  #[serde(default)]
+  pub sm_clock: Option<u32>,
+  /// MHz
+  #[serde(default)]
+  pub memory_clock: Option<u32>,
+  /// Percentage of the maximum speed the fan is asked to run at
+  #[serde(default)]
+  pub fan_speed: Option<u32>,
+  /// Milliwatts
+  #[serde(default)]
+  pub power_limit: Option<u32>,
+  /// Why the clocks are held down like `sw_power_cap` or `hw_thermal_slowdown`
+  #[serde(default)]
+  pub throttle_reasons: Option<Vec<String>>,
+  /// ECC errors since the driver was loaded
+  #[serde(default)]
+  pub ecc_errors_corrected: Option<u64>,
+  #[serde(default)]
+  pub ecc_errors_uncorrected: Option<u64>,
+  #[serde(default)]
+  pub processes: Option<Vec<GpuProcessStats>>,
+}
+
+/// One of the processes using the most of something, the rates are since the previous collection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProcessStats {
+  pub pid: u32,
+  pub name: String,
+  /// The user name, or the uid when it isn't known, `None` outside of Linux
+  pub user: Option<String>,
+  /// `None` when the command lines aren't reported
+  pub command: Option<String>,
+  /// Unix timestamp in seconds
+  pub start_time: u64,
+  /// Percentage of a single core, over 100 for processes using several
+  pub cpu_usage: f32,
+  /// Bytes of resident memory
+  pub memory: u64,
+  pub disk_read_bytes_per_second: u64,
+  pub disk_write_bytes_per_second: u64,
+  /// Bytes of memory on every GPU together, `None` when the process isn't using any
+  pub gpu_memory: Option<u64>,
+}
+
+/// The health of the systemd units
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SystemdStats {
+  /// Every failed unit, not only the configured ones
+  pub failed_units: u32,
+  pub units: Vec<SystemdUnitStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SystemdUnitStats {
+  pub name: String,
+  /// `loaded`, `not-found`, `masked`...
+  pub load_state: String,
+  /// `active`, `failed`, `inactive`, `activating`...
+  pub active_state: String,
+  /// The unit type specific state like `running` or `exited`
+  pub sub_state: String,
+  /// How many times systemd restarted the service, `None` for other unit types
+  pub restarts: Option<u32>,
+  /// Unix timestamp in seconds of when the unit entered its current state,
+  /// `None` if it never changed since boot
+  pub since: Option<u64>,
+}
+
+/// A Docker or Podman container, the usage is only known while it's running
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ContainerStats {
+  pub id: String,
+  pub name: String,
+  pub image: String,
+  /// `docker` or `podman`
+  pub runtime: String,
+  /// `running`, `exited`, `paused`, `restarting`...
+  pub state: String,
+  /// `healthy`, `unhealthy` or `starting`, `None` without a health check
+  pub health: Option<String>,
+  pub restart_count: u32,
+  /// Percentage of a single core since the previous collection, over 100 for containers using several
+  pub cpu_usage: Option<f64>,
+  /// Bytes of memory without the page cache
+  pub memory_used: Option<u64>,
+  pub memory_limit: Option<u64>,
+  /// Bytes since the container started over every interface
+  pub network_rx_bytes: Option<u64>,
+  pub network_tx_bytes: Option<u64>,
+  /// Bits per second since the previous collection like the network interfaces
+  pub network_rx: Option<u64>,
+  pub network_tx: Option<u64>,
+}
+
+/// A cgroup v2 group, the limits are `None` when there are none
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CgroupStats {
+  /// Like `/system.slice/xornet-reporter.service`, `/` inside a container with its own namespace
+  pub path: String,
+  /// Bytes of memory used by the group, the page cache included
+  pub memory_current: Option<u64>,
+  pub memory_max: Option<u64>,
+  pub swap_current: Option<u64>,
+  pub swap_max: Option<u64>,
+  /// Times the OOM killer killed a process of the group
+  pub oom_kills: Option<u64>,
+  /// Percentage of a single core since the previous collection, over 100 for groups using several
+  pub cpu_usage: Option<f64>,
+  /// How many cores the group can use at most from `cpu.max`
+  pub cpu_limit: Option<f64>,
+  /// Microseconds the group was throttled for hitting `cpu.max` since it was created
+  pub cpu_throttled_usec: Option<u64>,
+  pub cpu_pressure: Option<PressureStats>,
+  pub memory_pressure: Option<PressureStats>,
+  pub io_pressure: Option<PressureStats>,
+  /// The top level slices like `system.slice` and `user.slice`, empty inside containers
+  pub slices: Vec<CgroupSliceStats>,
+}
+
+/// Pressure stall information, how long tasks waited for a resource
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PressureStats {
+  /// Some of the tasks were stalled
+  pub some: PressureAverages,
+  /// Every task was stalled at once, `None` for the CPU of older kernels
+  pub full: Option<PressureAverages>,
+}
+
+/// The percentage of the time that was stalled over 10, 60 and 300 seconds
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PressureAverages {
+  pub avg10: f32,
+  pub avg60: f32,
+  pub avg300: f32,
+  /// Total stalled microseconds
+  pub total: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CgroupSliceStats {
+  pub name: String,
+  pub memory_current: Option<u64>,
+  /// Percentage of a single core since the previous collection
+  pub cpu_usage: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GpuProcessStats {
+  pub pid: u32,
+  pub name: Option<String>,
+  /// Bytes of GPU memory, `None` on Windows where the driver doesn't track it
+  pub memory_used: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -71,6 +320,36 @@ pub struct DiskStats {
   pub r#type: String,
   pub total: u64,
   pub used: u64,
+  /// Only known on Linux, shared by every filesystem on the same block device
+  #[serde(default)]
+  pub io: Option<DiskIoStats>,
+  /// `None` where the filesystem allocates inodes as it goes or there's no statvfs
+  #[serde(default)]
+  pub inodes_total: Option<u64>,
+  #[serde(default)]
+  pub inodes_used: Option<u64>,
+  #[serde(default)]
+  pub inodes_free: Option<u64>,
+  #[serde(default)]
+  pub read_only: bool,
+  /// The options from the mount table like `rw` and `noatime`, only known on Linux
+  #[serde(default)]
+  pub mount_options: Vec<String>,
+  #[serde(default)]
+  pub removable: bool,
+}
+
+/// The I/O of a block device since the previous collection
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DiskIoStats {
+  pub read_bytes_per_second: u64,
+  pub write_bytes_per_second: u64,
+  pub read_iops: f64,
+  pub write_iops: f64,
+  /// Average milliseconds a completed read or write took, queueing included
+  pub latency_ms: f64,
+  /// Percentage of the time the device was busy
+  pub utilization: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
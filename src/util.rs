@@ -1,10 +1,35 @@
 use parking_lot::Mutex;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 pub fn arcmutex<T>(item: T) -> Arc<Mutex<T>> {
   Arc::new(Mutex::new(item))
 }
 
+/// Returns the current unix timestamp in milliseconds
+pub fn unix_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .map(|duration| duration.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+/// Formats a number of bytes with a human readable unit
+pub fn format_bytes(bytes: u64) -> String {
+  const UNITS: [&str; 6] = ["B", "KB", "MB", "GB", "TB", "PB"];
+  let mut value = bytes as f64;
+  let mut unit = 0;
+  while value >= 1000.0 && unit < UNITS.len() - 1 {
+    value /= 1000.0;
+    unit += 1;
+  }
+  if unit == 0 {
+    format!("{} {}", bytes, UNITS[unit])
+  } else {
+    format!("{:.1} {}", value, UNITS[unit])
+  }
+}
+
 /// Returns the speed in megabytes per second
 /// # Arguments
 /// * `number` - The number to convert
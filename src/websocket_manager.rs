@@ -1,19 +1,24 @@
 use anyhow::Result;
+use native_tls::TlsConnector;
 use parking_lot::Mutex;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use std::net::TcpStream;
+use serde_json::json;
 use std::sync::Arc;
-use websocket::sync::stream::TlsStream;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use websocket::result::WebSocketError;
+use websocket::stream::sync::NetworkStream;
 use websocket::sync::Client;
-use websocket::{ClientBuilder, Message};
+use websocket::{ClientBuilder, Message, OwnedMessage};
 
 use crate::types::{
-  CPUStats, DiskStats, GPUStats, NetworkInterfaceStats, RAMStats, SwapStats, TempStats,
+  CPUStats, CgroupStats, ContainerStats, DiskStats, DynamicData, GPUStats, NetworkInterfaceStats,
+  ProcessStats, RAMStats, StaticData, SwapStats, SystemdStats, TempStats,
 };
 use crate::util::arcmutex;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum WebsocketEvent {
   Login {
@@ -23,13 +28,25 @@ pub enum WebsocketEvent {
     cpu: CPUStats,
     ram: RAMStats,
     swap: SwapStats,
-    gpu: Option<GPUStats>,
+    /// The first GPU for the backends that only know about one
+    gpu: Option<Box<GPUStats>>,
+    #[serde(default)]
+    gpus: Vec<GPUStats>,
     process_count: i32,
+    #[serde(default)]
+    top_processes: Vec<ProcessStats>,
     disks: Vec<DiskStats>,
     temps: Option<Vec<TempStats>>,
     network: Vec<NetworkInterfaceStats>,
+    #[serde(default)]
+    systemd: Option<SystemdStats>,
+    #[serde(default)]
+    containers: Vec<ContainerStats>,
+    #[serde(default)]
+    cgroup: Option<Box<CgroupStats>>,
     host_uptime: u64,
     reporter_uptime: u64,
+    timestamp: u64,
   },
   StaticData {
     hostname: Option<String>,
@@ -56,35 +73,486 @@ pub fn get_event_id(ev: &WebsocketEvent) -> &str {
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct WebsocketMessage {
-  e: WebsocketEvent,
-  data: Value,
+impl From<DynamicData> for WebsocketEvent {
+  fn from(dd: DynamicData) -> Self {
+    WebsocketEvent::DynamicData {
+      cpu: dd.cpu,
+      ram: dd.ram,
+      swap: dd.swap,
+      gpu: dd.gpus.first().cloned().map(Box::new),
+      gpus: dd.gpus,
+      process_count: dd.process_count,
+      top_processes: dd.top_processes,
+      disks: dd.disks,
+      temps: dd.temps,
+      network: dd.network,
+      systemd: dd.systemd,
+      containers: dd.containers,
+      cgroup: dd.cgroup.map(Box::new),
+      host_uptime: dd.host_uptime,
+      reporter_uptime: dd.reporter_uptime,
+      timestamp: dd.timestamp,
+    }
+  }
+}
+
+impl From<StaticData> for WebsocketEvent {
+  fn from(sd: StaticData) -> Self {
+    WebsocketEvent::StaticData {
+      hostname: sd.hostname,
+      public_ip: sd.public_ip,
+      country: sd.country,
+      city: sd.city,
+      isp: sd.isp,
+      timezone: sd.timezone,
+      cpu_model: sd.cpu_model,
+      os_version: sd.os_version,
+      os_name: sd.os_name,
+      cpu_cores: sd.cpu_cores,
+      cpu_threads: sd.cpu_threads,
+      total_mem: sd.total_mem,
+      reporter_version: sd.reporter_version,
+    }
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum WebsocketError {
+  #[error("Not connected to the backend, next reconnect attempt in {0:.1?}")]
+  NotConnected(Duration),
+  #[error("The backend rejected the login: {0}")]
+  LoginRejected(String),
+  #[error("The backend closed the connection: {0}")]
+  Closed(String),
+}
+
+/// How long the backend gets to turn the login down before it counts as accepted when it
+/// doesn't acknowledge it, it closes the connection on a bad access token
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The state of the connection to the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+  Disconnected,
+  /// The login was sent at the given time and the backend hasn't answered it yet
+  LoggingIn(Instant),
+  Authenticated,
+}
+
+/// Exponential backoff with jitter for the reconnect attempts
+#[derive(Debug, Clone)]
+pub struct Backoff {
+  pub base_delay: Duration,
+  pub max_delay: Duration,
+  attempt: u32,
+}
+
+impl Backoff {
+  pub fn new(base_delay: Duration, max_delay: Duration) -> Self {
+    Self {
+      base_delay,
+      max_delay,
+      attempt: 0,
+    }
+  }
+
+  /// Returns the delay before the next attempt and bumps the attempt counter.
+  /// Half of the delay is fixed and the other half is random so a fleet of reporters
+  /// that lost the backend at the same time doesn't reconnect in lockstep
+  pub fn next_delay(&mut self) -> Duration {
+    let exponential = self
+      .base_delay
+      .saturating_mul(2u32.saturating_pow(self.attempt))
+      .min(self.max_delay);
+    self.attempt = self.attempt.saturating_add(1);
+
+    let half = exponential / 2;
+    half + half.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+  }
+
+  pub fn reset(&mut self) {
+    self.attempt = 0;
+  }
 }
 
+/// A plain or TLS websocket connection depending on the URL scheme
+type WebsocketClient = Client<Box<dyn NetworkStream + Send>>;
+
+/// Owns the connection to the backend and transparently reconnects it,
+/// replaying the login and static data every time a new connection is made
 pub struct WebsocketManager {
   pub websocket_url: String,
-  pub websocket: Arc<Mutex<Client<TlsStream<TcpStream>>>>,
+  pub state: ConnectionState,
+  websocket: Option<Arc<Mutex<WebsocketClient>>>,
+  auth_token: String,
+  tls_connector: TlsConnector,
+  static_data: Option<WebsocketEvent>,
+  backoff: Backoff,
+  next_attempt: Instant,
 }
 
 impl WebsocketManager {
-  pub fn new(websocket_url: &str) -> Result<Self> {
-    let mut client = ClientBuilder::new(websocket_url)?;
-    Ok(Self {
+  /// Creates a disconnected manager, the connection is made on the first send
+  pub fn new(
+    websocket_url: &str,
+    auth_token: &str,
+    max_delay: Duration,
+    tls_connector: TlsConnector,
+  ) -> Self {
+    Self {
       websocket_url: websocket_url.to_string(),
-      websocket: arcmutex(client.connect_secure(None)?),
-    })
+      state: ConnectionState::Disconnected,
+      websocket: None,
+      auth_token: auth_token.to_string(),
+      tls_connector,
+      static_data: None,
+      backoff: Backoff::new(Duration::from_secs(1), max_delay),
+      next_attempt: Instant::now(),
+    }
+  }
+
+  pub fn is_connected(&self) -> bool {
+    self.websocket.is_some()
+  }
+
+  pub fn is_authenticated(&self) -> bool {
+    self.state == ConnectionState::Authenticated
+  }
+
+  /// Sets the static data that gets replayed after every reconnect
+  /// and sends it right away if we're already connected
+  pub fn set_static_data(&mut self, static_data: WebsocketEvent) {
+    self.static_data = Some(static_data.clone());
+    if self.is_connected() {
+      // A failure here is logged and the data is replayed on the reconnect
+      let _ = self.send(static_data);
+    }
+  }
+
+  /// Makes sure we're connected, attempting to reconnect if the backoff delay since the
+  /// last failure has passed. The login is only sent here, the backend's answer to it
+  /// is picked up by later calls without waiting for it
+  pub fn ensure_connected(&mut self) -> Result<()> {
+    if self.is_connected() {
+      if let Err(e) = self.poll() {
+        let delay = self.disconnect();
+        eprintln!(
+          "Lost connection to {}: {}, reconnecting in {:.1?}",
+          self.websocket_url, e, delay
+        );
+        return Err(e);
+      }
+      return Ok(());
+    }
+
+    let now = Instant::now();
+    if now < self.next_attempt {
+      return Err(WebsocketError::NotConnected(self.next_attempt - now).into());
+    }
+
+    match self.connect() {
+      Ok(_) => {
+        eprintln!("Connected to {}", self.websocket_url);
+        self.state = ConnectionState::LoggingIn(Instant::now());
+        Ok(())
+      }
+      Err(e) => {
+        let delay = self.disconnect();
+        eprintln!(
+          "Could not connect to {}: {}, retrying in {:.1?}",
+          self.websocket_url, e, delay
+        );
+        Err(e)
+      }
+    }
+  }
+
+  /// Opens a new connection and replays the login and static data on it
+  fn connect(&mut self) -> Result<()> {
+    let mut client = ClientBuilder::new(&self.websocket_url)?;
+    // Picks a plain or TLS stream depending on whether the URL is ws:// or wss://
+    self.websocket = Some(arcmutex(client.connect(Some(self.tls_connector.clone()))?));
+
+    self.send_raw(&WebsocketEvent::Login {
+      auth_token: self.auth_token.clone(),
+    })?;
+
+    if let Some(static_data) = self.static_data.clone() {
+      self.send_raw(&static_data)?;
+    }
+
+    Ok(())
+  }
+
+  /// Handles whatever the backend sent since the last call without blocking on it, fails
+  /// once the backend closed the connection or turned the login down
+  fn poll(&mut self) -> Result<()> {
+    while let Some(message) = self.try_recv()? {
+      if let OwnedMessage::Ping(payload) = message {
+        self.send_message(&OwnedMessage::Pong(payload))?;
+        continue;
+      }
+      self.state = next_state(self.state, &message)?;
+    }
+
+    if let ConnectionState::LoggingIn(sent) = self.state {
+      if sent.elapsed() >= LOGIN_TIMEOUT {
+        self.state = ConnectionState::Authenticated;
+      }
+    }
+    if self.is_authenticated() {
+      self.backoff.reset();
+    }
+    Ok(())
+  }
+
+  /// Reads the next message if one has arrived already
+  fn try_recv(&self) -> Result<Option<OwnedMessage>> {
+    let websocket = match self.websocket.as_ref() {
+      Some(websocket) => websocket,
+      None => return Ok(None),
+    };
+    let mut client = websocket.lock();
+    client.set_nonblocking(true)?;
+    let message = client.recv_message();
+    client.set_nonblocking(false)?;
+
+    match message {
+      Ok(message) => Ok(Some(message)),
+      Err(WebSocketError::IoError(e)) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+      // The connection went away without a close frame
+      Err(WebSocketError::NoDataAvailable) => Ok(Some(OwnedMessage::Close(None))),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Drops the current connection and schedules the next reconnect attempt
+  fn disconnect(&mut self) -> Duration {
+    let delay = self.backoff.next_delay();
+    self.websocket = None;
+    self.state = ConnectionState::Disconnected;
+    self.next_attempt = Instant::now() + delay;
+    delay
   }
 
   pub fn send(&mut self, data: WebsocketEvent) -> Result<()> {
-    let message = Message::text(
+    self.ensure_connected()?;
+
+    if let Err(e) = self.send_raw(&data) {
+      let delay = self.disconnect();
+      eprintln!(
+        "Lost connection to {}: {}, reconnecting in {:.1?}",
+        self.websocket_url, e, delay
+      );
+      return Err(e);
+    }
+
+    Ok(())
+  }
+
+  fn send_raw(&self, data: &WebsocketEvent) -> Result<()> {
+    self.send_message(&Message::text(
       json!({
-          "e": get_event_id(&data),
-          "d": &data,
+          "e": get_event_id(data),
+          "d": data,
       })
       .to_string(),
+    ))
+  }
+
+  fn send_message<M: websocket::ws::Message>(&self, message: &M) -> Result<()> {
+    match self.websocket.as_ref() {
+      Some(websocket) => Ok(websocket.lock().send_message(message)?),
+      None => Err(WebsocketError::NotConnected(Duration::ZERO).into()),
+    }
+  }
+}
+
+/// The state of the connection after a message from the backend. Only an explicit
+/// `login` event acknowledges the login and a close frame before that rejects it
+fn next_state(state: ConnectionState, message: &OwnedMessage) -> Result<ConnectionState> {
+  match message {
+    OwnedMessage::Close(close) => {
+      let reason = close
+        .as_ref()
+        .map(|close| {
+          format!("{} {}", close.status_code, close.reason)
+            .trim()
+            .to_string()
+        })
+        .unwrap_or_else(|| "connection closed".to_string());
+      Err(
+        match state {
+          ConnectionState::LoggingIn(_) => WebsocketError::LoginRejected(reason),
+          _ => WebsocketError::Closed(reason),
+        }
+        .into(),
+      )
+    }
+    OwnedMessage::Text(text) if matches!(state, ConnectionState::LoggingIn(_)) => {
+      let event = serde_json::from_str::<serde_json::Value>(text).ok();
+      match event.as_ref().and_then(|event| event.get("e")) {
+        Some(id) if id == "login" => Ok(ConnectionState::Authenticated),
+        _ => Ok(state),
+      }
+    }
+    // The reporter doesn't act on anything else the backend sends
+    _ => Ok(state),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::thread;
+  use websocket::CloseData;
+
+  #[test]
+  fn backoff_grows_exponentially_with_jitter() {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    for attempt in 0..5 {
+      let exponential = Duration::from_secs(1 << attempt);
+      let delay = backoff.next_delay();
+      assert!(
+        delay >= exponential / 2 && delay <= exponential,
+        "attempt {}: {:?}",
+        attempt,
+        delay
+      );
+    }
+  }
+
+  #[test]
+  fn backoff_is_capped() {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+    for _ in 0..100 {
+      assert!(backoff.next_delay() <= Duration::from_secs(10));
+    }
+    assert!(backoff.next_delay() >= Duration::from_secs(5));
+  }
+
+  #[test]
+  fn backoff_starts_over_after_a_reset() {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    for _ in 0..10 {
+      backoff.next_delay();
+    }
+    backoff.reset();
+    assert!(backoff.next_delay() <= Duration::from_secs(1));
+  }
+
+  #[test]
+  fn a_close_rejects_the_login_until_it_was_accepted() {
+    let logging_in = ConnectionState::LoggingIn(Instant::now());
+    let close = OwnedMessage::Close(Some(CloseData::new(
+      4001,
+      "invalid access token".to_string(),
+    )));
+    assert_eq!(
+      next_state(logging_in, &close).unwrap_err().to_string(),
+      "The backend rejected the login: 4001 invalid access token"
+    );
+    assert_eq!(
+      next_state(ConnectionState::Authenticated, &OwnedMessage::Close(None))
+        .unwrap_err()
+        .to_string(),
+      "The backend closed the connection: connection closed"
     );
+  }
+
+  #[test]
+  fn only_a_login_event_accepts_the_login() {
+    let logging_in = ConnectionState::LoggingIn(Instant::now());
+    let ack = OwnedMessage::Text(r#"{"e":"login","d":{}}"#.to_string());
+    assert_eq!(
+      next_state(logging_in, &ack).unwrap(),
+      ConnectionState::Authenticated
+    );
+
+    for message in [
+      OwnedMessage::Text(r#"{"e":"machine-update","d":{}}"#.to_string()),
+      OwnedMessage::Text("not json".to_string()),
+      OwnedMessage::Ping(vec![1, 2, 3]),
+      OwnedMessage::Binary(vec![1, 2, 3]),
+    ] {
+      assert_eq!(next_state(logging_in, &message).unwrap(), logging_in);
+    }
+  }
+
+  /// Serves a single connection that reads the login, answers it with `reply` and
+  /// then stays open until the manager drops it
+  fn backend(reply: Option<OwnedMessage>) -> WebsocketManager {
+    let mut server = websocket::sync::Server::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    thread::spawn(move || {
+      let mut client = match server.accept() {
+        Ok(upgrade) => upgrade.accept().unwrap(),
+        Err(_) => panic!("no websocket handshake"),
+      };
+      let login = client.recv_message().unwrap();
+      assert!(matches!(login, OwnedMessage::Text(text) if text.contains(r#""e":"login""#)));
+      if let Some(reply) = reply {
+        client.send_message(&reply).unwrap();
+      }
+      while client.recv_message().is_ok() {}
+    });
+    WebsocketManager::new(
+      &url,
+      "token",
+      Duration::from_secs(60),
+      TlsConnector::new().unwrap(),
+    )
+  }
+
+  #[test]
+  fn connecting_doesnt_wait_for_the_login() {
+    let mut manager = backend(None);
+    let started = Instant::now();
+    manager.ensure_connected().unwrap();
+    assert!(started.elapsed() < LOGIN_TIMEOUT / 2);
+    assert!(matches!(manager.state, ConnectionState::LoggingIn(_)));
+    manager.ensure_connected().unwrap();
+    assert!(!manager.is_authenticated());
+  }
+
+  #[test]
+  fn a_later_close_rejects_the_login() {
+    let mut manager = backend(Some(OwnedMessage::Close(Some(CloseData::new(
+      4001,
+      "invalid access token".to_string(),
+    )))));
+    manager.ensure_connected().unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let error = loop {
+      match manager.ensure_connected() {
+        Ok(_) => assert!(Instant::now() < deadline, "the close never arrived"),
+        Err(e) => break e,
+      }
+      thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(
+      error.to_string(),
+      "The backend rejected the login: 4001 invalid access token"
+    );
+    assert!(!manager.is_connected());
+  }
+
+  #[test]
+  fn an_acknowledged_login_is_authenticated() {
+    let mut manager = backend(Some(OwnedMessage::Text(
+      r#"{"e":"login","d":{}}"#.to_string(),
+    )));
+    manager.ensure_connected().unwrap();
 
-    Ok(self.websocket.lock().send_message(&message)?)
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !manager.is_authenticated() {
+      assert!(
+        Instant::now() < deadline,
+        "the acknowledgement never arrived"
+      );
+      thread::sleep(Duration::from_millis(10));
+      manager.ensure_connected().unwrap();
+    }
   }
 }